@@ -0,0 +1,250 @@
+//! Watches the battery voltage, so that the pack isn't discharged too far.
+
+/// The battery voltage below which to warn, until one is set. This is 3.3 V per cell for 10S.
+pub const DEFAULT_WARNING_MV: u16 = 33_000;
+
+/// The battery voltage below which to power off, until one is set. This is 3.0 V per cell for 10S.
+pub const DEFAULT_CUTOFF_MV: u16 = 30_000;
+
+/// How long the voltage must stay below a threshold before acting on it, so that brief sags under
+/// load don't trigger it.
+const DEBOUNCE_MS: u32 = 2000;
+
+/// How far the voltage must rise back above the warning threshold before warning again.
+const WARNING_HYSTERESIS_MV: u16 = 500;
+
+/// How long to take to ramp the torque down to zero before powering off.
+const CUTOFF_RAMP_MS: u32 = 1000;
+
+/// Something which the main loop should do because of the battery voltage.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BatteryEvent {
+    /// The voltage has dropped below the warning threshold.
+    Warning,
+    /// The voltage has dropped below the cutoff threshold, so the torque is being ramped down.
+    Cutoff,
+    /// The torque has finished ramping down, so it is time to power off.
+    PowerOff,
+    /// The voltage has risen back above the warning threshold since the last warning.
+    Recovered,
+}
+
+/// Watches the battery voltage for it dropping below the warning and cutoff thresholds.
+#[derive(Debug)]
+pub struct BatteryMonitor {
+    warning_mv: u16,
+    cutoff_mv: u16,
+    /// The timestamp since which the voltage has been below the warning threshold, if it is.
+    below_warning_since: Option<u32>,
+    /// The timestamp since which the voltage has been below the cutoff threshold, if it is.
+    below_cutoff_since: Option<u32>,
+    /// Whether the warning has been given since the voltage was last above the warning threshold.
+    warned: bool,
+    /// The timestamp at which the cutoff started, if it has.
+    cutoff_time: Option<u32>,
+}
+
+impl Default for BatteryMonitor {
+    fn default() -> Self {
+        Self {
+            warning_mv: DEFAULT_WARNING_MV,
+            cutoff_mv: DEFAULT_CUTOFF_MV,
+            below_warning_since: None,
+            below_cutoff_since: None,
+            warned: false,
+            cutoff_time: None,
+        }
+    }
+}
+
+impl BatteryMonitor {
+    pub fn set_thresholds(&mut self, warning_mv: u16, cutoff_mv: u16) {
+        self.warning_mv = warning_mv;
+        self.cutoff_mv = cutoff_mv;
+    }
+
+    /// Returns the warning and cutoff thresholds, in millivolts.
+    pub fn thresholds(&self) -> (u16, u16) {
+        (self.warning_mv, self.cutoff_mv)
+    }
+
+    /// Checks the latest battery voltage reading, and returns what to do about it, if anything.
+    pub fn update(&mut self, battery_voltage: u16, current_time: u32) -> Option<BatteryEvent> {
+        if let Some(cutoff_time) = self.cutoff_time {
+            // Once the cutoff has started there is no going back.
+            return if current_time >= cutoff_time + CUTOFF_RAMP_MS {
+                Some(BatteryEvent::PowerOff)
+            } else {
+                None
+            };
+        }
+
+        if below_for(
+            &mut self.below_cutoff_since,
+            battery_voltage < self.cutoff_mv,
+            current_time,
+        ) {
+            self.cutoff_time = Some(current_time);
+            return Some(BatteryEvent::Cutoff);
+        }
+
+        let below_warning = below_for(
+            &mut self.below_warning_since,
+            battery_voltage < self.warning_mv,
+            current_time,
+        );
+        if self.warned && battery_voltage > self.warning_mv.saturating_add(WARNING_HYSTERESIS_MV) {
+            self.warned = false;
+            return Some(BatteryEvent::Recovered);
+        }
+        if below_warning && !self.warned {
+            self.warned = true;
+            return Some(BatteryEvent::Warning);
+        }
+
+        None
+    }
+
+    /// Scales the requested torque down to 0 over `CUTOFF_RAMP_MS` once the cutoff has started.
+    pub fn limit_torque(&self, torque: i16, current_time: u32) -> i16 {
+        match self.cutoff_time {
+            Some(cutoff_time) => {
+                let elapsed = current_time.saturating_sub(cutoff_time).min(CUTOFF_RAMP_MS);
+                let remaining = (CUTOFF_RAMP_MS - elapsed) as i32;
+                (i32::from(torque) * remaining / CUTOFF_RAMP_MS as i32) as i16
+            }
+            None => torque,
+        }
+    }
+}
+
+/// Keeps track of how long some condition has been true, returning whether it has been true for at
+/// least `DEBOUNCE_MS`.
+fn below_for(since: &mut Option<u32>, below: bool, current_time: u32) -> bool {
+    if below {
+        let since = *since.get_or_insert(current_time);
+        current_time >= since + DEBOUNCE_MS
+    } else {
+        *since = None;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the monitor the given voltage every 100 ms from `start` until `end`, and returns the
+    /// events it reported.
+    fn run(monitor: &mut BatteryMonitor, battery_voltage: u16, start: u32, end: u32) -> [usize; 4] {
+        let mut counts = [0; 4];
+        for current_time in (start..end).step_by(100) {
+            if let Some(event) = monitor.update(battery_voltage, current_time) {
+                counts[event as usize] += 1;
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn nothing_when_healthy() {
+        let mut monitor = BatteryMonitor::default();
+        assert_eq!(run(&mut monitor, 40_000, 0, 10_000), [0, 0, 0, 0]);
+        assert_eq!(monitor.limit_torque(500, 10_000), 500);
+    }
+
+    #[test]
+    fn brief_sag_is_ignored() {
+        let mut monitor = BatteryMonitor::default();
+        assert_eq!(run(&mut monitor, 29_000, 0, DEBOUNCE_MS), [0, 0, 0, 0]);
+        assert_eq!(monitor.update(40_000, DEBOUNCE_MS), None);
+        // The debounce starts again from scratch.
+        assert_eq!(monitor.update(29_000, DEBOUNCE_MS + 100), None);
+        assert_eq!(monitor.update(29_000, 2 * DEBOUNCE_MS), None);
+    }
+
+    #[test]
+    fn warns_once_until_recovered() {
+        let mut monitor = BatteryMonitor::default();
+        assert_eq!(monitor.update(32_000, 0), None);
+        assert_eq!(
+            monitor.update(32_000, DEBOUNCE_MS),
+            Some(BatteryEvent::Warning)
+        );
+        assert_eq!(
+            run(&mut monitor, 32_000, DEBOUNCE_MS + 100, 10_000),
+            [0, 0, 0, 0]
+        );
+
+        // Rising just above the threshold isn't enough to warn again.
+        assert_eq!(
+            run(&mut monitor, DEFAULT_WARNING_MV + 1, 10_000, 11_000),
+            [0, 0, 0, 0]
+        );
+        assert_eq!(run(&mut monitor, 32_000, 11_000, 20_000), [0, 0, 0, 0]);
+
+        // But rising above the hysteresis is, and is reported once.
+        assert_eq!(
+            run(
+                &mut monitor,
+                DEFAULT_WARNING_MV + WARNING_HYSTERESIS_MV + 1,
+                20_000,
+                21_000
+            ),
+            [0, 0, 0, 1]
+        );
+        assert_eq!(run(&mut monitor, 32_000, 21_000, 30_000), [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn cutoff_ramps_down_then_powers_off() {
+        let mut monitor = BatteryMonitor::default();
+        monitor.set_thresholds(33_000, 30_000);
+        assert_eq!(monitor.update(29_000, 1000), None);
+        assert_eq!(
+            monitor.update(29_000, 1000 + DEBOUNCE_MS),
+            Some(BatteryEvent::Cutoff)
+        );
+        let cutoff_time = 1000 + DEBOUNCE_MS;
+
+        assert_eq!(monitor.limit_torque(800, cutoff_time), 800);
+        assert_eq!(
+            monitor.limit_torque(800, cutoff_time + CUTOFF_RAMP_MS / 4),
+            600
+        );
+        assert_eq!(
+            monitor.limit_torque(-800, cutoff_time + CUTOFF_RAMP_MS / 2),
+            -400
+        );
+        assert_eq!(monitor.limit_torque(800, cutoff_time + CUTOFF_RAMP_MS), 0);
+
+        // There's no going back once the cutoff has started, even if the voltage recovers.
+        assert_eq!(
+            monitor.update(40_000, cutoff_time + CUTOFF_RAMP_MS - 1),
+            None
+        );
+        assert_eq!(
+            monitor.update(40_000, cutoff_time + CUTOFF_RAMP_MS),
+            Some(BatteryEvent::PowerOff)
+        );
+        assert_eq!(monitor.limit_torque(800, cutoff_time + 10_000), 0);
+    }
+
+    #[test]
+    fn cutoff_takes_priority_over_warning() {
+        let mut monitor = BatteryMonitor::default();
+        assert_eq!(run(&mut monitor, 29_000, 0, DEBOUNCE_MS), [0, 0, 0, 0]);
+        assert_eq!(
+            monitor.update(29_000, DEBOUNCE_MS),
+            Some(BatteryEvent::Cutoff)
+        );
+    }
+
+    #[test]
+    fn thresholds_can_be_changed() {
+        let mut monitor = BatteryMonitor::default();
+        monitor.set_thresholds(40_000, 35_000);
+        assert_eq!(monitor.thresholds(), (40_000, 35_000));
+        assert_eq!(run(&mut monitor, 38_000, 0, 10_000), [1, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,176 @@
+//! Works out which Hall sensor state goes with each sector, by stepping the motor slowly through a
+//! few electrical turns open-loop.
+
+use crate::commutation::sector_start_angle;
+use messages::{CalibrationError, HallMapping};
+
+/// The motor power to drive the phases with while calibrating.
+pub const CALIBRATION_POWER: i16 = 60;
+
+/// How long to hold the first position, so the rotor can settle from wherever it started.
+const ALIGN_MS: u32 = 1000;
+
+/// How long to hold each later position before reading the Hall sensors.
+const STEP_MS: u32 = 250;
+
+/// How many electrical turns to step through forwards, before stepping back through them again.
+const TURNS: u32 = 2;
+
+/// The number of steps after the first position, forwards and back.
+const STEPS: u32 = 2 * TURNS * 6;
+
+/// What the motor should do next while calibrating.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CalibrationStep {
+    /// Drive the phases at the given electrical angle with `CALIBRATION_POWER`.
+    Drive(u16),
+    /// Calibration has finished, with the given result.
+    Done(Result<HallMapping, CalibrationError>),
+}
+
+/// Keeps track of a calibration run, which should be polled regularly until it is done.
+#[derive(Debug, Default)]
+pub struct Calibration {
+    /// The timestamp at which the current position started being held, or `None` if it hasn't been
+    /// polled yet.
+    step_start: Option<u32>,
+    /// The index of the current position, from 0 to `STEPS`.
+    step: u32,
+    /// The Hall sensor state seen in each sector so far.
+    states: [Option<u8>; 6],
+}
+
+impl Calibration {
+    /// Reads the Hall sensors at the end of each step, and returns what the motor should do next.
+    pub fn poll(&mut self, current_time: u32, hall_state: u8) -> CalibrationStep {
+        let step_start = *self.step_start.get_or_insert(current_time);
+        let hold_ms = if self.step == 0 { ALIGN_MS } else { STEP_MS };
+        if current_time >= step_start + hold_ms {
+            if let Err(e) = self.record(hall_state) {
+                return CalibrationStep::Done(Err(e));
+            }
+            if self.step == STEPS {
+                return CalibrationStep::Done(self.mapping());
+            }
+            self.step += 1;
+            self.step_start = Some(current_time);
+        }
+        CalibrationStep::Drive(sector_start_angle(self.sector()))
+    }
+
+    /// Returns the sector at the start of which to drive the phases for the current step.
+    fn sector(&self) -> u8 {
+        let position = if self.step <= STEPS / 2 {
+            self.step
+        } else {
+            STEPS - self.step
+        };
+        (position % 6) as u8
+    }
+
+    fn record(&mut self, hall_state: u8) -> Result<(), CalibrationError> {
+        if hall_state == 0 || hall_state > 6 {
+            return Err(CalibrationError::InvalidState(hall_state));
+        }
+        // Driving at the start of a sector leaves the rotor in the middle of the next one.
+        let rotor_sector = usize::from((self.sector() + 1) % 6);
+        match self.states[rotor_sector] {
+            Some(state) if state != hall_state => Err(CalibrationError::Inconsistent),
+            _ => {
+                self.states[rotor_sector] = Some(hall_state);
+                Ok(())
+            }
+        }
+    }
+
+    fn mapping(&self) -> Result<HallMapping, CalibrationError> {
+        let mut states = [0; 6];
+        for (state, seen) in states.iter_mut().zip(self.states.iter()) {
+            *state = seen.ok_or(CalibrationError::NotMoving)?;
+        }
+        HallMapping::new(states).map_err(|_| CalibrationError::NotMoving)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs calibration against a simulated motor, which reports the Hall sensor state returned by
+    /// `hall_state` for the sector the rotor is pulled to and the number of steps so far.
+    fn run(
+        mut hall_state: impl FnMut(usize, u32) -> u8,
+    ) -> (Result<HallMapping, CalibrationError>, u32) {
+        let mut calibration = Calibration::default();
+        let mut angle = None;
+        for time in (0..100_000).step_by(10) {
+            let state = match angle {
+                Some(angle) => {
+                    let sector = (0..6)
+                        .find(|&sector| sector_start_angle(sector) == angle)
+                        .unwrap();
+                    hall_state(usize::from(sector + 1) % 6, calibration.step)
+                }
+                None => 0,
+            };
+            match calibration.poll(time, state) {
+                CalibrationStep::Drive(next_angle) => angle = Some(next_angle),
+                CalibrationStep::Done(result) => return (result, time),
+            }
+        }
+        panic!("Calibration didn't finish");
+    }
+
+    #[test]
+    fn default_mapping() {
+        let states = HallMapping::DEFAULT.states();
+        assert_eq!(
+            run(|sector, _| states[sector]),
+            (Ok(HallMapping::DEFAULT), ALIGN_MS + STEPS * STEP_MS)
+        );
+    }
+
+    #[test]
+    fn reversed_mapping() {
+        let states = [0b011, 0b001, 0b101, 0b100, 0b110, 0b010];
+        let (result, _) = run(|sector, _| states[5 - sector]);
+        let mapping = result.unwrap();
+        assert!(mapping.is_reversed());
+        assert_eq!(mapping.sector(0b010), Some(0));
+    }
+
+    #[test]
+    fn rotated_mapping() {
+        let states = [0b110, 0b010, 0b011, 0b001, 0b101, 0b100];
+        let (result, _) = run(|sector, _| states[sector]);
+        assert_eq!(result, Ok(HallMapping::new(states).unwrap()));
+    }
+
+    #[test]
+    fn disconnected() {
+        assert_eq!(
+            run(|_, _| 0b111).0,
+            Err(CalibrationError::InvalidState(0b111))
+        );
+    }
+
+    #[test]
+    fn not_moving() {
+        assert_eq!(run(|_, _| 0b001).0, Err(CalibrationError::NotMoving));
+    }
+
+    #[test]
+    fn inconsistent() {
+        // The rotor lags a sector behind on the way back.
+        let states = HallMapping::DEFAULT.states();
+        assert_eq!(
+            run(|sector, step| if step > STEPS / 2 {
+                states[(sector + 5) % 6]
+            } else {
+                states[sector]
+            })
+            .0,
+            Err(CalibrationError::Inconsistent)
+        );
+    }
+}
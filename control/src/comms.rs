@@ -0,0 +1,258 @@
+//! Watches for commands from the host, so that the motor can be stopped if the host goes away while
+//! it is being driven.
+
+use crate::Target;
+
+/// How long to take to ramp the torque from where it was to the safe torque after timing out.
+const RAMP_MS: u32 = 500;
+
+/// The torque being applied while ramping to the safe torque.
+#[derive(Debug)]
+struct Ramp {
+    /// The timestamp at which the ramp started.
+    start_time: u32,
+    /// The torque being applied when the ramp started.
+    start_torque: i16,
+    /// The torque most recently set as the target.
+    torque: i16,
+}
+
+/// Keeps track of when the last command arrived from the host, and what to do if the host stops
+/// sending them.
+#[derive(Debug, Default)]
+pub struct CommsMonitor {
+    /// How long to go without a command before timing out, or 0 if the timeout is disabled.
+    timeout_ms: u32,
+    /// The torque to apply after timing out, or 0 to remove the target.
+    safe_torque: i16,
+    /// The timestamp at which the last command was received, or of the first poll after the
+    /// timeout was set.
+    last_command_time: Option<u32>,
+    /// Whether a command has been received since the last poll.
+    command_received: bool,
+    /// Whether the timeout has expired, and no command has been received since.
+    timed_out: bool,
+    /// The ramp to the safe torque after timing out, if it hasn't finished yet.
+    ramp: Option<Ramp>,
+}
+
+impl CommsMonitor {
+    /// Sets how long to go without a command before timing out, and the torque to apply after that.
+    /// A timeout of 0 disables it, and a safe torque of 0 removes the target instead.
+    pub fn set_timeout(&mut self, timeout_ms: u16, safe_torque: i16) {
+        self.timeout_ms = timeout_ms.into();
+        self.safe_torque = safe_torque;
+        self.last_command_time = None;
+        self.timed_out = false;
+    }
+
+    /// Records that a command has been received from the host.
+    pub fn command_received(&mut self) {
+        self.command_received = true;
+    }
+
+    /// Returns the target to use once the ramp after timing out has finished.
+    fn safe_target(&self) -> Option<Target> {
+        if self.safe_torque == 0 {
+            None
+        } else {
+            Some(Target::Torque(self.safe_torque))
+        }
+    }
+
+    /// Returns true if the timeout has just expired. This is only reported once until another
+    /// command is received.
+    ///
+    /// If there is a target when the timeout expires then it is replaced with a torque which ramps
+    /// from the given torque, which should be what the motor was last driven with, to the safe
+    /// torque over `RAMP_MS`. After that the safe torque is applied, or the target is removed if it
+    /// is 0. If the target is changed in the meantime, for example by a command, then the ramp
+    /// stops. If there is no target then the motor is left alone.
+    pub fn poll(&mut self, current_time: u32, torque: i16, target: &mut Option<Target>) -> bool {
+        self.update_ramp(current_time, target);
+        if self.timeout_ms == 0 {
+            self.command_received = false;
+            return false;
+        }
+        let last_command_time = self.last_command_time.get_or_insert(current_time);
+        if self.command_received {
+            self.command_received = false;
+            *last_command_time = current_time;
+            self.timed_out = false;
+        } else if !self.timed_out && current_time >= *last_command_time + self.timeout_ms {
+            self.timed_out = true;
+            if target.is_some() {
+                self.ramp = Some(Ramp {
+                    start_time: current_time,
+                    start_torque: torque,
+                    torque,
+                });
+                *target = Some(Target::Torque(torque));
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Moves the target along the ramp to the safe torque, if there is one.
+    fn update_ramp(&mut self, current_time: u32, target: &mut Option<Target>) {
+        if let Some(ramp) = &mut self.ramp {
+            if *target != Some(Target::Torque(ramp.torque)) {
+                self.ramp = None;
+            } else if current_time >= ramp.start_time + RAMP_MS {
+                self.ramp = None;
+                *target = self.safe_target();
+            } else {
+                let elapsed = (current_time - ramp.start_time) as i32;
+                let start_torque = i32::from(ramp.start_torque);
+                let change =
+                    (i32::from(self.safe_torque) - start_torque) * elapsed / RAMP_MS as i32;
+                ramp.torque = (start_torque + change) as i16;
+                *target = Some(Target::Torque(ramp.torque));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_by_default() {
+        let mut comms = CommsMonitor::default();
+        assert!(!comms.poll(0, 0, &mut None));
+        assert!(!comms.poll(1_000_000, 0, &mut None));
+    }
+
+    #[test]
+    fn stays_up_with_commands() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(300, 0);
+        for time in (1000..5000).step_by(100) {
+            comms.command_received();
+            assert!(!comms.poll(time, 0, &mut None));
+            assert!(!comms.poll(time + 99, 0, &mut None));
+        }
+    }
+
+    #[test]
+    fn times_out_once() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(300, 0);
+        // The timeout starts from the first poll after it is set.
+        assert!(!comms.poll(1000, 0, &mut None));
+        assert!(!comms.poll(1299, 0, &mut None));
+        assert!(comms.poll(1300, 0, &mut None));
+        assert!(!comms.poll(2000, 0, &mut None));
+    }
+
+    #[test]
+    fn times_out_again_after_command() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(300, 0);
+        assert!(!comms.poll(0, 0, &mut None));
+        assert!(comms.poll(300, 0, &mut None));
+        comms.command_received();
+        assert!(!comms.poll(1000, 0, &mut None));
+        assert!(!comms.poll(1299, 0, &mut None));
+        assert!(comms.poll(1300, 0, &mut None));
+    }
+
+    #[test]
+    fn disabling_stops_timeout() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(300, 0);
+        assert!(!comms.poll(0, 0, &mut None));
+        comms.set_timeout(0, 0);
+        assert!(!comms.poll(1000, 0, &mut None));
+    }
+
+    #[test]
+    fn ramps_down_then_removes_target() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(300, 0);
+        let mut target = Some(Target::Position(1000));
+        assert!(!comms.poll(0, 100, &mut target));
+        assert_eq!(target, Some(Target::Position(1000)));
+        // The ramp starts from the torque the motor was last driven with.
+        assert!(comms.poll(300, 100, &mut target));
+        assert_eq!(target, Some(Target::Torque(100)));
+        assert!(!comms.poll(300 + RAMP_MS / 2, 100, &mut target));
+        assert_eq!(target, Some(Target::Torque(50)));
+        assert!(!comms.poll(300 + RAMP_MS - 1, 50, &mut target));
+        assert_eq!(target, Some(Target::Torque(1)));
+        assert!(!comms.poll(300 + RAMP_MS, 1, &mut target));
+        assert_eq!(target, None);
+        assert!(!comms.poll(2000, 0, &mut target));
+        assert_eq!(target, None);
+    }
+
+    #[test]
+    fn ramps_to_safe_torque() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(300, -50);
+        let mut target = Some(Target::Velocity(200));
+        assert!(!comms.poll(0, 150, &mut target));
+        assert!(comms.poll(300, 150, &mut target));
+        assert_eq!(target, Some(Target::Torque(150)));
+        assert!(!comms.poll(300 + RAMP_MS / 2, 150, &mut target));
+        assert_eq!(target, Some(Target::Torque(50)));
+        assert!(!comms.poll(300 + RAMP_MS, 50, &mut target));
+        assert_eq!(target, Some(Target::Torque(-50)));
+        assert!(!comms.poll(2000, -50, &mut target));
+        assert_eq!(target, Some(Target::Torque(-50)));
+    }
+
+    #[test]
+    fn no_target_stays_without_target() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(300, -50);
+        let mut target = None;
+        assert!(!comms.poll(0, 0, &mut target));
+        assert!(comms.poll(300, 0, &mut target));
+        assert_eq!(target, None);
+        assert!(!comms.poll(300 + RAMP_MS, 0, &mut target));
+        assert_eq!(target, None);
+        assert!(!comms.poll(2000, 0, &mut target));
+        assert_eq!(target, None);
+    }
+
+    #[test]
+    fn command_without_target_continues_ramp() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(300, 0);
+        let mut target = Some(Target::Position(1000));
+        assert!(!comms.poll(0, 100, &mut target));
+        assert!(comms.poll(300, 100, &mut target));
+        // A keepalive doesn't leave the motor part way down the ramp.
+        comms.command_received();
+        assert!(!comms.poll(300 + RAMP_MS / 2, 100, &mut target));
+        assert_eq!(target, Some(Target::Torque(50)));
+        assert!(!comms.poll(300 + RAMP_MS, 50, &mut target));
+        assert_eq!(target, None);
+    }
+
+    #[test]
+    fn new_target_stops_ramp() {
+        let mut comms = CommsMonitor::default();
+        comms.set_timeout(1000, -50);
+        let mut target = Some(Target::Position(1000));
+        assert!(!comms.poll(0, 100, &mut target));
+        assert!(comms.poll(1000, 100, &mut target));
+        target = Some(Target::Velocity(10));
+        comms.command_received();
+        assert!(!comms.poll(1100, 80, &mut target));
+        assert_eq!(target, Some(Target::Velocity(10)));
+        assert!(!comms.poll(1000 + RAMP_MS, 20, &mut target));
+        assert_eq!(target, Some(Target::Velocity(10)));
+    }
+
+    #[test]
+    fn safe_target() {
+        let mut comms = CommsMonitor::default();
+        assert_eq!(comms.safe_target(), None);
+        comms.set_timeout(300, -50);
+        assert_eq!(comms.safe_target(), Some(Target::Torque(-50)));
+    }
+}
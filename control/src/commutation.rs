@@ -0,0 +1,245 @@
+//! Works out how hard to drive each of the three motor phases from the rotor position.
+//!
+//! Phase powers are in the same units as motor power, from -1000 to 1000, and are given in the
+//! order yellow, blue, green.
+
+/// Units of electrical angle per Hall sensor sector.
+pub const ANGLE_PER_SECTOR: u16 = 64;
+const ANGLE_PER_TURN: u16 = 6 * ANGLE_PER_SECTOR;
+const QUARTER_TURN: u16 = ANGLE_PER_TURN / 4;
+
+/// The sine of every `SINE_TABLE_STEP` units of angle over a quarter turn, out of 1000.
+const SINE_TABLE: [i32; 25] = [
+    0, 65, 131, 195, 259, 321, 383, 442, 500, 556, 609, 659, 707, 752, 793, 831, 866, 897, 924,
+    947, 966, 981, 991, 998, 1000,
+];
+const SINE_TABLE_STEP: u16 = QUARTER_TURN / (SINE_TABLE.len() as u16 - 1);
+
+/// The electrical angle at the start of sector 0. This is chosen so that the voltage vector in the
+/// middle of each sector is the same one that block commutation uses for the whole sector.
+const SECTOR_0_START: u16 = 160;
+
+/// Sine amplitude out of 1000 for full power. Centring the phases between the highest and lowest
+/// brings the peak back down to full power, so this is 2/√3 to use the whole supply voltage.
+const SINE_AMPLITUDE: i32 = 1155;
+
+/// Returns the phase powers for six-step block commutation in the given Hall sensor sector, or all
+/// zeroes if the sector is invalid.
+pub fn block_phase_powers(power: i16, sector: u8) -> [i16; 3] {
+    match sector {
+        0 => [0, power, -power],
+        1 => [-power, power, 0],
+        2 => [-power, 0, power],
+        3 => [0, -power, power],
+        4 => [power, -power, 0],
+        5 => [power, 0, -power],
+        _ => [0, 0, 0],
+    }
+}
+
+/// Returns the phase powers for sinusoidal commutation at the given electrical angle, as returned
+/// by `rotor_angle`.
+///
+/// The phases are shifted together so that they are centred, which is equivalent to space vector
+/// modulation and lets full power reach the same peak phase voltage as block commutation.
+pub fn sinusoidal_phase_powers(power: i16, angle: u16) -> [i16; 3] {
+    let amplitude = i32::from(power) * SINE_AMPLITUDE / 1000;
+    let phases = [
+        sin(angle),
+        sin(angle + ANGLE_PER_TURN - ANGLE_PER_TURN / 3),
+        sin(angle + ANGLE_PER_TURN / 3),
+    ]
+    .map(|sine| amplitude * sine / 1000);
+    let highest = phases.iter().max().unwrap();
+    let lowest = phases.iter().min().unwrap();
+    let centre = (highest + lowest) / 2;
+    phases.map(|phase| (phase - centre) as i16)
+}
+
+/// Estimates the electrical angle of the rotor within the given Hall sensor sector, from the
+/// velocity in steps per second and how long it has been since the sector was entered.
+///
+/// If the motor is stopped this is the middle of the sector. Otherwise the angle starts from
+/// whichever edge the rotor must have entered by, and stops at the far edge until the Hall sensors
+/// catch up.
+pub fn rotor_angle(sector: u8, velocity: i32, elapsed_cycles: u32, cycles_per_second: u32) -> u16 {
+    let sector_angle = i64::from(ANGLE_PER_SECTOR);
+    let offset = if velocity == 0 || cycles_per_second == 0 {
+        sector_angle / 2
+    } else {
+        let moved = i64::from(velocity) * i64::from(elapsed_cycles) * sector_angle
+            / i64::from(cycles_per_second);
+        let entry = if velocity > 0 { 0 } else { sector_angle };
+        (entry + moved).clamp(0, sector_angle)
+    };
+    (SECTOR_0_START + u16::from(sector % 6) * ANGLE_PER_SECTOR + offset as u16) % ANGLE_PER_TURN
+}
+
+/// Returns the electrical angle at the start of the given Hall sensor sector.
+///
+/// Driving the phases at this angle with `sinusoidal_phase_powers` pulls the rotor to the middle of
+/// the next sector, as the torque-producing angle is a quarter turn ahead of the rotor.
+pub fn sector_start_angle(sector: u8) -> u16 {
+    (SECTOR_0_START + u16::from(sector % 6) * ANGLE_PER_SECTOR) % ANGLE_PER_TURN
+}
+
+/// Converts phase powers to PWM duty cycles out of `duty_max`, with 0 power at half duty.
+///
+/// The duty cycles are kept a little away from either end, so that the dead time between switching
+/// the high and low sides is always respected.
+pub fn duty_cycles(phase_powers: [i16; 3], duty_max: u16) -> [u16; 3] {
+    let power_max = i32::from(duty_max / 2);
+    phase_powers.map(|power| {
+        let duty = (i32::from(power.clamp(-1000, 1000)) * power_max / 1000 + power_max) as u16;
+        duty.clamp(10, duty_max - 10)
+    })
+}
+
+/// Returns the sine of the given electrical angle, out of 1000, interpolating between entries.
+fn sin(angle: u16) -> i32 {
+    let angle = angle % ANGLE_PER_TURN;
+    let (angle, sign) = if angle < ANGLE_PER_TURN / 2 {
+        (angle, 1)
+    } else {
+        (angle - ANGLE_PER_TURN / 2, -1)
+    };
+    let angle = if angle > QUARTER_TURN {
+        ANGLE_PER_TURN / 2 - angle
+    } else {
+        angle
+    };
+    let index = usize::from(angle / SINE_TABLE_STEP);
+    let remainder = i32::from(angle % SINE_TABLE_STEP);
+    let value = if remainder == 0 {
+        SINE_TABLE[index]
+    } else {
+        SINE_TABLE[index]
+            + (SINE_TABLE[index + 1] - SINE_TABLE[index]) * remainder / i32::from(SINE_TABLE_STEP)
+    };
+    sign * value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(0, 0)]
+    #[test_case(32, 500 ; "thirty degrees")]
+    #[test_case(96, 1000 ; "ninety degrees")]
+    #[test_case(98, 999 ; "just past ninety degrees")]
+    #[test_case(192, 0 ; "one hundred and eighty degrees")]
+    #[test_case(288, -1000 ; "two hundred and seventy degrees")]
+    #[test_case(386, -sin(382) ; "wraps around")]
+    fn sine(angle: u16, expected: i32) {
+        assert_eq!(sin(angle), expected);
+    }
+
+    #[test]
+    fn sine_interpolates() {
+        assert_eq!(sin(2), 32);
+        assert!(sin(1) > sin(0) && sin(1) < sin(2));
+    }
+
+    /// In the middle of each sector, sinusoidal commutation should drive the phases just like
+    /// block commutation does.
+    #[test_case(0)]
+    #[test_case(1)]
+    #[test_case(2)]
+    #[test_case(3)]
+    #[test_case(4)]
+    #[test_case(5)]
+    fn sinusoidal_matches_block_at_sector_centre(sector: u8) {
+        for power in [1000, 300, -500] {
+            let angle = rotor_angle(sector, 0, 0, 72_000_000);
+            let sinusoidal = sinusoidal_phase_powers(power, angle);
+            let block = block_phase_powers(power, sector);
+            for (s, b) in sinusoidal.iter().zip(block.iter()) {
+                assert!(
+                    (s - b).abs() <= 2,
+                    "sector {} power {}: sinusoidal {:?}, block {:?}",
+                    sector,
+                    power,
+                    sinusoidal,
+                    block
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sinusoidal_within_power() {
+        for angle in 0..ANGLE_PER_TURN {
+            for phase in sinusoidal_phase_powers(1000, angle) {
+                assert!(phase.abs() <= 1000, "angle {}: {}", angle, phase);
+            }
+        }
+    }
+
+    #[test]
+    fn sinusoidal_negative_power_mirrors() {
+        for angle in 0..ANGLE_PER_TURN {
+            let positive = sinusoidal_phase_powers(700, angle);
+            let negative = sinusoidal_phase_powers(-700, angle);
+            for (p, n) in positive.iter().zip(negative.iter()) {
+                assert!((p + n).abs() <= 1, "angle {}", angle);
+            }
+        }
+    }
+
+    #[test]
+    fn sinusoidal_zero_power() {
+        assert_eq!(sinusoidal_phase_powers(0, 123), [0, 0, 0]);
+    }
+
+    #[test]
+    fn block_invalid_sector() {
+        assert_eq!(block_phase_powers(500, 6), [0, 0, 0]);
+    }
+
+    #[test]
+    fn rotor_angle_stopped() {
+        assert_eq!(rotor_angle(0, 0, 1000, 1000), SECTOR_0_START + 32);
+        assert_eq!(rotor_angle(2, 0, 1000, 1000), SECTOR_0_START + 2 * 64 + 32);
+    }
+
+    #[test]
+    fn rotor_angle_forwards() {
+        // 10 steps per second, so a quarter of the way through the sector after 25 ms.
+        assert_eq!(rotor_angle(1, 10, 25, 1000), SECTOR_0_START + 64 + 16);
+        // Stop at the far edge if the next Hall edge is late.
+        assert_eq!(rotor_angle(1, 10, 500, 1000), SECTOR_0_START + 2 * 64);
+    }
+
+    #[test]
+    fn rotor_angle_backwards() {
+        assert_eq!(rotor_angle(1, -10, 25, 1000), SECTOR_0_START + 64 + 48);
+        assert_eq!(rotor_angle(1, -10, 500, 1000), SECTOR_0_START + 64);
+    }
+
+    #[test]
+    fn rotor_angle_wraps() {
+        assert_eq!(
+            rotor_angle(5, 10, 90, 1000),
+            (SECTOR_0_START + 5 * 64 + 57) % 384
+        );
+    }
+
+    #[test]
+    fn sector_start_angle_wraps() {
+        assert_eq!(sector_start_angle(0), SECTOR_0_START);
+        assert_eq!(sector_start_angle(1), SECTOR_0_START + 64);
+        assert_eq!(sector_start_angle(4), 32);
+        assert_eq!(sector_start_angle(6), SECTOR_0_START);
+    }
+
+    #[test]
+    fn duty_cycles_centred() {
+        assert_eq!(duty_cycles([0, 500, -500], 2000), [1000, 1500, 500]);
+    }
+
+    #[test]
+    fn duty_cycles_clamped() {
+        assert_eq!(duty_cycles([1000, -1000, 2000], 2000), [1990, 10, 1990]);
+    }
+}
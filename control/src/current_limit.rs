@@ -0,0 +1,226 @@
+//! Limits the motor current by folding back the allowed motor power.
+
+use messages::Fault;
+
+/// The current limit to use until one is set, in milliamps.
+pub const DEFAULT_CURRENT_LIMIT_MA: u16 = 15_000;
+
+/// The maximum magnitude of motor power, when not limited.
+const MAX_MOTOR_POWER: i16 = 1000;
+
+/// How much to reduce the allowed motor power for each ADC sample over the current limit.
+const FOLDBACK_STEP: i16 = 5;
+
+/// How much to raise the allowed motor power for each ADC sample under the current limit.
+const RECOVERY_STEP: i16 = 1;
+
+/// How long the current must be over the limit before a fault is latched. Samples under the limit
+/// count back down again, so brief spikes don't add up to a fault.
+const OVERLOAD_FAULT_MS: u32 = 500;
+
+/// Limits the motor power when the current is too high, and latches a fault if it stays too high.
+#[derive(Debug)]
+pub struct CurrentLimiter {
+    /// The maximum motor current in milliamps, in either direction.
+    limit_ma: i32,
+    /// The maximum magnitude of motor power currently allowed.
+    power_limit: i16,
+    /// Roughly how many samples the current has been over the limit for recently.
+    overload_samples: u32,
+    /// The number of samples over the limit after which a fault is latched.
+    fault_samples: u32,
+    /// The latched fault, if any.
+    fault: Option<Fault>,
+    /// Whether the latched fault has been reported yet.
+    fault_reported: bool,
+}
+
+impl CurrentLimiter {
+    /// Creates a limiter which will be updated at the given rate. ADC samples are taken once per
+    /// motor PWM timer cycle, so this is the PWM frequency.
+    pub fn new(sample_rate_hz: u32) -> Self {
+        Self {
+            limit_ma: DEFAULT_CURRENT_LIMIT_MA.into(),
+            power_limit: MAX_MOTOR_POWER,
+            overload_samples: 0,
+            fault_samples: samples_for(OVERLOAD_FAULT_MS, sample_rate_hz),
+            fault: None,
+            fault_reported: false,
+        }
+    }
+
+    /// Changes the rate at which `update` is called, so that a fault still takes the same time.
+    pub fn set_sample_rate(&mut self, sample_rate_hz: u32) {
+        self.fault_samples = samples_for(OVERLOAD_FAULT_MS, sample_rate_hz);
+    }
+
+    pub fn set_limit(&mut self, limit_ma: u16) {
+        self.limit_ma = limit_ma.into();
+    }
+
+    /// Returns the current limit in milliamps.
+    pub fn limit(&self) -> u16 {
+        self.limit_ma as u16
+    }
+
+    /// Updates the allowed motor power based on a new current reading. This should be called for
+    /// each ADC sample.
+    pub fn update(&mut self, current_ma: i32) {
+        if self.fault.is_some() {
+            return;
+        }
+
+        if current_ma.abs() > self.limit_ma {
+            self.power_limit = (self.power_limit - FOLDBACK_STEP).max(0);
+            self.overload_samples += 1;
+            if self.overload_samples >= self.fault_samples {
+                self.fault = Some(Fault::OverCurrent { current_ma });
+                self.fault_reported = false;
+                self.power_limit = 0;
+            }
+        } else {
+            self.power_limit = (self.power_limit + RECOVERY_STEP).min(MAX_MOTOR_POWER);
+            self.overload_samples = self.overload_samples.saturating_sub(1);
+        }
+    }
+
+    /// The maximum magnitude of motor power currently allowed.
+    pub fn power_limit(&self) -> i16 {
+        self.power_limit
+    }
+
+    /// Returns the latched fault if it hasn't already been returned.
+    pub fn take_new_fault(&mut self) -> Option<Fault> {
+        if self.fault_reported {
+            None
+        } else {
+            self.fault_reported = true;
+            self.fault
+        }
+    }
+
+    /// Clears any latched fault, returning whether there was one.
+    pub fn clear_fault(&mut self) -> bool {
+        let had_fault = self.fault.is_some();
+        self.fault = None;
+        self.overload_samples = 0;
+        self.power_limit = MAX_MOTOR_POWER;
+        had_fault
+    }
+}
+
+/// Returns the number of samples taken at the given rate in the given time, but at least one.
+pub(crate) fn samples_for(time_ms: u32, sample_rate_hz: u32) -> u32 {
+    (time_ms * sample_rate_hz / 1000).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rate at which ADC samples are taken at the default PWM frequency.
+    const SAMPLE_RATE_HZ: u32 = 16_000;
+
+    /// The number of samples over the limit after which a fault is latched at that rate.
+    const FAULT_SAMPLES: u32 = 8000;
+
+    /// Feeds the limiter the given current reading for the given number of samples.
+    fn run(limiter: &mut CurrentLimiter, current_ma: i32, samples: u32) {
+        for _ in 0..samples {
+            limiter.update(current_ma);
+        }
+    }
+
+    #[test]
+    fn under_limit_allows_full_power() {
+        let mut limiter = CurrentLimiter::new(SAMPLE_RATE_HZ);
+        let limit_ma = i32::from(DEFAULT_CURRENT_LIMIT_MA);
+        run(&mut limiter, limit_ma, 100);
+        run(&mut limiter, -limit_ma, 100);
+        assert_eq!(limiter.power_limit(), MAX_MOTOR_POWER);
+        assert_eq!(limiter.take_new_fault(), None);
+    }
+
+    #[test]
+    fn folds_back_over_limit() {
+        let mut limiter = CurrentLimiter::new(SAMPLE_RATE_HZ);
+        limiter.set_limit(1000);
+        assert_eq!(limiter.limit(), 1000);
+        run(&mut limiter, 1001, 10);
+        assert_eq!(limiter.power_limit(), MAX_MOTOR_POWER - 10 * FOLDBACK_STEP);
+        // Negative currents are limited too.
+        run(&mut limiter, -1001, 10);
+        assert_eq!(limiter.power_limit(), MAX_MOTOR_POWER - 20 * FOLDBACK_STEP);
+
+        // The power limit doesn't go below zero.
+        run(&mut limiter, 1001, 1000);
+        assert_eq!(limiter.power_limit(), 0);
+        assert_eq!(limiter.take_new_fault(), None);
+    }
+
+    #[test]
+    fn recovers_under_limit() {
+        let mut limiter = CurrentLimiter::new(SAMPLE_RATE_HZ);
+        limiter.set_limit(1000);
+        run(&mut limiter, 2000, 10);
+        run(&mut limiter, 500, 20);
+        assert_eq!(
+            limiter.power_limit(),
+            MAX_MOTOR_POWER - 10 * FOLDBACK_STEP + 20 * RECOVERY_STEP
+        );
+        run(&mut limiter, 500, 1000);
+        assert_eq!(limiter.power_limit(), MAX_MOTOR_POWER);
+    }
+
+    #[test]
+    fn latches_fault_after_sustained_overload() {
+        let mut limiter = CurrentLimiter::new(SAMPLE_RATE_HZ);
+        limiter.set_limit(1000);
+        run(&mut limiter, 2000, FAULT_SAMPLES - 1);
+        assert_eq!(limiter.take_new_fault(), None);
+        limiter.update(2000);
+        assert_eq!(limiter.power_limit(), 0);
+        assert_eq!(
+            limiter.take_new_fault(),
+            Some(Fault::OverCurrent { current_ma: 2000 })
+        );
+        // The fault is only reported once.
+        assert_eq!(limiter.take_new_fault(), None);
+
+        // The fault stays latched even once the current drops.
+        run(&mut limiter, 0, 1000);
+        assert_eq!(limiter.power_limit(), 0);
+
+        assert!(limiter.clear_fault());
+        assert_eq!(limiter.power_limit(), MAX_MOTOR_POWER);
+        assert!(!limiter.clear_fault());
+    }
+
+    #[test]
+    fn fault_time_independent_of_sample_rate() {
+        let mut limiter = CurrentLimiter::new(SAMPLE_RATE_HZ);
+        limiter.set_limit(1000);
+        limiter.set_sample_rate(SAMPLE_RATE_HZ * 2);
+        run(&mut limiter, 2000, FAULT_SAMPLES * 2 - 1);
+        assert_eq!(limiter.take_new_fault(), None);
+        limiter.update(2000);
+        assert!(limiter.take_new_fault().is_some());
+
+        let mut limiter = CurrentLimiter::new(SAMPLE_RATE_HZ / 2);
+        limiter.set_limit(1000);
+        run(&mut limiter, 2000, FAULT_SAMPLES / 2);
+        assert!(limiter.take_new_fault().is_some());
+    }
+
+    #[test]
+    fn brief_spikes_dont_add_up_to_fault() {
+        let mut limiter = CurrentLimiter::new(SAMPLE_RATE_HZ);
+        limiter.set_limit(1000);
+        // Over the limit for a quarter of the time.
+        for _ in 0..FAULT_SAMPLES {
+            run(&mut limiter, 2000, 1);
+            run(&mut limiter, 0, 3);
+        }
+        assert_eq!(limiter.take_new_fault(), None);
+    }
+}
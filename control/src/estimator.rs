@@ -0,0 +1,173 @@
+//! Estimates the motor velocity from the timing of Hall sensor transitions.
+
+use crate::commutation::rotor_angle;
+
+/// If there has been no Hall sensor transition for this long, the motor is taken to be stopped.
+const VELOCITY_TIMEOUT_MS: u32 = 200;
+
+/// Each new velocity sample moves the filtered velocity this fraction of the way towards it.
+const VELOCITY_FILTER_DIVISOR: i32 = 4;
+
+/// Estimates the motor velocity from the time between Hall sensor transitions.
+#[derive(Clone, Debug)]
+pub struct VelocityEstimator {
+    /// The frequency of the cycle counter used for timestamps.
+    cycles_per_second: u32,
+    /// The cycle count at the last Hall sensor transition, or `None` if the motor is stopped.
+    last_step_time: Option<u32>,
+    /// The filtered velocity, in steps per second.
+    velocity: i32,
+}
+
+impl VelocityEstimator {
+    pub fn new(cycles_per_second: u32) -> Self {
+        Self {
+            cycles_per_second,
+            last_step_time: None,
+            velocity: 0,
+        }
+    }
+
+    /// Returns the filtered velocity, in steps per second.
+    pub fn velocity(&self) -> i32 {
+        self.velocity
+    }
+
+    /// Updates the estimate after the motor moved by the given number of steps at the given cycle
+    /// count.
+    pub fn step(&mut self, steps: i32, now: u32) {
+        if let Some(last_step_time) = self.last_step_time {
+            let elapsed = now.wrapping_sub(last_step_time).max(1);
+            let sample = (steps as i64 * self.cycles_per_second as i64 / elapsed as i64) as i32;
+            self.velocity += (sample - self.velocity) / VELOCITY_FILTER_DIVISOR;
+        }
+        // Otherwise the motor was stopped, so we can't tell how long this step took.
+        self.last_step_time = Some(now);
+    }
+
+    /// Estimates the electrical angle of the rotor within the given Hall sensor sector, as of the
+    /// given cycle count.
+    pub fn rotor_angle(&self, sector: u8, now: u32) -> u16 {
+        match self.last_step_time {
+            Some(last_step_time) => rotor_angle(
+                sector,
+                self.velocity,
+                now.wrapping_sub(last_step_time),
+                self.cycles_per_second,
+            ),
+            None => rotor_angle(sector, 0, 0, self.cycles_per_second),
+        }
+    }
+
+    /// Updates the estimate when the motor hasn't moved since the last call.
+    pub fn idle(&mut self, now: u32) {
+        if let Some(last_step_time) = self.last_step_time {
+            let elapsed = now.wrapping_sub(last_step_time);
+            if elapsed > self.cycles_per_second / 1000 * VELOCITY_TIMEOUT_MS {
+                self.last_step_time = None;
+                self.velocity = 0;
+            } else {
+                // The motor can't be going any faster than one step in the time since the last one.
+                let bound = (self.cycles_per_second / elapsed.max(1)) as i32;
+                self.velocity = self.velocity.clamp(-bound, bound);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cycle counter running at 1 MHz, so one cycle is a microsecond.
+    const CYCLES_PER_SECOND: u32 = 1_000_000;
+
+    /// Steps the estimator the given number of times at a steady velocity, starting from the given
+    /// cycle count, and returns the cycle count of the last step.
+    fn run(
+        estimator: &mut VelocityEstimator,
+        steps: i32,
+        interval: u32,
+        count: u32,
+        start: u32,
+    ) -> u32 {
+        let mut now = start;
+        for _ in 0..count {
+            now = now.wrapping_add(interval);
+            estimator.step(steps, now);
+        }
+        now
+    }
+
+    #[test]
+    fn first_step_from_stopped() {
+        let mut estimator = VelocityEstimator::new(CYCLES_PER_SECOND);
+        estimator.step(1, 5000);
+        // There's no previous step to time it from.
+        assert_eq!(estimator.velocity(), 0);
+    }
+
+    #[test]
+    fn filters_towards_sample() {
+        let mut estimator = VelocityEstimator::new(CYCLES_PER_SECOND);
+        estimator.step(1, 0);
+        // One step in 10 ms is 100 steps per second, and each sample moves a quarter of the way.
+        estimator.step(1, 10_000);
+        assert_eq!(estimator.velocity(), 25);
+        estimator.step(1, 20_000);
+        assert_eq!(estimator.velocity(), 43);
+    }
+
+    #[test]
+    fn converges_in_each_direction() {
+        let mut estimator = VelocityEstimator::new(CYCLES_PER_SECOND);
+        run(&mut estimator, 1, 10_000, 50, 0);
+        assert!((97..=100).contains(&estimator.velocity()));
+
+        let mut estimator = VelocityEstimator::new(CYCLES_PER_SECOND);
+        run(&mut estimator, -2, 10_000, 50, 0);
+        assert!((-200..=-197).contains(&estimator.velocity()));
+    }
+
+    #[test]
+    fn handles_counter_wrapping() {
+        let mut estimator = VelocityEstimator::new(CYCLES_PER_SECOND);
+        run(&mut estimator, 1, 10_000, 50, u32::MAX - 200_000);
+        assert!((97..=100).contains(&estimator.velocity()));
+    }
+
+    #[test]
+    fn idle_bounds_velocity() {
+        let mut estimator = VelocityEstimator::new(CYCLES_PER_SECOND);
+        let now = run(&mut estimator, 1, 10_000, 50, 0);
+        // Still plausible after 5 ms without a step.
+        estimator.idle(now + 5_000);
+        assert!((97..=100).contains(&estimator.velocity()));
+        // But after 20 ms without a step it can't be going faster than 50 steps per second.
+        estimator.idle(now + 20_000);
+        assert_eq!(estimator.velocity(), 50);
+    }
+
+    #[test]
+    fn stall_times_out() {
+        let mut estimator = VelocityEstimator::new(CYCLES_PER_SECOND);
+        let now = run(&mut estimator, 1, 10_000, 50, 0);
+        estimator.idle(now + VELOCITY_TIMEOUT_MS * 1000);
+        assert_ne!(estimator.velocity(), 0);
+        estimator.idle(now + VELOCITY_TIMEOUT_MS * 1000 + 1);
+        assert_eq!(estimator.velocity(), 0);
+
+        // The next step is treated as starting from stopped, rather than timed from the last one.
+        estimator.step(1, now + 1_000_000);
+        assert_eq!(estimator.velocity(), 0);
+    }
+
+    #[test]
+    fn rotor_angle_when_stopped() {
+        let estimator = VelocityEstimator::new(CYCLES_PER_SECOND);
+        assert_eq!(
+            estimator.rotor_angle(2, 12345),
+            rotor_angle(2, 0, 0, CYCLES_PER_SECOND)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Watches the Hall sensors for errors, so that the motor can be stopped if they are disconnected.
+
+use crate::current_limit::samples_for;
+use messages::Fault;
+
+/// How long the Hall sensors must stay in an invalid state while the motor is being driven before
+/// a fault is latched.
+const INVALID_FAULT_MS: u32 = 50;
+
+/// What the Hall sensors looked like on a single update of the motor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HallStatus {
+    /// The sensors were in a valid state, adjacent to or the same as the last one.
+    Valid,
+    /// The sensors were in the given invalid raw state.
+    Invalid(u8),
+    /// The sensors were in a valid state, but on the opposite side from the last one so it isn't
+    /// possible to tell which way the motor moved.
+    Skipped,
+}
+
+/// Keeps count of Hall sensor errors, and latches a fault if the sensors look to be disconnected
+/// while the motor is being driven.
+#[derive(Debug)]
+pub struct HallMonitor {
+    /// The number of times the sensors have gone into an invalid state.
+    invalid_states: u32,
+    /// The number of times the sensors have skipped over a sector.
+    skipped_sectors: u32,
+    /// The number of consecutive updates for which the sensors have been in an invalid state while
+    /// the motor was being driven.
+    invalid_samples: u32,
+    /// The number of consecutive invalid updates after which a fault is latched.
+    fault_samples: u32,
+    /// Whether the sensors were in an invalid state on the last update.
+    was_invalid: bool,
+    /// The latched fault, if any.
+    fault: Option<Fault>,
+    /// Whether the latched fault has been reported yet.
+    fault_reported: bool,
+}
+
+impl HallMonitor {
+    /// Creates a monitor which will be updated at the given rate. The motor is updated once per PWM
+    /// timer cycle, so this is the PWM frequency.
+    pub fn new(update_rate_hz: u32) -> Self {
+        Self {
+            invalid_states: 0,
+            skipped_sectors: 0,
+            invalid_samples: 0,
+            fault_samples: samples_for(INVALID_FAULT_MS, update_rate_hz),
+            was_invalid: false,
+            fault: None,
+            fault_reported: false,
+        }
+    }
+
+    /// Changes the rate at which `update` is called, so that a fault still takes the same time.
+    pub fn set_update_rate(&mut self, update_rate_hz: u32) {
+        self.fault_samples = samples_for(INVALID_FAULT_MS, update_rate_hz);
+    }
+
+    /// Updates the counters based on the status from a motor update, and whether the motor is being
+    /// driven. Returns true if a fault was just latched, in which case the motor output should be
+    /// disabled immediately.
+    pub fn update(&mut self, status: HallStatus, driving: bool) -> bool {
+        let invalid = matches!(status, HallStatus::Invalid(_));
+        if invalid && !self.was_invalid {
+            self.invalid_states = self.invalid_states.saturating_add(1);
+        }
+        self.was_invalid = invalid;
+        if status == HallStatus::Skipped {
+            self.skipped_sectors = self.skipped_sectors.saturating_add(1);
+        }
+
+        match status {
+            HallStatus::Invalid(state) if driving && self.fault.is_none() => {
+                self.invalid_samples += 1;
+                if self.invalid_samples >= self.fault_samples {
+                    self.fault = Some(Fault::HallSensors { state });
+                    self.fault_reported = false;
+                    return true;
+                }
+            }
+            _ => self.invalid_samples = 0,
+        }
+        false
+    }
+
+    /// Returns whether there is a latched fault, in which case the motor must not be driven.
+    pub fn has_fault(&self) -> bool {
+        self.fault.is_some()
+    }
+
+    /// Returns the number of times the sensors have gone into an invalid state, and the number of
+    /// times they have skipped over a sector.
+    pub fn diagnostics(&self) -> (u32, u32) {
+        (self.invalid_states, self.skipped_sectors)
+    }
+
+    /// Returns the latched fault if it hasn't already been returned.
+    pub fn take_new_fault(&mut self) -> Option<Fault> {
+        if self.fault_reported {
+            None
+        } else {
+            self.fault_reported = true;
+            self.fault
+        }
+    }
+
+    /// Clears any latched fault, returning whether there was one.
+    pub fn clear_fault(&mut self) -> bool {
+        let had_fault = self.fault.is_some();
+        self.fault = None;
+        self.invalid_samples = 0;
+        had_fault
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rate at which the motor is updated at the default PWM frequency.
+    const UPDATE_RATE_HZ: u32 = 16_000;
+
+    /// The number of consecutive invalid updates after which a fault is latched at that rate.
+    const FAULT_SAMPLES: u32 = 800;
+
+    /// Feeds the monitor the given status for the given number of updates, and returns whether a
+    /// fault was latched on any of them.
+    fn run(monitor: &mut HallMonitor, status: HallStatus, driving: bool, updates: u32) -> bool {
+        let mut latched = false;
+        for _ in 0..updates {
+            latched |= monitor.update(status, driving);
+        }
+        latched
+    }
+
+    #[test]
+    fn counts_invalid_states_once_each() {
+        let mut monitor = HallMonitor::new(UPDATE_RATE_HZ);
+        run(&mut monitor, HallStatus::Valid, false, 10);
+        assert_eq!(monitor.diagnostics(), (0, 0));
+
+        // A run of invalid updates only counts as one invalid state.
+        run(&mut monitor, HallStatus::Invalid(0b000), false, 10);
+        assert_eq!(monitor.diagnostics(), (1, 0));
+        run(&mut monitor, HallStatus::Valid, false, 1);
+        run(&mut monitor, HallStatus::Invalid(0b111), false, 1);
+        assert_eq!(monitor.diagnostics(), (2, 0));
+    }
+
+    #[test]
+    fn counts_skipped_sectors() {
+        let mut monitor = HallMonitor::new(UPDATE_RATE_HZ);
+        run(&mut monitor, HallStatus::Skipped, true, 3);
+        run(&mut monitor, HallStatus::Valid, true, 1);
+        assert_eq!(monitor.diagnostics(), (0, 3));
+        assert!(!monitor.has_fault());
+    }
+
+    #[test]
+    fn no_fault_when_not_driving() {
+        let mut monitor = HallMonitor::new(UPDATE_RATE_HZ);
+        assert!(!run(
+            &mut monitor,
+            HallStatus::Invalid(0b111),
+            false,
+            FAULT_SAMPLES * 2
+        ));
+        assert!(!monitor.has_fault());
+        assert_eq!(monitor.take_new_fault(), None);
+    }
+
+    #[test]
+    fn brief_glitches_dont_latch_fault() {
+        let mut monitor = HallMonitor::new(UPDATE_RATE_HZ);
+        for _ in 0..10 {
+            run(
+                &mut monitor,
+                HallStatus::Invalid(0b000),
+                true,
+                FAULT_SAMPLES - 1,
+            );
+            run(&mut monitor, HallStatus::Valid, true, 1);
+        }
+        assert!(!monitor.has_fault());
+        assert_eq!(monitor.diagnostics(), (10, 0));
+    }
+
+    #[test]
+    fn fault_time_independent_of_update_rate() {
+        let mut monitor = HallMonitor::new(UPDATE_RATE_HZ);
+        monitor.set_update_rate(UPDATE_RATE_HZ * 2);
+        assert!(!run(
+            &mut monitor,
+            HallStatus::Invalid(0b000),
+            true,
+            FAULT_SAMPLES * 2 - 1
+        ));
+        assert!(monitor.update(HallStatus::Invalid(0b000), true));
+
+        let mut monitor = HallMonitor::new(UPDATE_RATE_HZ / 2);
+        assert!(run(
+            &mut monitor,
+            HallStatus::Invalid(0b000),
+            true,
+            FAULT_SAMPLES / 2
+        ));
+    }
+
+    #[test]
+    fn latches_fault_while_driving() {
+        let mut monitor = HallMonitor::new(UPDATE_RATE_HZ);
+        assert!(!run(
+            &mut monitor,
+            HallStatus::Invalid(0b111),
+            true,
+            FAULT_SAMPLES - 1
+        ));
+        assert!(monitor.update(HallStatus::Invalid(0b111), true));
+        assert!(monitor.has_fault());
+        assert_eq!(
+            monitor.take_new_fault(),
+            Some(Fault::HallSensors { state: 0b111 })
+        );
+        // The fault is only reported once, and only latched once.
+        assert_eq!(monitor.take_new_fault(), None);
+        assert!(!run(
+            &mut monitor,
+            HallStatus::Invalid(0b111),
+            true,
+            FAULT_SAMPLES * 2
+        ));
+
+        // The fault stays latched even once the sensors recover.
+        run(&mut monitor, HallStatus::Valid, true, 10);
+        assert!(monitor.has_fault());
+
+        assert!(monitor.clear_fault());
+        assert!(!monitor.has_fault());
+        assert!(!monitor.clear_fault());
+        // The count starts again from scratch after clearing.
+        assert!(!monitor.update(HallStatus::Invalid(0b111), true));
+    }
+}
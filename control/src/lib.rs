@@ -0,0 +1,26 @@
+//! Motor control logic for the hoverboard firmware, kept separate from the hardware so that it can
+//! be tested on the host.
+
+#![no_std]
+
+mod battery;
+mod calibration;
+mod comms;
+pub mod commutation;
+mod current_limit;
+mod estimator;
+mod hall_monitor;
+mod link;
+mod position;
+mod velocity;
+
+pub use battery::{BatteryEvent, BatteryMonitor, DEFAULT_CUTOFF_MV, DEFAULT_WARNING_MV};
+pub use calibration::{Calibration, CalibrationStep, CALIBRATION_POWER};
+pub use comms::CommsMonitor;
+pub use current_limit::{CurrentLimiter, DEFAULT_CURRENT_LIMIT_MA};
+pub use estimator::VelocityEstimator;
+pub use hall_monitor::{HallMonitor, HallStatus};
+pub use link::{LinkEvent, LinkMonitor};
+pub use messages::Target;
+pub use position::{PositionController, GAIN_SCALE};
+pub use velocity::{VelocityController, VELOCITY_GAINS};
@@ -0,0 +1,154 @@
+//! Watches the serial link between the primary and secondary boards, which each send the other a
+//! heartbeat periodically.
+
+/// How often to send a heartbeat to the other board.
+const HEARTBEAT_INTERVAL_MS: u32 = 100;
+
+/// How long to go without a heartbeat from the other board before deciding the link is lost.
+const LINK_TIMEOUT_MS: u32 = 500;
+
+/// A change in the state of the link, which the main loop should act on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LinkEvent {
+    /// No heartbeat has been received for `LINK_TIMEOUT_MS`, so the motor should be stopped.
+    Lost,
+    /// A heartbeat has been received after the link was lost.
+    Restored,
+}
+
+/// Keeps track of heartbeats from the other board, and when to send the next one.
+#[derive(Debug, Default)]
+pub struct LinkMonitor {
+    /// The timestamp at which the last heartbeat was received, or `None` if the link has never been
+    /// established. The link isn't reported lost until it has been up, so that a board can be used
+    /// without the other one.
+    last_heartbeat_time: Option<u32>,
+    /// Whether a heartbeat has been received since the last poll.
+    heartbeat_received: bool,
+    /// Whether the link has been lost, and not restored since.
+    lost: bool,
+    /// The timestamp at which to next send a heartbeat.
+    next_send_time: u32,
+}
+
+impl LinkMonitor {
+    /// Records that a heartbeat has been received from the other board.
+    pub fn heartbeat_received(&mut self) {
+        self.heartbeat_received = true;
+    }
+
+    /// Returns whether the link has been lost, and not restored since.
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    /// Returns whether it is time to send a heartbeat to the other board. Heartbeats should still
+    /// be sent while the link is lost, so that the other board can tell when it is restored.
+    pub fn heartbeat_due(&mut self, current_time: u32) -> bool {
+        if current_time >= self.next_send_time {
+            self.next_send_time = current_time + HEARTBEAT_INTERVAL_MS;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Checks whether the link has been lost or restored since the last poll.
+    pub fn poll(&mut self, current_time: u32) -> Option<LinkEvent> {
+        if self.heartbeat_received {
+            self.heartbeat_received = false;
+            self.last_heartbeat_time = Some(current_time);
+            if self.lost {
+                self.lost = false;
+                return Some(LinkEvent::Restored);
+            }
+        } else if let Some(last_heartbeat_time) = self.last_heartbeat_time {
+            if !self.lost && current_time >= last_heartbeat_time + LINK_TIMEOUT_MS {
+                self.lost = true;
+                return Some(LinkEvent::Lost);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_up_with_heartbeats() {
+        let mut link = LinkMonitor::default();
+        for time in (1000..5000).step_by(HEARTBEAT_INTERVAL_MS as usize) {
+            link.heartbeat_received();
+            assert_eq!(link.poll(time), None);
+            assert_eq!(link.poll(time + HEARTBEAT_INTERVAL_MS - 1), None);
+        }
+        assert!(!link.is_lost());
+    }
+
+    #[test]
+    fn lost_after_timeout() {
+        let mut link = LinkMonitor::default();
+        link.heartbeat_received();
+        assert_eq!(link.poll(1000), None);
+        assert_eq!(link.poll(1000 + LINK_TIMEOUT_MS - 1), None);
+        assert_eq!(link.poll(1000 + LINK_TIMEOUT_MS), Some(LinkEvent::Lost));
+        assert!(link.is_lost());
+        // Only reported once.
+        assert_eq!(link.poll(1000 + 2 * LINK_TIMEOUT_MS), None);
+        assert!(link.is_lost());
+    }
+
+    #[test]
+    fn not_lost_if_never_up() {
+        let mut link = LinkMonitor::default();
+        assert_eq!(link.poll(0), None);
+        assert_eq!(link.poll(2000 + LINK_TIMEOUT_MS), None);
+        assert_eq!(link.poll(1_000_000), None);
+        assert!(!link.is_lost());
+    }
+
+    #[test]
+    fn first_heartbeat_starts_timeout() {
+        let mut link = LinkMonitor::default();
+        assert_eq!(link.poll(0), None);
+        // The first heartbeat isn't a restoration, as the link was never lost.
+        link.heartbeat_received();
+        assert_eq!(link.poll(2000), None);
+        assert_eq!(link.poll(2000 + LINK_TIMEOUT_MS - 1), None);
+        assert_eq!(link.poll(2000 + LINK_TIMEOUT_MS), Some(LinkEvent::Lost));
+    }
+
+    #[test]
+    fn restored_by_heartbeat() {
+        let mut link = LinkMonitor::default();
+        link.heartbeat_received();
+        assert_eq!(link.poll(0), None);
+        assert_eq!(link.poll(LINK_TIMEOUT_MS), Some(LinkEvent::Lost));
+        link.heartbeat_received();
+        assert_eq!(link.poll(2000), Some(LinkEvent::Restored));
+        assert!(!link.is_lost());
+        assert_eq!(link.poll(2000 + LINK_TIMEOUT_MS - 1), None);
+        // The timeout starts again from the heartbeat which restored it.
+        assert_eq!(link.poll(2000 + LINK_TIMEOUT_MS), Some(LinkEvent::Lost));
+    }
+
+    #[test]
+    fn heartbeat_due_at_interval() {
+        let mut link = LinkMonitor::default();
+        assert!(link.heartbeat_due(1000));
+        assert!(!link.heartbeat_due(1000));
+        assert!(!link.heartbeat_due(1000 + HEARTBEAT_INTERVAL_MS - 1));
+        assert!(link.heartbeat_due(1000 + HEARTBEAT_INTERVAL_MS));
+    }
+
+    #[test]
+    fn heartbeat_due_while_lost() {
+        let mut link = LinkMonitor::default();
+        link.heartbeat_received();
+        assert_eq!(link.poll(0), None);
+        assert_eq!(link.poll(LINK_TIMEOUT_MS), Some(LinkEvent::Lost));
+        assert!(link.heartbeat_due(LINK_TIMEOUT_MS));
+    }
+}
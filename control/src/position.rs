@@ -0,0 +1,171 @@
+use messages::{Gains, TorqueLimits};
+
+/// The gains are in hundredths, so this is the value of a gain of 1.
+pub const GAIN_SCALE: i64 = 100;
+
+pub(crate) const MILLIS_PER_SECOND: i64 = 1000;
+
+/// A PID controller which drives the motor towards a target position.
+///
+/// The derivative term acts on the measured velocity rather than the change in error, so it damps
+/// motion without kicking when the target jumps.
+#[derive(Clone, Debug)]
+pub struct PositionController {
+    gains: Gains,
+    /// The accumulated integral term, in units of torque scaled by `GAIN_SCALE` and
+    /// `MILLIS_PER_SECOND`.
+    integral: i64,
+}
+
+impl PositionController {
+    pub fn new(gains: Gains) -> Self {
+        Self { gains, integral: 0 }
+    }
+
+    pub fn gains(&self) -> Gains {
+        self.gains
+    }
+
+    pub fn set_gains(&mut self, gains: Gains) {
+        self.gains = gains;
+    }
+
+    /// Forget the accumulated integral term, e.g. because there is no longer a target.
+    pub fn reset(&mut self) {
+        self.integral = 0;
+    }
+
+    /// Returns the torque to apply to move from `position` towards `target`, given the current
+    /// velocity in steps per second and the time in milliseconds since the last update.
+    ///
+    /// The integral term alone is limited to the torque limits, so that it doesn't wind up while
+    /// the output is saturated.
+    pub fn update(
+        &mut self,
+        target: i64,
+        position: i64,
+        velocity: i32,
+        torque_limits: TorqueLimits,
+        dt_ms: u32,
+    ) -> i16 {
+        let error = target.saturating_sub(position);
+
+        let integral_limit = MILLIS_PER_SECOND * GAIN_SCALE;
+        self.integral = self.integral.saturating_add(
+            error
+                .saturating_mul(self.gains.ki.into())
+                .saturating_mul(dt_ms.into()),
+        );
+        self.integral = self.integral.clamp(
+            i64::from(torque_limits.negative) * integral_limit,
+            i64::from(torque_limits.positive) * integral_limit,
+        );
+
+        let proportional = error.saturating_mul(self.gains.kp.into());
+        let integral = self.integral / MILLIS_PER_SECOND;
+        let derivative = -i64::from(velocity) * i64::from(self.gains.kd);
+        let torque = proportional
+            .saturating_add(integral)
+            .saturating_add(derivative)
+            / GAIN_SCALE;
+        torque.clamp(torque_limits.negative.into(), torque_limits.positive.into()) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const LIMITS: TorqueLimits = TorqueLimits {
+        negative: -200,
+        positive: 200,
+    };
+
+    fn controller(kp: u16, ki: u16, kd: u16) -> PositionController {
+        PositionController::new(Gains { kp, ki, kd })
+    }
+
+    #[test_case(0, 0 ; "zero")]
+    #[test_case(5, 50 ; "positive")]
+    #[test_case(-5, -50 ; "negative")]
+    #[test_case(100, 200 ; "saturates positive")]
+    #[test_case(-100, -200 ; "saturates negative")]
+    fn proportional(error: i64, expected_torque: i16) {
+        let mut controller = controller(1000, 0, 0);
+        assert_eq!(controller.update(error, 0, 0, LIMITS, 1), expected_torque);
+    }
+
+    #[test]
+    fn asymmetric_limits() {
+        let limits = TorqueLimits {
+            negative: -200,
+            positive: 30,
+        };
+        let mut controller = controller(1000, 0, 0);
+        assert_eq!(controller.update(10, 0, 0, limits, 1), 30);
+        assert_eq!(controller.update(-10, 0, 0, limits, 1), -100);
+    }
+
+    #[test]
+    fn derivative_opposes_velocity() {
+        let mut controller = controller(0, 0, 50);
+        assert_eq!(controller.update(0, 0, 100, LIMITS, 1), -50);
+        assert_eq!(controller.update(0, 0, -100, LIMITS, 1), 50);
+    }
+
+    #[test]
+    fn derivative_damps_approach() {
+        let mut controller = controller(1000, 0, 50);
+        // Moving towards the target quickly should reduce the torque pushing towards it.
+        assert_eq!(controller.update(10, 0, 100, LIMITS, 1), 50);
+    }
+
+    #[test]
+    fn integral_accumulates() {
+        let mut controller = controller(0, 100, 0);
+        // 1 torque per step of error per second, so 10 steps of error for 100 ms gives 1.
+        for _ in 0..99 {
+            controller.update(10, 0, 0, LIMITS, 1);
+        }
+        assert_eq!(controller.update(10, 0, 0, LIMITS, 1), 1);
+        for _ in 0..900 {
+            controller.update(10, 0, 0, LIMITS, 1);
+        }
+        assert_eq!(controller.update(10, 0, 0, LIMITS, 1), 10);
+    }
+
+    #[test]
+    fn integral_scales_with_elapsed_time() {
+        let mut controller = controller(0, 100, 0);
+        // One update after 100 ms should accumulate as much as 100 updates after 1 ms each.
+        assert_eq!(controller.update(10, 0, 0, LIMITS, 100), 1);
+        assert_eq!(controller.update(10, 0, 0, LIMITS, 0), 1);
+        assert_eq!(controller.update(10, 0, 0, LIMITS, 900), 10);
+    }
+
+    #[test]
+    fn integral_windup_limited() {
+        let mut controller = controller(0, 1000, 0);
+        for _ in 0..100_000 {
+            controller.update(100, 0, 0, LIMITS, 1);
+        }
+        assert_eq!(controller.update(100, 0, 0, LIMITS, 1), 200);
+        // Once the error reverses, the integral should start unwinding immediately rather than
+        // having to work off a huge accumulated value.
+        for _ in 0..100 {
+            controller.update(-100, 0, 0, LIMITS, 1);
+        }
+        assert_eq!(controller.update(-100, 0, 0, LIMITS, 1), 99);
+    }
+
+    #[test]
+    fn reset_clears_integral() {
+        let mut controller = controller(0, 1000, 0);
+        for _ in 0..100 {
+            controller.update(10, 0, 0, LIMITS, 1);
+        }
+        controller.reset();
+        assert_eq!(controller.update(0, 0, 0, LIMITS, 1), 0);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::position::{GAIN_SCALE, MILLIS_PER_SECOND};
+use messages::{Gains, TorqueLimits};
+
+/// The gains to use for the velocity controller, in hundredths. The proportional gain is in torque
+/// per step per second of velocity error, and the integral gain in torque per step of accumulated
+/// error. There is no derivative term, as the velocity estimate is too noisy to differentiate.
+pub const VELOCITY_GAINS: Gains = Gains {
+    kp: 50,
+    ki: 200,
+    kd: 0,
+};
+
+/// A PI controller which drives the motor at a target velocity.
+#[derive(Clone, Debug)]
+pub struct VelocityController {
+    gains: Gains,
+    /// The accumulated integral term, in units of torque scaled by `GAIN_SCALE` and
+    /// `MILLIS_PER_SECOND`.
+    integral: i64,
+}
+
+impl VelocityController {
+    pub fn new(gains: Gains) -> Self {
+        Self { gains, integral: 0 }
+    }
+
+    pub fn gains(&self) -> Gains {
+        self.gains
+    }
+
+    pub fn set_gains(&mut self, gains: Gains) {
+        self.gains = gains;
+    }
+
+    /// Forget the accumulated integral term, e.g. because the motor is no longer in velocity mode.
+    pub fn reset(&mut self) {
+        self.integral = 0;
+    }
+
+    /// Returns the torque to apply to move from `velocity` towards `target`, both in steps per
+    /// second, given the time in milliseconds since the last update.
+    ///
+    /// The integral term is limited to the torque limits, so that it doesn't wind up while the
+    /// output is saturated.
+    pub fn update(
+        &mut self,
+        target: i32,
+        velocity: i32,
+        torque_limits: TorqueLimits,
+        dt_ms: u32,
+    ) -> i16 {
+        let error = i64::from(target) - i64::from(velocity);
+
+        let integral_limit = MILLIS_PER_SECOND * GAIN_SCALE;
+        self.integral = self.integral.saturating_add(
+            error
+                .saturating_mul(self.gains.ki.into())
+                .saturating_mul(dt_ms.into()),
+        );
+        self.integral = self.integral.clamp(
+            i64::from(torque_limits.negative) * integral_limit,
+            i64::from(torque_limits.positive) * integral_limit,
+        );
+
+        let proportional = error.saturating_mul(self.gains.kp.into());
+        let integral = self.integral / MILLIS_PER_SECOND;
+        let torque = proportional.saturating_add(integral) / GAIN_SCALE;
+        torque.clamp(torque_limits.negative.into(), torque_limits.positive.into()) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: TorqueLimits = TorqueLimits {
+        negative: -200,
+        positive: 30,
+    };
+
+    #[test]
+    fn proportional() {
+        let mut controller = VelocityController::new(Gains {
+            kp: 100,
+            ki: 0,
+            kd: 0,
+        });
+        assert_eq!(controller.update(0, 0, LIMITS, 1), 0);
+        assert_eq!(controller.update(20, 0, LIMITS, 1), 20);
+        assert_eq!(controller.update(20, 30, LIMITS, 1), -10);
+        assert_eq!(controller.update(100, 0, LIMITS, 1), 30);
+        assert_eq!(controller.update(-1000, 0, LIMITS, 1), -200);
+    }
+
+    #[test]
+    fn integral_holds_speed() {
+        let mut controller = VelocityController::new(VELOCITY_GAINS);
+        for _ in 0..1000 {
+            controller.update(10, 0, LIMITS, 1);
+        }
+        // Once at the target velocity, the integral term should keep driving the motor.
+        assert_eq!(controller.update(10, 10, LIMITS, 1), 20);
+    }
+
+    #[test]
+    fn integral_windup_limited() {
+        let mut controller = VelocityController::new(VELOCITY_GAINS);
+        for _ in 0..100_000 {
+            controller.update(-1000, 0, LIMITS, 1);
+        }
+        assert_eq!(controller.update(0, 0, LIMITS, 1), -200);
+        controller.reset();
+        assert_eq!(controller.update(0, 0, LIMITS, 1), 0);
+    }
+
+    #[test]
+    fn large_time_step() {
+        let mut controller = VelocityController::new(Gains {
+            kp: u16::MAX,
+            ki: u16::MAX,
+            kd: 0,
+        });
+        // A long gap between updates mustn't overflow the integral term.
+        assert_eq!(controller.update(i32::MAX, i32::MIN, LIMITS, u32::MAX), 30);
+        assert_eq!(
+            controller.update(i32::MIN, i32::MAX, LIMITS, u32::MAX),
+            -200
+        );
+    }
+}
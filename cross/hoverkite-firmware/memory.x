@@ -4,7 +4,8 @@ MEMORY
   /* TODO Adjust these memory regions to match your device memory layout */
   /* Memory layout for GD32F130C8T. */
   RAM (xrw)  : ORIGIN = 0x20000000, LENGTH = 8K
-  FLASH (rx) : ORIGIN = 0x8000000, LENGTH = 64K
+  /* The last 2K of the 64K flash is left out for storing config; see src/config.rs. */
+  FLASH (rx) : ORIGIN = 0x8000000, LENGTH = 62K
 }
 
 /* This is where the call stack will be allocated. */
@@ -0,0 +1,142 @@
+use crate::hoverboard::{Hoverboard, DEFAULT_MOTOR_TUNING};
+use control::{
+    BatteryMonitor, PositionController, VelocityController, DEFAULT_CURRENT_LIMIT_MA,
+    DEFAULT_CUTOFF_MV, DEFAULT_WARNING_MV, VELOCITY_GAINS,
+};
+use gd32f1x0_hal::flash::{self, FlashSize, FlashWriter, Parts, SectorSize};
+use messages::config::{find_latest, next_slot, StoredConfig};
+use messages::{Commutation, Gains, HallMapping, Role, TorqueLimits};
+
+/// The size of each page of flash, which is the unit it is erased in.
+const PAGE_LENGTH: usize = 1024;
+
+/// The number of pages at the end of flash used to store config. These must be left out of the
+/// flash region in `memory.x`, so that the program isn't put there.
+const CONFIG_PAGES: usize = 2;
+
+/// The total size of the flash.
+const FLASH_LENGTH: usize = 64 * 1024;
+
+const CONFIG_LENGTH: usize = CONFIG_PAGES * PAGE_LENGTH;
+
+/// The offset from the start of flash of the region used to store config.
+const CONFIG_OFFSET: u32 = (FLASH_LENGTH - CONFIG_LENGTH) as u32;
+
+/// The config to use if none has been saved.
+pub const DEFAULT_CONFIG: StoredConfig = StoredConfig {
+    torque_limits: TorqueLimits {
+        negative: -200,
+        positive: 200,
+    },
+    gains: Gains {
+        kp: 1000,
+        ki: 0,
+        kd: 20,
+    },
+    velocity_gains: VELOCITY_GAINS,
+    current_limit_ma: DEFAULT_CURRENT_LIMIT_MA,
+    battery_warning_mv: DEFAULT_WARNING_MV,
+    battery_cutoff_mv: DEFAULT_CUTOFF_MV,
+    commutation: Commutation::Block,
+    motor_tuning: DEFAULT_MOTOR_TUNING,
+    hall_mapping: HallMapping::DEFAULT,
+    role: None,
+};
+
+/// Saves config to the end of the on-chip flash, and loads it back again.
+pub struct ConfigStore {
+    flash: Parts,
+    /// The role to save, which takes effect the next time the board starts.
+    role: Option<Role>,
+}
+
+impl ConfigStore {
+    pub fn new(flash: Parts) -> Self {
+        Self { flash, role: None }
+    }
+
+    /// Returns the role to save.
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+
+    /// Sets the role to save, or `None` to have the board work out its role again when it next
+    /// starts.
+    pub fn set_role(&mut self, role: Option<Role>) {
+        self.role = role;
+    }
+
+    fn writer(&mut self) -> FlashWriter<'_> {
+        self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K)
+    }
+
+    /// Returns the config most recently saved, if there is any.
+    pub fn load(&mut self) -> Option<StoredConfig> {
+        let writer = self.writer();
+        let region = writer.read(CONFIG_OFFSET, CONFIG_LENGTH).ok()?;
+        find_latest(region).map(|(config, _)| config)
+    }
+
+    /// Saves the given config after the one most recently saved, erasing a page first if needed.
+    ///
+    /// The CPU stalls while the flash is being written, so this shouldn't be done while the motor
+    /// is being driven.
+    pub fn save(&mut self, config: &StoredConfig) -> Result<(), flash::Error> {
+        let mut writer = self.writer();
+        let (slot, erase) = next_slot(writer.read(CONFIG_OFFSET, CONFIG_LENGTH)?, PAGE_LENGTH);
+        let offset = CONFIG_OFFSET + slot.offset as u32;
+        if erase {
+            writer.page_erase(offset)?;
+        }
+        writer.write(offset, &config.to_record(slot.sequence))
+    }
+
+    /// Erases all saved config, including the role to save.
+    pub fn erase(&mut self) -> Result<(), flash::Error> {
+        self.role = None;
+        self.writer().erase(CONFIG_OFFSET, CONFIG_LENGTH)
+    }
+}
+
+/// Returns the config currently in use, with the given role.
+pub fn current_config(
+    hoverboard: &mut Hoverboard,
+    role: Option<Role>,
+    torque_limits: TorqueLimits,
+    position_controller: &PositionController,
+    velocity_controller: &VelocityController,
+    battery_monitor: &BatteryMonitor,
+) -> StoredConfig {
+    let (battery_warning_mv, battery_cutoff_mv) = battery_monitor.thresholds();
+    StoredConfig {
+        torque_limits,
+        gains: position_controller.gains(),
+        velocity_gains: velocity_controller.gains(),
+        current_limit_ma: hoverboard.current_limit(),
+        battery_warning_mv,
+        battery_cutoff_mv,
+        commutation: hoverboard.commutation(),
+        motor_tuning: hoverboard.motor_tuning(),
+        hall_mapping: hoverboard.hall_mapping(),
+        role,
+    }
+}
+
+/// Starts using the given config. The role can't change while running, so it's only used at boot.
+pub fn apply_config(
+    config: &StoredConfig,
+    hoverboard: &mut Hoverboard,
+    torque_limits: &mut TorqueLimits,
+    position_controller: &mut PositionController,
+    velocity_controller: &mut VelocityController,
+    battery_monitor: &mut BatteryMonitor,
+) {
+    *torque_limits = config.torque_limits;
+    position_controller.set_gains(config.gains);
+    velocity_controller.set_gains(config.velocity_gains);
+    hoverboard.set_current_limit(config.current_limit_ma);
+    battery_monitor.set_thresholds(config.battery_warning_mv, config.battery_cutoff_mv);
+    hoverboard.set_commutation(config.commutation);
+    hoverboard.set_motor_tuning(config.motor_tuning);
+    hoverboard.set_hall_mapping(config.hall_mapping);
+}
@@ -12,26 +12,74 @@ use gd32f1x0_hal::{
     rcu::{Clocks, APB2},
 };
 
-// TODO: Use this to calculate the motor current.
-#[allow(dead_code)]
-const CURRENT_OFFSET_DC: u16 = 1073;
+/// The resistance of the DC link current shunt. The shunt voltage is subtracted from a fixed offset
+/// of about a third of the supply voltage, so the measured voltage falls as the current rises.
+const CURRENT_SHUNT_MILLIOHMS: i32 = 4;
+
+/// The number of ADC samples to average to find the zero-current offset at boot.
+const CURRENT_OFFSET_SAMPLES: u32 = 1024;
 
 #[derive(Debug, Default, Clone)]
 pub struct AdcReadings {
     pub battery_voltage: u16,
-    pub motor_current: u16,
+    /// Motor current in milliamps, positive when drawing power from the battery.
+    pub motor_current: i32,
     pub backup_battery_voltage: u16,
 }
 
 impl AdcReadings {
-    fn update_from_buffer(&mut self, buffer: &[u16; 3], adc: &Adc) {
+    fn update_from_buffer(
+        &mut self,
+        buffer: &[u16; 3],
+        adc: &Adc,
+        current_sensor: &mut CurrentSensor,
+    ) {
         // TODO: Or is it better to just hardcode the ADC scaling factor?
         self.battery_voltage = adc.calculate_voltage(buffer[0]) * 30;
-        self.motor_current = adc.calculate_voltage(buffer[1]);
+        self.motor_current = current_sensor.current_ma(adc.calculate_voltage(buffer[1]));
         self.backup_battery_voltage = adc.calculate_voltage(buffer[2]) * 2;
     }
 }
 
+/// Converts the current shunt voltage to a current, calibrating the zero-current offset from the
+/// first samples after boot while the motor is disabled.
+#[derive(Debug, Default)]
+pub struct CurrentSensor {
+    /// The sum of the shunt voltages sampled so far during calibration, in millivolts.
+    offset_sum_mv: u32,
+    /// The number of samples taken so far during calibration.
+    offset_samples: u32,
+    /// The calibrated shunt voltage at zero current, in microvolts.
+    offset_uv: i32,
+}
+
+impl CurrentSensor {
+    /// Returns whether enough samples have been taken to know the zero-current offset.
+    pub fn is_calibrated(&self) -> bool {
+        self.offset_samples >= CURRENT_OFFSET_SAMPLES
+    }
+
+    /// The zero-current shunt voltage, in microvolts. This is only meaningful once calibrated.
+    pub fn offset_uv(&self) -> i32 {
+        self.offset_uv
+    }
+
+    /// Converts the given shunt voltage to a current in milliamps, or takes it as a calibration
+    /// sample and returns 0 if calibration is not yet finished.
+    fn current_ma(&mut self, voltage_mv: u16) -> i32 {
+        if !self.is_calibrated() {
+            self.offset_sum_mv += voltage_mv as u32;
+            self.offset_samples += 1;
+            if self.is_calibrated() {
+                self.offset_uv =
+                    (self.offset_sum_mv as u64 * 1000 / self.offset_samples as u64) as i32;
+            }
+            return 0;
+        }
+        (self.offset_uv - voltage_mv as i32 * 1000) / CURRENT_SHUNT_MILLIOHMS
+    }
+}
+
 pub enum AdcDmaState {
     NotStarted(AdcDma<Sequence, Scan>, &'static mut [u16; 3]),
     Started(Transfer<W, &'static mut [u16; 3], AdcDma<Sequence, Scan>>),
@@ -77,11 +125,15 @@ impl AdcDmaState {
     }
 
     /// Fetch ADC results from the DMA buffer, in response to a DMA interrupt.
-    pub fn read_dma_result(&mut self, result: &mut AdcReadings) {
+    pub fn read_dma_result(
+        &mut self,
+        result: &mut AdcReadings,
+        current_sensor: &mut CurrentSensor,
+    ) {
         self.with(move |adc_dma| {
             if let AdcDmaState::Started(transfer) = adc_dma {
                 let (buffer, adc_dma) = transfer.wait();
-                result.update_from_buffer(buffer, adc_dma.as_ref());
+                result.update_from_buffer(buffer, adc_dma.as_ref(), current_sensor);
                 AdcDmaState::NotStarted(adc_dma, buffer)
             } else {
                 adc_dma
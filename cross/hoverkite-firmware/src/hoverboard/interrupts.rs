@@ -1,5 +1,6 @@
-use super::adc::{AdcDmaState, AdcReadings};
+use super::adc::{AdcDmaState, AdcReadings, CurrentSensor};
 use super::motor::Motor;
+use control::{CurrentLimiter, HallMonitor};
 use core::cell::RefCell;
 use cortex_m::{
     interrupt::{free, Mutex},
@@ -14,6 +15,9 @@ pub struct Shared {
     pub motor: Motor,
     pub adc_dma: AdcDmaState,
     pub last_adc_readings: AdcReadings,
+    pub current_sensor: CurrentSensor,
+    pub current_limiter: CurrentLimiter,
+    pub hall_monitor: HallMonitor,
 }
 
 pub static SHARED: Mutex<RefCell<Option<Shared>>> = Mutex::new(RefCell::new(None));
@@ -39,19 +43,40 @@ fn DMA_Channel0() {
             // Fetch ADC readings from the DMA buffer.
             shared
                 .adc_dma
-                .read_dma_result(&mut shared.last_adc_readings);
+                .read_dma_result(&mut shared.last_adc_readings, &mut shared.current_sensor);
 
-            shared.motor.update();
+            shared
+                .current_limiter
+                .update(shared.last_adc_readings.motor_current);
+            shared.motor.power_limit = if shared.hall_monitor.has_fault() {
+                0
+            } else {
+                shared.current_limiter.power_limit()
+            };
+
+            // Keep the motor off until the current sensor has been calibrated.
+            if shared.current_sensor.is_calibrated() {
+                let hall_status = shared.motor.update();
+                let driving = shared.motor.is_driving();
+                if shared.hall_monitor.update(hall_status, driving) {
+                    // The motor won't turn its own output off while it can't tell where it is.
+                    shared.motor.pwm.output_disable();
+                }
+            }
         }
     });
 }
 
 pub fn unmask_interrupts(motor: Motor, adc_dma: AdcDmaState) {
+    let pwm_frequency_hz = motor.tuning().pwm_frequency_hz;
     free(move |cs| {
         SHARED.borrow(cs).replace(Some(Shared {
             motor,
             adc_dma,
             last_adc_readings: AdcReadings::default(),
+            current_sensor: CurrentSensor::default(),
+            current_limiter: CurrentLimiter::new(pwm_frequency_hz),
+            hall_monitor: HallMonitor::new(pwm_frequency_hz),
         }))
     });
 
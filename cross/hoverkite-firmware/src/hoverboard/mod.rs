@@ -9,12 +9,20 @@ use self::adc::AdcDmaState;
 pub use self::adc::AdcReadings;
 pub use self::buzzer::Buzzer;
 use self::interrupts::{unmask_interrupts, SHARED};
+pub use self::motor::DEFAULT_MOTOR_TUNING;
 use self::motor::{HallSensors, Motor};
-use self::serial::{setup_usart0_buffered_writer, setup_usart1_buffered_writer};
+use self::serial::{
+    setup_usart0_buffered_reader, setup_usart0_buffered_writer, setup_usart1_buffered_reader,
+    setup_usart1_buffered_writer,
+};
+pub use self::serial::{SerialReader, SerialWriter};
+use self::util::buffered_rx::BufferedSerialReader;
 use self::util::buffered_tx::BufferedSerialWriter;
 use crate::log;
+use crate::protocol::{self, role};
 use bmi160::{
-    interface::I2cInterface, AccelerometerPowerMode, Bmi160, GyroscopePowerMode, SlaveAddr,
+    interface::I2cInterface, AccelerometerPowerMode, Bmi160, GyroscopePowerMode, Sensor3DData,
+    SensorSelector, SlaveAddr,
 };
 use cortex_m::{interrupt::free, peripheral::DWT};
 use gd32f1x0_hal::{
@@ -25,16 +33,16 @@ use gd32f1x0_hal::{
         gpiof::PF0,
         Alternate, Floating, Input, Output, OutputMode, PullMode, PullUp, PushPull, AF1,
     },
-    i2c::{BlockingI2c, Mode},
+    i2c::{self, BlockingI2c, Mode},
     pac::{Adc, Dma, Gpioa, Gpiob, Gpioc, Gpiof, I2c0, Timer0, Timer1, Usart0, Usart1},
     prelude::*,
     pwm::Channel,
     rcu::{Clocks, AHB, APB1, APB2},
-    serial::{Config, Rx, Serial, Tx},
+    serial::{Config, Rx, Serial, TxDma0, TxDma1},
 };
+use messages::{Commutation, Fault, HallMapping, MotorTuning, Role};
 
 const USART_BAUD_RATE: u32 = 115200;
-const MOTOR_PWM_FREQ_HERTZ: u32 = 16000;
 
 // These settings are fairly arbitrary, but they seem to work.
 const I2C_FREQUENCY_HERTZ: u32 = 400_000;
@@ -43,6 +51,9 @@ const I2C_START_RETRIES: u8 = 3;
 const I2C_ADDR_TIMEOUT_US: u32 = 1000;
 const I2C_DATA_TIMEOUT_US: u32 = 1000;
 
+/// How long to wait for the current sensor zero-current offset to be calibrated at boot.
+const CURRENT_CALIBRATION_TIMEOUT_MS: u32 = 500;
+
 pub struct Leds {
     pub side: PA0<Output<PushPull>>,
     pub green: PA15<Output<PushPull>>,
@@ -50,11 +61,20 @@ pub struct Leds {
     pub red: PB3<Output<PushPull>>,
 }
 
+/// Raw readings from the IMU, in X, Y, Z order.
+#[derive(Debug, Default, Clone)]
+pub struct ImuReadings {
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+}
+
 pub struct Hoverboard {
-    pub serial_remote_rx: Rx<Usart0>,
-    pub serial_remote_writer: BufferedSerialWriter<Tx<Usart0>>,
-    pub serial_rx: Rx<Usart1>,
-    pub serial_writer: BufferedSerialWriter<Tx<Usart1>>,
+    pub serial_remote_rx: BufferedSerialReader<Rx<Usart0>>,
+    pub serial_remote_writer: BufferedSerialWriter<TxDma0>,
+    pub serial_rx: BufferedSerialReader<Rx<Usart1>>,
+    pub serial_writer: BufferedSerialWriter<TxDma1>,
+    /// The writer for responses to the host, which depends on the role.
+    response_writer: SerialWriter,
     pub imu: Bmi160<I2cInterface<BlockingI2c<I2c0, PB8<Alternate<AF1>>, PB9<Alternate<AF1>>>>>,
     pub buzzer: Buzzer,
     pub power_latch: PB2<Output<PushPull>>,
@@ -85,13 +105,16 @@ impl Hoverboard {
         apb2: &mut APB2,
         dwt: &mut DWT,
         clocks: Clocks,
-        negate_motor: bool,
+        role: Option<Role>,
     ) -> Hoverboard {
         let mut gpioa = gpioa.split(ahb);
         let mut gpiob = gpiob.split(ahb);
         let mut gpioc = gpioc.split(ahb);
         let mut gpiof = gpiof.split(ahb);
 
+        // DMA controller
+        let dma = dma.split(ahb);
+
         // USART0
         let tx0 =
             gpiob
@@ -112,7 +135,8 @@ impl Hoverboard {
             apb2,
         )
         .split();
-        let serial_remote_writer = setup_usart0_buffered_writer(serial_remote_tx);
+        let serial_remote_writer = setup_usart0_buffered_writer(serial_remote_tx, dma.1);
+        let serial_remote_rx = setup_usart0_buffered_reader(serial_remote_rx);
 
         // USART1
         let tx1 =
@@ -134,7 +158,15 @@ impl Hoverboard {
             apb1,
         )
         .split();
-        let mut serial_writer = setup_usart1_buffered_writer(serial_tx);
+        let serial_writer = setup_usart1_buffered_writer(serial_tx, dma.3);
+        let serial_rx = setup_usart1_buffered_reader(serial_rx);
+
+        // Until the role is known there's nowhere to send responses, so they are dropped.
+        let mut response_writer = SerialWriter::None;
+        if let Some(role) = role {
+            protocol::set_role(role);
+            response_writer = role_response_writer(role, &serial_remote_writer, &serial_writer);
+        }
 
         // I2C0
         let scl =
@@ -164,22 +196,21 @@ impl Hoverboard {
         let mut imu = Bmi160::new_with_i2c(i2c, SlaveAddr::Default);
         if let Err(e) = imu.set_accel_power_mode(AccelerometerPowerMode::Normal) {
             log!(
-                &mut serial_writer,
+                &mut response_writer,
+                Error,
                 "Error setting accelerometer power mode: {:?}",
                 e
             );
         }
         if let Err(e) = imu.set_gyro_power_mode(GyroscopePowerMode::Normal) {
             log!(
-                &mut serial_writer,
+                &mut response_writer,
+                Error,
                 "Error setting gyroscope power mode: {:?}",
                 e
             );
         }
 
-        // DMA controller
-        let dma = dma.split(ahb);
-
         // ADC
         let battery_voltage = gpioa.pa4.into_analog(&mut gpioa.config);
         let motor_current = gpioa.pa6.into_analog(&mut gpioa.config);
@@ -227,7 +258,6 @@ impl Hoverboard {
         );
         let motor = Motor::new(
             timer0,
-            MOTOR_PWM_FREQ_HERTZ.hz(),
             clocks,
             motor_pins,
             emergency_off,
@@ -244,11 +274,46 @@ impl Hoverboard {
 
         unmask_interrupts(motor, adc_dma);
 
+        // Wait for the current sensor offset to be calibrated before anything might turn on the
+        // motor. If the ADC isn't producing samples, give up rather than hanging here forever; the
+        // motor stays off until calibration finishes, but the rest of the firmware can still run.
+        let calibration_start = DWT::cycle_count();
+        let calibration_timeout = clocks.sysclk().0 / 1000 * CURRENT_CALIBRATION_TIMEOUT_MS;
+        let current_offset_uv = loop {
+            let offset = free(|cs| {
+                let shared = &*SHARED.borrow(cs).borrow();
+                let current_sensor = &shared.as_ref().unwrap().current_sensor;
+                current_sensor
+                    .is_calibrated()
+                    .then(|| current_sensor.offset_uv())
+            });
+            if offset.is_some()
+                || DWT::cycle_count().wrapping_sub(calibration_start) > calibration_timeout
+            {
+                break offset;
+            }
+        };
+        if let Some(current_offset_uv) = current_offset_uv {
+            log!(
+                &mut response_writer,
+                Info,
+                "Motor current offset {} uV",
+                current_offset_uv
+            );
+        } else {
+            log!(
+                &mut response_writer,
+                Error,
+                "Timed out calibrating motor current sensor"
+            );
+        }
+
         Hoverboard {
             serial_remote_rx,
             serial_remote_writer,
             serial_rx,
             serial_writer,
+            response_writer,
             imu,
             buzzer,
             power_latch: gpiob.pb2.into_push_pull_output(&mut gpiob.config),
@@ -260,10 +325,37 @@ impl Hoverboard {
                 orange: gpioa.pa12.into_push_pull_output(&mut gpioa.config),
                 red: gpiob.pb3.into_push_pull_output(&mut gpiob.config),
             },
-            negate_motor,
+            // The secondary is mounted the other way round, so turns the other way.
+            negate_motor: role == Some(Role::Secondary),
+        }
+    }
+
+    /// Set the role once it has been worked out, if it wasn't known at boot. This must only be
+    /// called once.
+    pub fn set_role(&mut self, role: Role) {
+        protocol::set_role(role);
+        self.response_writer =
+            role_response_writer(role, &self.serial_remote_writer, &self.serial_writer);
+        if role == Role::Secondary {
+            self.negate_motor = !self.negate_motor;
+        }
+    }
+
+    /// Get the receiver for commands: from the host for the primary, or forwarded from the primary
+    /// for the secondary. This must only be called once the role is known.
+    pub fn command_rx(&mut self) -> SerialReader<'_> {
+        match role().unwrap() {
+            Role::Primary => SerialReader::Usart0(&mut self.serial_remote_rx),
+            Role::Secondary => SerialReader::Usart1(&mut self.serial_rx),
         }
     }
 
+    /// Get the writer for responses to the host, directly for the primary or via the primary for
+    /// the secondary.
+    pub fn response_tx(&mut self) -> &mut SerialWriter {
+        &mut self.response_writer
+    }
+
     pub fn adc_readings(&self) -> AdcReadings {
         free(|cs| {
             if let Some(shared) = &mut *SHARED.borrow(cs).borrow_mut() {
@@ -274,6 +366,15 @@ impl Hoverboard {
         })
     }
 
+    /// Read the current accelerometer and gyroscope values from the IMU.
+    pub fn imu_readings(&mut self) -> Result<ImuReadings, bmi160::Error<i2c::Error>> {
+        let data = self.imu.data(SensorSelector::new().accel().gyro())?;
+        Ok(ImuReadings {
+            accel: data.accel.map_or([0; 3], sensor_data_to_array),
+            gyro: data.gyro.map_or([0; 3], sensor_data_to_array),
+        })
+    }
+
     /// Get the current position of the motor.
     pub fn motor_position(&self) -> i64 {
         free(|cs| {
@@ -289,6 +390,21 @@ impl Hoverboard {
         })
     }
 
+    /// Get the current velocity of the motor, in steps per second.
+    pub fn motor_velocity(&self) -> i32 {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            if self.negate_motor {
+                -shared.motor.velocity()
+            } else {
+                shared.motor.velocity()
+            }
+        })
+    }
+
     /// Set the desired power for the motor.
     pub fn set_motor_power(&mut self, power: i16) {
         free(|cs| {
@@ -300,6 +416,177 @@ impl Hoverboard {
         })
     }
 
+    /// Set the maximum motor current in milliamps.
+    pub fn set_current_limit(&mut self, current_limit_ma: u16) {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.current_limiter.set_limit(current_limit_ma);
+        })
+    }
+
+    /// Get the maximum motor current in milliamps.
+    pub fn current_limit(&mut self) -> u16 {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.current_limiter.limit()
+        })
+    }
+
+    /// Set how the motor phases are driven from the rotor position.
+    pub fn set_commutation(&mut self, commutation: Commutation) {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.motor.commutation = commutation;
+        })
+    }
+
+    /// Get the motor tuning currently in use.
+    pub fn motor_tuning(&mut self) -> MotorTuning {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.motor.tuning()
+        })
+    }
+
+    /// Change the motor tuning, returning what was actually applied after limiting it.
+    pub fn set_motor_tuning(&mut self, tuning: MotorTuning) -> MotorTuning {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.motor.set_tuning(tuning);
+            // Keep the fault times the same, as they are counted in PWM cycles.
+            let pwm_frequency_hz = shared.motor.tuning().pwm_frequency_hz;
+            shared.current_limiter.set_sample_rate(pwm_frequency_hz);
+            shared.hall_monitor.set_update_rate(pwm_frequency_hz);
+            shared.motor.tuning()
+        })
+    }
+
+    /// Get how the motor phases are driven from the rotor position.
+    pub fn commutation(&mut self) -> Commutation {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.motor.commutation
+        })
+    }
+
+    /// Get the mapping from Hall sensor states to sectors currently in use.
+    pub fn hall_mapping(&mut self) -> HallMapping {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.motor.hall_mapping
+        })
+    }
+
+    /// Get the last raw reading from the Hall sensors.
+    pub fn hall_state(&mut self) -> u8 {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.motor.hall_state
+        })
+    }
+
+    /// Drive the phases at the given electrical angle and power, regardless of the rotor position.
+    pub fn drive_open_loop(&mut self, angle: u16, power: i16) {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.motor.open_loop_angle = Some(angle);
+            shared.motor.target_power = power;
+        })
+    }
+
+    /// Stop driving the phases open-loop, and turn the motor off until the next power is set.
+    pub fn stop_open_loop(&mut self) {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.motor.open_loop_angle = None;
+            shared.motor.target_power = 0;
+        })
+    }
+
+    /// Use the given mapping from Hall sensor states to sectors.
+    ///
+    /// If it turns the motor the opposite way to the mapping in use before, the motor readings and
+    /// power are negated from then on so that it still turns the same way as before.
+    pub fn set_hall_mapping(&mut self, hall_mapping: HallMapping) {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            if hall_mapping.is_reversed() != shared.motor.hall_mapping.is_reversed() {
+                self.negate_motor = !self.negate_motor;
+            }
+            shared.motor.hall_mapping = hall_mapping;
+        })
+    }
+
+    /// Returns a fault which has been latched since this was last called, if any.
+    pub fn take_new_fault(&mut self) -> Option<Fault> {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared
+                .current_limiter
+                .take_new_fault()
+                .or_else(|| shared.hall_monitor.take_new_fault())
+        })
+    }
+
+    /// Clear any latched fault so the motor can run again, returning whether there was one.
+    pub fn clear_fault(&mut self) -> bool {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.current_limiter.clear_fault() | shared.hall_monitor.clear_fault()
+        })
+    }
+
+    /// Returns the number of times the Hall sensors have gone into an invalid state, and the number
+    /// of times they have skipped over a sector.
+    pub fn hall_diagnostics(&mut self) -> (u32, u32) {
+        free(|cs| {
+            // SHARED must have been initialised by the time this is called.
+            let shared = &mut *SHARED.borrow(cs).borrow_mut();
+            let shared = shared.as_mut().unwrap();
+
+            shared.hall_monitor.diagnostics()
+        })
+    }
+
     /// Set the motor's current position as 0.
     pub fn recenter_motor(&mut self) {
         free(|cs| {
@@ -327,3 +614,20 @@ impl Hoverboard {
         })
     }
 }
+
+/// Returns the writer for responses to the host for the given role: USART0 for the primary, which
+/// is connected to the host, or USART1 for the secondary, which is connected to the primary.
+fn role_response_writer(
+    role: Role,
+    serial_remote_writer: &BufferedSerialWriter<TxDma0>,
+    serial_writer: &BufferedSerialWriter<TxDma1>,
+) -> SerialWriter {
+    match role {
+        Role::Primary => SerialWriter::Usart0(serial_remote_writer.clone()),
+        Role::Secondary => SerialWriter::Usart1(serial_writer.clone()),
+    }
+}
+
+fn sensor_data_to_array(data: Sensor3DData) -> [i16; 3] {
+    [data.x, data.y, data.z]
+}
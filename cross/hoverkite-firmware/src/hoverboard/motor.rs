@@ -1,4 +1,8 @@
 use crate::util::clamp;
+use control::commutation::{block_phase_powers, duty_cycles, sinusoidal_phase_powers};
+use control::{HallStatus, VelocityEstimator};
+use core::ops::RangeInclusive;
+use cortex_m::peripheral::DWT;
 use embedded_hal::digital::InputPin;
 use gd32f1x0_hal::{
     gpio::{
@@ -14,13 +18,22 @@ use gd32f1x0_hal::{
     time::Hertz,
     timer::{Event, Timer},
 };
+use messages::{Commutation, HallMapping, MotorTuning};
 
-/// The minimum number of timer interrupt cycles to wait between increasing the motor power by one
-/// step.
-const MOTOR_POWER_SMOOTHING_CYCLES_PER_STEP: u32 = 5;
+/// The motor tuning to use until it is set.
+pub const DEFAULT_MOTOR_TUNING: MotorTuning = MotorTuning {
+    ramp_cycles_per_step: 5,
+    ramp_down: false,
+    dead_zone: 10,
+    pwm_frequency_hz: 16000,
+};
+
+/// The PWM frequencies which may be set. Much lower is audible and makes the current ripple worse,
+/// while much higher wastes more power switching and leaves fewer steps of duty cycle.
+const PWM_FREQUENCY_RANGE_HZ: RangeInclusive<u32> = 8000..=32000;
 
-/// If the motor power is below this level, don't bother running it at all.
-const MOTOR_POWER_DEAD_ZONE: i16 = 10;
+/// The highest dead zone which may be set, as a motor power.
+const MAX_DEAD_ZONE: u16 = 1000;
 
 pub struct HallSensors {
     hall_a: PB11<Input<Floating>>,
@@ -41,35 +54,38 @@ impl HallSensors {
         }
     }
 
-    /// Get the current position of the motor from the hall effoct sensors, or `None` if they are in
-    /// an invalid configuration.
-    ///
-    /// The position will be in the range 0-5, inclusive.
-    pub fn position(&mut self) -> Option<u8> {
+    /// Get the raw state of the hall effect sensors, with A in bit 2, B in bit 1 and C in bit 0.
+    pub fn state(&mut self) -> u8 {
         let hall_a = self.hall_a.is_high().unwrap();
         let hall_b = self.hall_b.is_high().unwrap();
         let hall_c = self.hall_c.is_high().unwrap();
-        match (hall_a, hall_b, hall_c) {
-            (false, false, true) => Some(0),
-            (true, false, true) => Some(1),
-            (true, false, false) => Some(2),
-            (true, true, false) => Some(3),
-            (false, true, false) => Some(4),
-            (false, true, true) => Some(5),
-            _ => None,
-        }
+        u8::from(hall_a) << 2 | u8::from(hall_b) << 1 | u8::from(hall_c)
     }
 }
 
 pub struct Motor {
     pub pwm: Pwm<Timer0, OptionalPins>,
     hall_sensors: HallSensors,
+    /// Which Hall sensor state goes with each sector.
+    pub hall_mapping: HallMapping,
+    /// The last raw reading from the Hall sensors.
+    pub hall_state: u8,
     /// The absolute position of the motor.
     pub position: i64,
     /// The last valid reading from the Hall sensors.
     last_hall_position: Option<u8>,
+    velocity: VelocityEstimator,
     /// The desired motor power.
     pub target_power: i16,
+    /// The maximum magnitude of motor power allowed, regardless of the target.
+    pub power_limit: i16,
+    /// How to drive the phases from the rotor position.
+    pub commutation: Commutation,
+    /// If this is set, the phases are driven at this electrical angle regardless of the rotor
+    /// position, e.g. while calibrating.
+    pub open_loop_angle: Option<u16>,
+    /// How the motor power is turned into PWM output.
+    tuning: MotorTuning,
     /// The last set motor power.
     power: i16,
     /// The number of timer cycles since the motor power was last changed.
@@ -132,52 +148,64 @@ fn setup_pwm(
 impl Motor {
     pub fn new(
         timer: Timer0,
-        pwm_frequency: Hertz,
         clocks: Clocks,
         pins: Pins,
         emergency_off: PB12<Alternate<AF2>>,
         apb2: &mut APB2,
         hall_sensors: HallSensors,
     ) -> Self {
-        let pwm = setup_pwm(timer, pwm_frequency, clocks, pins, apb2);
+        let pwm = setup_pwm(
+            timer,
+            Hertz(DEFAULT_MOTOR_TUNING.pwm_frequency_hz),
+            clocks,
+            pins,
+            apb2,
+        );
         Self {
             pwm,
             hall_sensors,
+            hall_mapping: HallMapping::default(),
+            hall_state: 0,
             position: 0,
             last_hall_position: None,
+            velocity: VelocityEstimator::new(clocks.sysclk().0),
             power: 0,
             target_power: 0,
+            power_limit: 0,
+            commutation: Commutation::default(),
+            open_loop_angle: None,
+            tuning: DEFAULT_MOTOR_TUNING,
             smoothing_cycles: 0,
             _emergency_off: emergency_off,
         }
     }
 
     fn set_position_power(&mut self, power: i16, position: u8) {
+        let power: i16 = clamp(power, &(-1000..=1000));
+        let phase_powers = match self.commutation {
+            Commutation::Block => block_phase_powers(power, position),
+            Commutation::Sinusoidal => {
+                let angle = self.velocity.rotor_angle(position, DWT::cycle_count());
+                sinusoidal_phase_powers(power, angle)
+            }
+        };
+        self.set_phase_powers(power, phase_powers);
+    }
+
+    fn set_open_loop_power(&mut self, power: i16, angle: u16) {
+        let power: i16 = clamp(power, &(-1000..=1000));
+        self.set_phase_powers(power, sinusoidal_phase_powers(power, angle));
+    }
+
+    fn set_phase_powers(&mut self, power: i16, phase_powers: [i16; 3]) {
         // If power is below a threshold, turn it off entirely.
-        if power.abs() < MOTOR_POWER_DEAD_ZONE {
+        if power.unsigned_abs() < self.tuning.dead_zone {
             self.pwm.output_disable();
             return;
         }
         self.pwm.automatic_output_enable();
 
-        let power: i16 = clamp(power, &(-1000..=1000));
-        let (y, b, g) = match position {
-            0 => (0, power, -power),
-            1 => (-power, power, 0),
-            2 => (-power, 0, power),
-            3 => (0, -power, power),
-            4 => (power, -power, 0),
-            5 => (power, 0, -power),
-            _ => (0, 0, 0),
-        };
-        let duty_max = self.pwm.max_duty_cycle();
-        let power_max = (duty_max / 2) as i32;
-        let y = y as i32 * power_max / 1000;
-        let b = b as i32 * power_max / 1000;
-        let g = g as i32 * power_max / 1000;
-        let y = clamp((y + power_max) as u16, &(10..=duty_max - 10));
-        let b = clamp((b + power_max) as u16, &(10..=duty_max - 10));
-        let g = clamp((g + power_max) as u16, &(10..=duty_max - 10));
+        let [y, b, g] = duty_cycles(phase_powers, self.pwm.max_duty_cycle());
         self.set_duty_cycles(y, b, g);
     }
 
@@ -187,44 +215,96 @@ impl Motor {
         self.pwm.set_duty_cycle(Channel::C2, g);
     }
 
-    /// This should be called at regular intervals from the timer interrupt.
-    pub fn update(&mut self) {
+    /// Get the filtered velocity of the motor, in steps per second.
+    pub fn velocity(&self) -> i32 {
+        self.velocity.velocity()
+    }
+
+    /// Returns whether the motor is meant to be driven at the moment, i.e. the target power is
+    /// outside the dead zone and allowed by the power limit.
+    pub fn is_driving(&self) -> bool {
+        self.target_power.abs().min(self.power_limit).unsigned_abs() >= self.tuning.dead_zone
+    }
+
+    /// Get the motor tuning currently in use.
+    pub fn tuning(&self) -> MotorTuning {
+        self.tuning
+    }
+
+    /// Change the motor tuning, limiting the dead zone and PWM frequency to what is allowed.
+    pub fn set_tuning(&mut self, tuning: MotorTuning) {
+        let pwm_frequency_hz = clamp(tuning.pwm_frequency_hz, &PWM_FREQUENCY_RANGE_HZ);
+        if pwm_frequency_hz != self.tuning.pwm_frequency_hz {
+            self.pwm.set_period(Hertz(pwm_frequency_hz));
+        }
+        self.tuning = MotorTuning {
+            dead_zone: tuning.dead_zone.min(MAX_DEAD_ZONE),
+            pwm_frequency_hz,
+            ..tuning
+        };
+    }
+
+    /// This should be called at regular intervals from the timer interrupt. Returns what the Hall
+    /// sensors looked like.
+    pub fn update(&mut self) -> HallStatus {
         // Read the Hall effect sensors on the motor.
-        if let Some(hall_position) = self.hall_sensors.position() {
+        let hall_state = self.hall_sensors.state();
+        self.hall_state = hall_state;
+        let mut status = HallStatus::Valid;
+        if let Some(hall_position) = self.hall_mapping.sector(hall_state) {
             if let Some(last_hall_position) = self.last_hall_position {
                 // Update absolute position.
                 let difference = (6 + hall_position - last_hall_position) % 6;
-                match difference {
-                    0 => {}
-                    1 => self.position += 1,
-                    2 => self.position += 2,
-                    4 => self.position -= 2,
-                    5 => self.position -= 1,
-                    _ => {
-                        // TODO: Log error
+                let steps = match difference {
+                    1 => 1,
+                    2 => 2,
+                    4 => -2,
+                    5 => -1,
+                    3 => {
+                        status = HallStatus::Skipped;
+                        0
                     }
+                    _ => 0,
+                };
+                self.position += steps as i64;
+                let now = DWT::cycle_count();
+                if steps == 0 {
+                    self.velocity.idle(now);
+                } else {
+                    self.velocity.step(steps, now);
                 }
             }
 
             self.last_hall_position = Some(hall_position);
 
+            // Apply the power limit immediately, without smoothing.
+            let limits = -self.power_limit..=self.power_limit;
+            let target_power = clamp(self.target_power, &limits);
+            self.power = clamp(self.power, &limits);
+
             // Smoothing for motor power: don't change more than one unit every
-            // MOTOR_POWER_SMOOTHING_CYCLES_PER_STEP interrupts. This is only applied when
-            // increasing the power, not decreasing, to avoid overshooting.
-            if self.smoothing_cycles < MOTOR_POWER_SMOOTHING_CYCLES_PER_STEP
-                && self.target_power.abs() > self.power.abs()
+            // `ramp_cycles_per_step` interrupts. By default this is only applied when increasing
+            // the power, not decreasing, to avoid overshooting.
+            if self.smoothing_cycles < self.tuning.ramp_cycles_per_step.into()
+                && (self.tuning.ramp_down || target_power.abs() > self.power.abs())
             {
                 self.smoothing_cycles += 1;
-            } else if self.target_power > self.power {
+            } else if target_power > self.power {
                 self.power += 1;
                 self.smoothing_cycles = 0;
-            } else if self.target_power < self.power {
+            } else if target_power < self.power {
                 self.power -= 1;
                 self.smoothing_cycles = 0;
             }
 
             // Set motor position based on desired power and Hall sensor reading.
-            self.set_position_power(self.power, hall_position);
+            match self.open_loop_angle {
+                Some(angle) => self.set_open_loop_power(self.power, angle),
+                None => self.set_position_power(self.power, hall_position),
+            }
+        } else {
+            status = HallStatus::Invalid(hall_state);
         }
+        status
     }
 }
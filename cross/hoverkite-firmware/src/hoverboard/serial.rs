@@ -1,72 +1,247 @@
-use super::util::buffered_tx::{BufferState, BufferedSerialWriter, Listenable};
+use super::util::buffered_rx::{BufferedSerialReader, RxBufferState};
+use super::util::buffered_tx::{BufferState, BufferedSerialWriter, DmaTransmitter};
 use core::cell::RefCell;
-use core::ops::Deref;
 use cortex_m::{
     interrupt::{free, Mutex},
     peripheral::NVIC,
 };
+use embedded_io::{ErrorType, Read, ReadReady, Write};
 use gd32f1x0_hal::{
-    pac::{interrupt, usart0, Interrupt, Usart0, Usart1},
-    serial::Tx,
+    dma::{self, Event, Transfer, WriteDma, R},
+    pac::{interrupt, Interrupt, Usart0, Usart1},
+    serial::{self, Rx, Tx, TxDma0, TxDma1},
 };
 
-static SERIAL0_BUFFER: Mutex<RefCell<BufferState<Tx<Usart0>>>> =
+static SERIAL0_BUFFER: Mutex<RefCell<BufferState<TxDma0>>> =
     Mutex::new(RefCell::new(BufferState::new()));
-static SERIAL1_BUFFER: Mutex<RefCell<BufferState<Tx<Usart1>>>> =
+static SERIAL1_BUFFER: Mutex<RefCell<BufferState<TxDma1>>> =
     Mutex::new(RefCell::new(BufferState::new()));
+static SERIAL0_RX_BUFFER: Mutex<RefCell<RxBufferState<Rx<Usart0>>>> =
+    Mutex::new(RefCell::new(RxBufferState::new()));
+static SERIAL1_RX_BUFFER: Mutex<RefCell<RxBufferState<Rx<Usart1>>>> =
+    Mutex::new(RefCell::new(RxBufferState::new()));
 
 pub fn setup_usart0_buffered_writer(
-    mut serial_remote_tx: Tx<Usart0>,
-) -> BufferedSerialWriter<Tx<Usart0>> {
-    serial_remote_tx.listen();
+    serial_remote_tx: Tx<Usart0>,
+    dma_channel: dma::C1,
+) -> BufferedSerialWriter<TxDma0> {
+    // The HAL doesn't enable DMA requests from the USART itself.
+    // SAFETY: Only the DMA transmit enable bit is changed, and nothing else uses it.
+    unsafe { &*Usart0::ptr() }
+        .ctl2()
+        .modify(|_, w| w.dent().enabled());
+    let serial_remote_tx = serial_remote_tx.with_dma(dma_channel);
     free(move |cs| {
         SERIAL0_BUFFER
             .borrow(cs)
             .borrow_mut()
-            .set_writer(serial_remote_tx)
+            .set_transmitter(serial_remote_tx)
     });
     unsafe {
-        NVIC::unmask(Interrupt::USART0);
+        NVIC::unmask(Interrupt::DMA_Channel1_2);
     }
     BufferedSerialWriter::new(&SERIAL0_BUFFER)
 }
 
 pub fn setup_usart1_buffered_writer(
-    mut serial_remote_tx: Tx<Usart1>,
-) -> BufferedSerialWriter<Tx<Usart1>> {
-    serial_remote_tx.listen();
+    serial_tx: Tx<Usart1>,
+    dma_channel: dma::C3,
+) -> BufferedSerialWriter<TxDma1> {
+    // SAFETY: Only the DMA transmit enable bit is changed, and nothing else uses it.
+    unsafe { &*Usart1::ptr() }
+        .ctl2()
+        .modify(|_, w| w.dent().enabled());
+    let serial_tx = serial_tx.with_dma(dma_channel);
     free(move |cs| {
         SERIAL1_BUFFER
             .borrow(cs)
             .borrow_mut()
-            .set_writer(serial_remote_tx)
+            .set_transmitter(serial_tx)
     });
     unsafe {
-        NVIC::unmask(Interrupt::USART1);
+        NVIC::unmask(Interrupt::DMA_Channel3_4);
     }
     BufferedSerialWriter::new(&SERIAL1_BUFFER)
 }
 
+pub fn setup_usart0_buffered_reader(
+    mut serial_remote_rx: Rx<Usart0>,
+) -> BufferedSerialReader<Rx<Usart0>> {
+    serial_remote_rx.listen();
+    free(move |cs| {
+        SERIAL0_RX_BUFFER
+            .borrow(cs)
+            .borrow_mut()
+            .set_reader(serial_remote_rx)
+    });
+    unsafe {
+        NVIC::unmask(Interrupt::USART0);
+    }
+    BufferedSerialReader::new(&SERIAL0_RX_BUFFER)
+}
+
+pub fn setup_usart1_buffered_reader(mut serial_rx: Rx<Usart1>) -> BufferedSerialReader<Rx<Usart1>> {
+    serial_rx.listen();
+    free(move |cs| {
+        SERIAL1_RX_BUFFER
+            .borrow(cs)
+            .borrow_mut()
+            .set_reader(serial_rx)
+    });
+    unsafe {
+        NVIC::unmask(Interrupt::USART1);
+    }
+    BufferedSerialReader::new(&SERIAL1_RX_BUFFER)
+}
+
 #[interrupt]
 fn USART0() {
     free(|cs| {
-        SERIAL0_BUFFER.borrow(cs).borrow_mut().try_write();
+        SERIAL0_RX_BUFFER.borrow(cs).borrow_mut().try_read();
     })
 }
 
 #[interrupt]
 fn USART1() {
+    free(|cs| {
+        SERIAL1_RX_BUFFER.borrow(cs).borrow_mut().try_read();
+    })
+}
+
+#[interrupt]
+fn DMA_Channel1_2() {
+    free(|cs| {
+        SERIAL0_BUFFER.borrow(cs).borrow_mut().try_write();
+    })
+}
+
+#[interrupt]
+fn DMA_Channel3_4() {
     free(|cs| {
         SERIAL1_BUFFER.borrow(cs).borrow_mut().try_write();
     })
 }
 
-impl<USART: Deref<Target = usart0::RegisterBlock>> Listenable for Tx<USART> {
-    fn listen(&mut self) {
-        self.listen()
+macro_rules! dma_transmitter {
+    ($TxDmaX:ty, $USARTX:ty) => {
+        impl DmaTransmitter for $TxDmaX {
+            type Error = serial::Error;
+            type Transfer = Transfer<R, &'static [u8], $TxDmaX>;
+
+            fn start(mut self, bytes: &'static [u8]) -> Self::Transfer {
+                self.channel.listen(Event::TransferComplete);
+                self.write(bytes)
+            }
+
+            fn is_done(transfer: &Self::Transfer) -> bool {
+                transfer.is_done()
+            }
+
+            fn finish(transfer: Self::Transfer) -> Self {
+                transfer.wait().1
+            }
+
+            fn flush(&mut self) -> Result<(), Self::Error> {
+                // SAFETY: This only reads the status register.
+                while unsafe { &*<$USARTX>::ptr() }
+                    .stat()
+                    .read()
+                    .tc()
+                    .bit_is_clear()
+                {}
+                Ok(())
+            }
+        }
+    };
+}
+
+dma_transmitter!(TxDma0, Usart0);
+dma_transmitter!(TxDma1, Usart1);
+
+/// A buffered writer for either USART, so that which one to use can be chosen at runtime.
+pub enum SerialWriter {
+    /// Everything written is dropped.
+    None,
+    Usart0(BufferedSerialWriter<TxDma0>),
+    Usart1(BufferedSerialWriter<TxDma1>),
+}
+
+impl SerialWriter {
+    /// Returns the number of bytes which can be written without waiting.
+    pub fn space(&self) -> usize {
+        match self {
+            Self::None => usize::MAX,
+            Self::Usart0(writer) => writer.space(),
+            Self::Usart1(writer) => writer.space(),
+        }
+    }
+}
+
+impl ErrorType for SerialWriter {
+    type Error = serial::Error;
+}
+
+impl Write for SerialWriter {
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Self::Error> {
+        match self {
+            Self::None => Ok(buffer.len()),
+            Self::Usart0(writer) => writer.write(buffer),
+            Self::Usart1(writer) => writer.write(buffer),
+        }
     }
 
-    fn unlisten(&mut self) {
-        self.unlisten()
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        match self {
+            Self::None => Ok(()),
+            Self::Usart0(writer) => writer.flush(),
+            Self::Usart1(writer) => writer.flush(),
+        }
+    }
+}
+
+/// The buffered reader for either USART, so that which one to use can be chosen at runtime.
+pub enum SerialReader<'a> {
+    Usart0(&'a mut BufferedSerialReader<Rx<Usart0>>),
+    Usart1(&'a mut BufferedSerialReader<Rx<Usart1>>),
+}
+
+impl SerialReader<'_> {
+    /// Returns the number of bytes which have been dropped since the last call because the buffer
+    /// was full.
+    pub fn take_overruns(&mut self) -> u32 {
+        match self {
+            Self::Usart0(rx) => rx.take_overruns(),
+            Self::Usart1(rx) => rx.take_overruns(),
+        }
+    }
+
+    /// Returns the number of errors the USART has reported since the last call.
+    pub fn take_line_errors(&mut self) -> u32 {
+        match self {
+            Self::Usart0(rx) => rx.take_line_errors(),
+            Self::Usart1(rx) => rx.take_line_errors(),
+        }
+    }
+}
+
+impl ErrorType for SerialReader<'_> {
+    type Error = serial::Error;
+}
+
+impl Read for SerialReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        match self {
+            Self::Usart0(rx) => rx.read(buffer),
+            Self::Usart1(rx) => rx.read(buffer),
+        }
+    }
+}
+
+impl ReadReady for SerialReader<'_> {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        match self {
+            Self::Usart0(rx) => rx.read_ready(),
+            Self::Usart1(rx) => rx.read_ready(),
+        }
     }
 }
@@ -0,0 +1,110 @@
+use super::circular_buffer::CircularBuffer;
+use core::cell::RefCell;
+use cortex_m::{
+    asm::wfi,
+    interrupt::{free, Mutex},
+};
+use embedded_io::{ErrorType, Read, ReadReady};
+use messages::RxCounts;
+
+const SERIAL_RX_BUFFER_SIZE: usize = 128;
+
+/// Serial reader which reads from a buffer filled by the receive interrupt, so that bytes aren't
+/// lost while the main loop is busy.
+pub struct BufferedSerialReader<R: 'static + Read + ReadReady> {
+    state: &'static Mutex<RefCell<RxBufferState<R>>>,
+}
+
+impl<R: Read + ReadReady> BufferedSerialReader<R> {
+    pub fn new(state: &'static Mutex<RefCell<RxBufferState<R>>>) -> Self {
+        Self { state }
+    }
+
+    /// Returns the number of bytes which have been dropped since the last call because the buffer
+    /// was full.
+    pub fn take_overruns(&mut self) -> u32 {
+        free(|cs| self.state.borrow(cs).borrow_mut().counts.take_overruns())
+    }
+
+    /// Returns the number of errors the USART has reported since the last call.
+    pub fn take_line_errors(&mut self) -> u32 {
+        free(|cs| self.state.borrow(cs).borrow_mut().counts.take_line_errors())
+    }
+}
+
+impl<R: Read + ReadReady> ErrorType for BufferedSerialReader<R> {
+    type Error = R::Error;
+}
+
+impl<R: Read + ReadReady> ReadReady for BufferedSerialReader<R> {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        free(|cs| {
+            let state = &mut *self.state.borrow(cs).borrow_mut();
+            Ok(!state.buffer.is_empty())
+        })
+    }
+}
+
+impl<R: Read + ReadReady> Read for BufferedSerialReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let read = free(|cs| {
+                let state = &mut *self.state.borrow(cs).borrow_mut();
+
+                // Take as many bytes as are available and will fit.
+                let mut read = 0;
+                for slot in buffer.iter_mut() {
+                    match state.buffer.take() {
+                        Some(byte) => *slot = byte,
+                        None => break,
+                    }
+                    read += 1;
+                }
+                read
+            });
+
+            if read == 0 {
+                // Buffer was empty, wait for an interrupt which might indicate that the interrupt
+                // handler has received some bytes before trying again.
+                wfi();
+            } else {
+                return Ok(read);
+            }
+        }
+    }
+}
+
+pub struct RxBufferState<R> {
+    buffer: CircularBuffer<u8, SERIAL_RX_BUFFER_SIZE>,
+    reader: Option<R>,
+    /// The number of bytes lost since they were last taken.
+    counts: RxCounts,
+}
+
+impl<R> RxBufferState<R> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: CircularBuffer::new(),
+            reader: None,
+            counts: RxCounts::new(),
+        }
+    }
+}
+
+impl<R: Read + ReadReady> RxBufferState<R> {
+    pub fn set_reader(&mut self, reader: R) {
+        self.reader = Some(reader);
+    }
+
+    /// If the reader is set, move any bytes it has received into the buffer.
+    pub fn try_read(&mut self) {
+        if let Some(reader) = &mut self.reader {
+            let buffer = &mut self.buffer;
+            self.counts.receive(reader, |byte| buffer.add(byte));
+        }
+    }
+}
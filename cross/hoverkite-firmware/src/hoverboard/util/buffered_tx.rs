@@ -1,5 +1,5 @@
 use super::circular_buffer::CircularBuffer;
-use core::{cell::RefCell, fmt};
+use core::{cell::RefCell, fmt, mem, slice};
 use cortex_m::{
     asm::wfi,
     interrupt::{free, Mutex},
@@ -8,30 +8,51 @@ use embedded_io::{ErrorType, Write, WriteReady};
 
 const SERIAL_BUFFER_SIZE: usize = 300;
 
-/// Serial writer for which interrupts can be enabled and disabled.
-pub trait Listenable {
-    /// Enable interrupt for when when a byte may be written.
-    fn listen(&mut self);
+/// Serial transmitter which can send bytes straight from memory by DMA.
+pub trait DmaTransmitter: Sized {
+    type Error: embedded_io::Error;
+    type Transfer;
 
-    /// Disable interrupt for when a byte may be written.
-    fn unlisten(&mut self);
+    /// Start sending the given bytes, with an interrupt when they have all been sent.
+    fn start(self, bytes: &'static [u8]) -> Self::Transfer;
+
+    /// Returns true if the given transfer has finished.
+    fn is_done(transfer: &Self::Transfer) -> bool;
+
+    /// Wait for the given transfer to finish, and return the transmitter so it can be used again.
+    fn finish(transfer: Self::Transfer) -> Self;
+
+    /// Wait until the last byte has been sent completely.
+    fn flush(&mut self) -> Result<(), Self::Error>;
 }
 
-pub struct BufferedSerialWriter<W: 'static + Write + WriteReady + Listenable> {
-    state: &'static Mutex<RefCell<BufferState<W>>>,
+pub struct BufferedSerialWriter<T: 'static + DmaTransmitter> {
+    state: &'static Mutex<RefCell<BufferState<T>>>,
 }
 
-impl<W: Write + WriteReady + Listenable> BufferedSerialWriter<W> {
-    pub fn new(state: &'static Mutex<RefCell<BufferState<W>>>) -> Self {
+impl<T: DmaTransmitter> BufferedSerialWriter<T> {
+    pub fn new(state: &'static Mutex<RefCell<BufferState<T>>>) -> Self {
         Self { state }
     }
+
+    /// Returns the number of bytes which can be written without waiting.
+    pub fn space(&self) -> usize {
+        free(|cs| self.state.borrow(cs).borrow().buffer.space())
+    }
 }
 
-impl<W: Write + WriteReady + Listenable> ErrorType for BufferedSerialWriter<W> {
-    type Error = W::Error;
+// Every writer for the same buffer shares its state, so it is fine to have more than one.
+impl<T: DmaTransmitter> Clone for BufferedSerialWriter<T> {
+    fn clone(&self) -> Self {
+        Self { state: self.state }
+    }
+}
+
+impl<T: DmaTransmitter> ErrorType for BufferedSerialWriter<T> {
+    type Error = T::Error;
 }
 
-impl<W: Write + WriteReady + Listenable> WriteReady for BufferedSerialWriter<W> {
+impl<T: DmaTransmitter> WriteReady for BufferedSerialWriter<T> {
     fn write_ready(&mut self) -> Result<bool, Self::Error> {
         free(|cs| {
             let state = &mut *self.state.borrow(cs).borrow_mut();
@@ -40,7 +61,7 @@ impl<W: Write + WriteReady + Listenable> WriteReady for BufferedSerialWriter<W>
     }
 }
 
-impl<W: Write + WriteReady + Listenable> Write for BufferedSerialWriter<W> {
+impl<T: DmaTransmitter> Write for BufferedSerialWriter<T> {
     fn write(&mut self, buffer: &[u8]) -> Result<usize, Self::Error> {
         if buffer.is_empty() {
             return Ok(0);
@@ -54,14 +75,14 @@ impl<W: Write + WriteReady + Listenable> Write for BufferedSerialWriter<W> {
                 // Add as many bytes as possible to the buffer.
                 written = state.buffer.add_all(buffer);
 
-                // Try writing the first byte, as an interrupt might not happen if nothing has been
-                // written.
+                // Start sending them if the DMA channel is idle, as there won't be an interrupt
+                // otherwise.
                 state.try_write();
             });
 
             if written == 0 {
-                // Buffer was full, wait for an interrupt which might indicate that the interrupt
-                // handler has sent some bytes before trying again.
+                // Buffer was full, wait for an interrupt which might indicate that a DMA transfer
+                // has finished and made space before trying again.
                 wfi();
             } else {
                 return Ok(written);
@@ -73,14 +94,12 @@ impl<W: Write + WriteReady + Listenable> Write for BufferedSerialWriter<W> {
         loop {
             if let Some(result) = free(|cs| {
                 let state = &mut *self.state.borrow(cs).borrow_mut();
-                if state.buffer.is_empty() {
-                    if let Some(writer) = &mut state.writer {
-                        Some(writer.flush())
-                    } else {
-                        Some(Ok(()))
+                match &mut state.transmitter {
+                    Transmitter::Idle(transmitter) if state.buffer.is_empty() => {
+                        Some(transmitter.flush())
                     }
-                } else {
-                    None
+                    Transmitter::None => Some(Ok(())),
+                    _ => None,
                 }
             }) {
                 break result;
@@ -90,54 +109,61 @@ impl<W: Write + WriteReady + Listenable> Write for BufferedSerialWriter<W> {
     }
 }
 
-impl<W: Write + WriteReady + Listenable> fmt::Write for BufferedSerialWriter<W> {
+impl<T: DmaTransmitter> fmt::Write for BufferedSerialWriter<T> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
     }
 }
 
-pub struct BufferState<W> {
+/// What the DMA channel for a buffer is doing.
+enum Transmitter<T: DmaTransmitter> {
+    /// The transmitter hasn't been set yet.
+    None,
+    Idle(T),
+    /// Sending the given number of bytes from the start of the buffer.
+    Sending(T::Transfer, usize),
+}
+
+/// The buffer of bytes waiting to be sent, which must be in a static so that DMA can read from it.
+pub struct BufferState<T: DmaTransmitter> {
     buffer: CircularBuffer<u8, SERIAL_BUFFER_SIZE>,
-    writer: Option<W>,
+    transmitter: Transmitter<T>,
 }
 
-impl<W> BufferState<W> {
+impl<T: DmaTransmitter> BufferState<T> {
     pub const fn new() -> Self {
         Self {
             buffer: CircularBuffer::new(),
-            writer: None,
+            transmitter: Transmitter::None,
         }
     }
-}
 
-impl<W: Write + WriteReady + Listenable> BufferState<W> {
-    pub fn set_writer(&mut self, writer: W) {
-        self.writer = Some(writer);
+    pub fn set_transmitter(&mut self, transmitter: T) {
+        self.transmitter = Transmitter::Idle(transmitter);
     }
 
-    pub fn writer(&mut self) -> Option<&mut W> {
-        self.writer.as_mut()
-    }
-
-    /// If the writer is set and there's data in the buffer waiting to be written, try writing it.
+    /// If the last transfer has finished, remove what it sent from the buffer and start sending as
+    /// much of the rest as is contiguous.
     pub fn try_write(&mut self) {
-        if let Some(writer) = &mut self.writer {
-            // If there's a byte to write, try writing it.
-            if let Some(byte) = self.buffer.peek() {
-                if let Ok(true) = writer.write_ready() {
-                    if writer.write_all(&[byte]).is_ok() {
-                        // If the byte was written successfully, remove it from the buffer.
-                        self.buffer.take();
-                    }
-                }
+        self.transmitter = match mem::replace(&mut self.transmitter, Transmitter::None) {
+            Transmitter::Sending(transfer, length) if T::is_done(&transfer) => {
+                let transmitter = T::finish(transfer);
+                self.buffer.discard(length);
+                self.start_next(transmitter)
             }
+            Transmitter::Idle(transmitter) => self.start_next(transmitter),
+            transmitter => transmitter,
+        };
+    }
 
-            // If there are bytes left to write, enable interrupts, otherwise disable them.
-            if self.buffer.is_empty() {
-                writer.unlisten();
-            } else {
-                writer.listen();
-            }
+    fn start_next(&mut self, transmitter: T) -> Transmitter<T> {
+        let (bytes, _) = self.buffer.as_slices();
+        if bytes.is_empty() {
+            return Transmitter::Idle(transmitter);
         }
+        // SAFETY: The buffer is in a static, and bytes are only added after the ones being sent,
+        // which aren't removed until the transfer has finished.
+        let bytes: &'static [u8] = unsafe { slice::from_raw_parts(bytes.as_ptr(), bytes.len()) };
+        Transmitter::Sending(transmitter.start(bytes), bytes.len())
     }
 }
@@ -64,20 +64,36 @@ impl<T: Copy + Default, const SIZE: usize> CircularBuffer<T, SIZE> {
         }
     }
 
-    /// Get a copy of the next element from the buffer, but don't remove it.
-    pub fn peek(&self) -> Option<T> {
-        if self.length == 0 {
-            None
+    /// Returns the elements in the buffer as two slices, the second of which is only non-empty if
+    /// the elements wrap around the end of the underlying array.
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        if self.start + self.length <= SIZE {
+            (&self.buffer[self.start..self.start + self.length], &[])
         } else {
-            Some(self.buffer[self.start])
+            (
+                &self.buffer[self.start..],
+                &self.buffer[..self.start + self.length - SIZE],
+            )
         }
     }
 
+    /// Remove up to the given number of elements from the start of the buffer.
+    pub fn discard(&mut self, count: usize) {
+        let count = count.min(self.length);
+        self.start = (self.start + count) % SIZE;
+        self.length -= count;
+    }
+
     /// Returns true if there are no elements in the buffer.
     pub fn is_empty(&self) -> bool {
         self.length == 0
     }
 
+    /// Returns the number of elements which could be added to the buffer before it is full.
+    pub fn space(&self) -> usize {
+        SIZE - self.length
+    }
+
     /// Returns true if there is no space in the buffer for any more elements.
     pub fn is_full(&self) -> bool {
         self.length == SIZE
@@ -1,2 +1,3 @@
+pub mod buffered_rx;
 pub mod buffered_tx;
 pub mod circular_buffer;
@@ -1,44 +1,46 @@
 #![no_std]
 #![no_main]
 
+mod config;
 mod hoverboard;
 mod protocol;
+mod reporting;
 mod systick;
 mod util;
 
-#[cfg(feature = "primary")]
-use messages::Command;
-use messages::TorqueLimits;
-#[cfg(feature = "secondary")]
-use messages::{Note, Response, SideResponse};
+use messages::{Command, DirectedCommand, Encoding, Note, Response, Role, SideResponse};
 // pick a panicking behavior
 use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch panics
                      // use panic_abort as _; // requires nightly
                      // use panic_itm as _; // logs messages over ITM; requires ITM support
                      // use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
 
-#[cfg(feature = "secondary")]
+use config::{apply_config, ConfigStore, DEFAULT_CONFIG};
+use control::{
+    BatteryEvent, BatteryMonitor, Calibration, CalibrationStep, CommsMonitor, LinkEvent,
+    LinkMonitor, PositionController, Target, VelocityController, CALIBRATION_POWER,
+};
 use core::num::NonZeroU32;
 use cortex_m_rt::entry;
 use embedded_hal::digital::{InputPin, OutputPin};
-use embedded_io::{Read, ReadReady};
-#[cfg(feature = "secondary")]
-use gd32f1x0_hal::time::Hertz;
-use gd32f1x0_hal::{pac, prelude::*, watchdog::FreeWatchdog};
+use embedded_io::{Read, ReadReady, Write};
+use gd32f1x0_hal::{pac, prelude::*, time::Hertz, watchdog::FreeWatchdog};
 use hoverboard::util::circular_buffer::CircularBuffer;
 use hoverboard::Hoverboard;
-#[cfg(feature = "primary")]
-use protocol::process_response;
-use protocol::{process_command, send_position, HoverboardExt};
+use protocol::{
+    probe_command, process_command, process_response, report_dropped, role, send_heartbeat,
+    send_response, this_side,
+};
+use reporting::Reporting;
 use systick::SysTick;
 use util::clamp;
 
-#[cfg(feature = "secondary")]
-use crate::protocol::THIS_SIDE;
-
 const WATCHDOG_MILLIS: u32 = 1000;
 
-#[cfg(feature = "secondary")]
+/// How often to run the position or velocity controller.
+const CONTROL_INTERVAL_MS: u32 = 1;
+
+/// Tune for the secondary to play when it is powered on.
 const POWER_ON_TUNE: [Note; 2] = [
     Note {
         frequency: NonZeroU32::new(1000),
@@ -50,20 +52,29 @@ const POWER_ON_TUNE: [Note; 2] = [
     },
 ];
 
+/// Tune to play when the battery voltage drops below the warning threshold.
+const LOW_BATTERY_TUNE: [Note; 3] = [
+    Note {
+        frequency: NonZeroU32::new(1500),
+        duration_ms: 150,
+    },
+    Note {
+        frequency: None,
+        duration_ms: 100,
+    },
+    Note {
+        frequency: NonZeroU32::new(1000),
+        duration_ms: 300,
+    },
+];
+
 /// Frequency of tone to play while powering off. We can't easily play a tune because the main loop
-/// is no longer running by then.
-#[cfg(feature = "secondary")]
+/// is no longer running by then. Only the secondary plays this.
 const POWER_OFF_FREQUENCY: Hertz = Hertz(800);
 
 /// If the power button is held for more than this duration then don't play the power on tune.
-#[cfg(feature = "secondary")]
 const POWER_ON_SILENT_MS: u32 = 1000;
 
-#[cfg(feature = "primary")]
-const NEGATE_MOTOR: bool = false;
-#[cfg(feature = "secondary")]
-const NEGATE_MOTOR: bool = true;
-
 #[entry]
 fn main() -> ! {
     let mut cp = cortex_m::Peripherals::take().unwrap();
@@ -82,6 +93,10 @@ fn main() -> ! {
 
     let systick = SysTick::start(cp.SYST, &clocks);
 
+    // Load the saved config first, as it may say which role this board has.
+    let mut config_store = ConfigStore::new(flash);
+    let saved_config = config_store.load();
+
     let mut hoverboard = Hoverboard::new(
         dp.gpioa,
         dp.gpiob,
@@ -99,7 +114,7 @@ fn main() -> ! {
         &mut rcu.apb2,
         &mut cp.DWT,
         clocks,
-        NEGATE_MOTOR,
+        saved_config.and_then(|config| config.role),
     );
 
     // Keep power on.
@@ -107,11 +122,13 @@ fn main() -> ! {
 
     log!(
         hoverboard.response_tx(),
+        Debug,
         "System clock {} Hz",
         clocks.sysclk().0
     );
     log!(
         hoverboard.response_tx(),
+        Debug,
         "ADC clock {} Hz",
         clocks.adcclk().0
     );
@@ -125,55 +142,95 @@ fn main() -> ! {
     // The timestamp at which to start playing the next note.
     let mut next_note_time = 0;
 
-    #[cfg(feature = "secondary")]
-    if systick.millis_since_start() < POWER_ON_SILENT_MS {
+    // Decide now whether to play the power on tune, as the role may not be known for a while.
+    let play_power_on_tune = systick.millis_since_start() < POWER_ON_SILENT_MS;
+
+    if role() == Some(Role::Secondary) && play_power_on_tune {
         note_queue.add_all(&POWER_ON_TUNE);
     }
 
-    log!(hoverboard.response_tx(), "Ready");
+    // If the role isn't known yet then nothing can be sent, so this is logged once it is.
+    log!(hoverboard.response_tx(), Info, "Ready");
 
-    let mut last_position = 0;
-    let mut command_buffer = [0; 12];
+    // Save the role which the board started with, unless it is changed.
+    config_store.set_role(role());
+
+    let mut command_buffer = [0; 32];
     let mut command_len = 0;
-    #[cfg(feature = "primary")]
-    let mut proxy_response_buffer = [0; 100];
-    #[cfg(feature = "primary")]
+    // Commands forwarded by the primary, which are read while working out the role.
+    let mut forwarded_buffer = [0; 32];
+    let mut forwarded_len = 0;
+    let mut proxy_response_buffer = [0; 320];
     let mut proxy_response_length = 0;
-    let mut target_position: Option<i64> = None;
-    let mut torque_limits = TorqueLimits {
-        negative: -200,
-        positive: 200,
-    };
-    let mut spring_constant = 10;
+    let mut target: Option<Target> = None;
+    let mut torque_limits = DEFAULT_CONFIG.torque_limits;
+    let mut position_controller = PositionController::new(DEFAULT_CONFIG.gains);
+    let mut velocity_controller = VelocityController::new(DEFAULT_CONFIG.velocity_gains);
+    let mut battery_monitor = BatteryMonitor::default();
+    if let Some(config) = saved_config {
+        log!(hoverboard.response_tx(), Info, "Loaded saved config");
+        apply_config(
+            &config,
+            &mut hoverboard,
+            &mut torque_limits,
+            &mut position_controller,
+            &mut velocity_controller,
+            &mut battery_monitor,
+        );
+    }
+    let mut calibration: Option<Calibration> = None;
+    // The timestamp at which to next run the position or velocity controller, and at which it was
+    // last run. These start from now rather than 0, so that the first time step doesn't cover all
+    // the time since boot.
+    let mut next_control_time = systick.millis_since_start();
+    let mut last_control_time = next_control_time;
+    let mut torque = 0;
+    let mut reporting = Reporting::default();
+    let mut link_monitor = LinkMonitor::default();
+    let mut comms_monitor = CommsMonitor::default();
+    // Whether there are bytes in the command buffer which haven't been processed yet.
+    let mut new_command_bytes = false;
     loop {
         // The watchdog must be fed every second or so or the microcontroller will reset.
         watchdog.feed();
 
+        // Work out the role if it wasn't saved. Until then no commands are processed, so the motor
+        // isn't driven, but everything else keeps running so that the battery is still monitored.
+        if role().is_none() {
+            if let Some(role) = probe_role(
+                &mut hoverboard,
+                &mut command_buffer,
+                &mut command_len,
+                &mut forwarded_buffer,
+                &mut forwarded_len,
+            ) {
+                hoverboard.set_role(role);
+                config_store.set_role(Some(role));
+                log!(hoverboard.response_tx(), Info, "Role {:?}", role);
+                if role == Role::Secondary && play_power_on_tune {
+                    note_queue.add_all(&POWER_ON_TUNE);
+                }
+                log!(hoverboard.response_tx(), Info, "Ready");
+                // The command which showed the role is already in the command buffer.
+                new_command_bytes = true;
+            }
+        }
+        let role = role();
+
         // Read from the command USART if data is available.
-        if hoverboard.command_rx().read_ready().unwrap() {
+        if role.is_some() && hoverboard.command_rx().read_ready().unwrap() {
             match hoverboard
                 .command_rx()
                 .read(&mut command_buffer[command_len..command_len + 1])
             {
                 Ok(1) => {
                     command_len += 1;
-                    if process_command(
-                        &command_buffer[0..command_len],
-                        &mut hoverboard,
-                        &mut torque_limits,
-                        &mut target_position,
-                        &mut spring_constant,
-                        &mut note_queue,
-                    ) {
-                        command_len = 0;
-                    } else if command_len >= command_buffer.len() {
-                        log!(hoverboard.response_tx(), "Command too long");
-                        command_len = 0;
-                    }
+                    new_command_bytes = true;
                 }
                 Ok(read_length) => {
                     log!(
                         hoverboard.response_tx(),
+                        Error,
                         "Read unexpected number of bytes {}, dropping {} bytes",
                         read_length,
                         command_len,
@@ -183,6 +240,7 @@ fn main() -> ! {
                 Err(e) => {
                     log!(
                         hoverboard.response_tx(),
+                        Error,
                         "Read error {:?}, dropping {} bytes",
                         e,
                         command_len,
@@ -191,85 +249,353 @@ fn main() -> ! {
                 }
             }
         }
+        if new_command_bytes {
+            new_command_bytes = false;
+            loop {
+                let mut used = process_command(
+                    &command_buffer[0..command_len],
+                    &mut hoverboard,
+                    &mut torque_limits,
+                    &mut target,
+                    &mut position_controller,
+                    &mut velocity_controller,
+                    &mut calibration,
+                    &mut config_store,
+                    &mut reporting,
+                    &mut battery_monitor,
+                    &mut link_monitor,
+                    &mut comms_monitor,
+                    &mut note_queue,
+                );
+                if used == 0 {
+                    if command_len < command_buffer.len() {
+                        break;
+                    }
+                    // Drop the first byte, in case a corrupt frame length is hiding the start of a
+                    // valid frame.
+                    log!(hoverboard.response_tx(), Warn, "Command too long");
+                    used = 1;
+                }
+                command_buffer.copy_within(used..command_len, 0);
+                command_len -= used;
+                if command_len == 0 {
+                    break;
+                }
+            }
+        }
 
         // Read from the secondary USART if data is available
-        #[cfg(feature = "primary")]
-        match hoverboard.serial_rx.read() {
-            Ok(char) => {
-                proxy_response_buffer[proxy_response_length] = char;
-                proxy_response_length += 1;
-                if process_response(
-                    &proxy_response_buffer[0..proxy_response_length],
-                    &mut hoverboard,
-                ) {
+        if role == Some(Role::Primary) && hoverboard.serial_rx.read_ready().unwrap() {
+            match hoverboard
+                .serial_rx
+                .read(&mut proxy_response_buffer[proxy_response_length..proxy_response_length + 1])
+            {
+                Ok(1) => {
+                    proxy_response_length += 1;
+                    loop {
+                        let mut used = process_response(
+                            &proxy_response_buffer[0..proxy_response_length],
+                            &mut hoverboard,
+                            &mut link_monitor,
+                        );
+                        if used == 0 {
+                            if proxy_response_length < proxy_response_buffer.len() {
+                                break;
+                            }
+                            log!(
+                                hoverboard.response_tx(),
+                                Warn,
+                                "Secondary response too long"
+                            );
+                            used = 1;
+                        }
+                        proxy_response_buffer.copy_within(used..proxy_response_length, 0);
+                        proxy_response_length -= used;
+                        if proxy_response_length == 0 {
+                            break;
+                        }
+                    }
+                }
+                Ok(read_length) => {
+                    log!(
+                        hoverboard.response_tx(),
+                        Error,
+                        "Read unexpected number of bytes {} on secondary, dropping {} bytes",
+                        read_length,
+                        proxy_response_length
+                    );
                     proxy_response_length = 0;
-                } else if proxy_response_length >= proxy_response_buffer.len() {
-                    log!(hoverboard.response_tx(), "Secondary response too long");
+                }
+                Err(e) => {
+                    log!(
+                        hoverboard.response_tx(),
+                        Error,
+                        "Read error on secondary {:?}, dropping {} bytes",
+                        e,
+                        proxy_response_length
+                    );
                     proxy_response_length = 0;
                 }
             }
-            Err(nb::Error::WouldBlock) => {}
-            Err(nb::Error::Other(e)) => {
+        }
+
+        // Report any bytes which were lost because they arrived faster than they were processed,
+        // and any errors on the line.
+        if role.is_some() {
+            let overruns = hoverboard.command_rx().take_overruns();
+            if overruns > 0 {
+                log!(
+                    hoverboard.response_tx(),
+                    Warn,
+                    "Lost {} command bytes",
+                    overruns
+                );
+            }
+            let line_errors = hoverboard.command_rx().take_line_errors();
+            if line_errors > 0 {
+                log!(
+                    hoverboard.response_tx(),
+                    Warn,
+                    "{} errors receiving commands",
+                    line_errors
+                );
+            }
+        }
+        if role == Some(Role::Primary) {
+            let overruns = hoverboard.serial_rx.take_overruns();
+            if overruns > 0 {
+                log!(
+                    hoverboard.response_tx(),
+                    Warn,
+                    "Lost {} bytes from secondary",
+                    overruns
+                );
+            }
+            let line_errors = hoverboard.serial_rx.take_line_errors();
+            if line_errors > 0 {
+                log!(
+                    hoverboard.response_tx(),
+                    Warn,
+                    "{} errors receiving from secondary",
+                    line_errors
+                );
+            }
+        }
+
+        let current_time = systick.millis_since_start();
+        reporting.poll(&mut hoverboard, current_time);
+        report_dropped(hoverboard.response_tx());
+        if let Some(fault) = hoverboard.take_new_fault() {
+            log!(hoverboard.response_tx(), Error, "Fault: {}", fault);
+            send_response(hoverboard.response_tx(), Response::Fault(fault));
+        }
+        let battery_voltage = hoverboard.adc_readings().battery_voltage;
+        match battery_monitor.update(battery_voltage, current_time) {
+            Some(BatteryEvent::Warning) => {
+                log!(
+                    hoverboard.response_tx(),
+                    Warn,
+                    "Battery low: {} mV",
+                    battery_voltage
+                );
+                note_queue.add_all(&LOW_BATTERY_TUNE);
+                send_response(
+                    hoverboard.response_tx(),
+                    Response::LowBattery {
+                        battery_voltage,
+                        cutoff: false,
+                    },
+                );
+            }
+            Some(BatteryEvent::Cutoff) => {
+                log!(
+                    hoverboard.response_tx(),
+                    Error,
+                    "Battery critical: {} mV, cutting off",
+                    battery_voltage
+                );
+                send_response(
+                    hoverboard.response_tx(),
+                    Response::LowBattery {
+                        battery_voltage,
+                        cutoff: true,
+                    },
+                );
+            }
+            Some(BatteryEvent::PowerOff) => poweroff_both_sides(&mut hoverboard),
+            Some(BatteryEvent::Recovered) => {
+                log!(
+                    hoverboard.response_tx(),
+                    Info,
+                    "Battery recovered: {} mV",
+                    battery_voltage
+                );
+                send_response(
+                    hoverboard.response_tx(),
+                    Response::BatteryRecovered { battery_voltage },
+                );
+            }
+            None => {}
+        }
+
+        // Keep checking the link to the other side, and stop if it is lost.
+        if link_monitor.heartbeat_due(current_time) {
+            send_heartbeat(&mut hoverboard);
+        }
+        match link_monitor.poll(current_time) {
+            Some(LinkEvent::Lost) => {
+                log!(hoverboard.response_tx(), Warn, "Link to other side lost");
+                target = None;
+                send_response(hoverboard.response_tx(), Response::LinkLost);
+            }
+            Some(LinkEvent::Restored) => {
                 log!(
                     hoverboard.response_tx(),
-                    "Read error on secondary {:?}, dropping {} bytes",
-                    e,
-                    proxy_response_length
+                    Info,
+                    "Link to other side restored"
                 );
-                proxy_response_length = 0;
+                send_response(hoverboard.response_tx(), Response::LinkRestored);
             }
+            None => {}
+        }
+
+        // Stop chasing the target if the host has gone away, ramping down to the safe torque.
+        if comms_monitor.poll(current_time, torque, &mut target) {
+            log!(
+                hoverboard.response_tx(),
+                Warn,
+                "No command from host, stopping"
+            );
+            send_response(hoverboard.response_tx(), Response::CommsTimeout);
         }
 
-        // Log if the position has changed.
         let position = hoverboard.motor_position();
-        if position != last_position {
-            send_position(hoverboard.response_tx(), position);
-            last_position = position;
+
+        let control_due = current_time >= next_control_time;
+        let control_dt_ms = current_time - last_control_time;
+        if control_due {
+            // Keep to a fixed schedule rather than drifting later each time, but if we have fallen
+            // a whole interval behind then skip the missed runs rather than bunching them up.
+            next_control_time += CONTROL_INTERVAL_MS;
+            if next_control_time <= current_time {
+                next_control_time = current_time + CONTROL_INTERVAL_MS;
+            }
+            last_control_time = current_time;
         }
 
-        // Try to move towards the target position.
-        let torque;
-        if let Some(target_position) = target_position {
-            let difference = target_position - position;
-            torque = clamp(difference * spring_constant, &torque_limits.into());
+        match target {
+            Some(Target::Position(target_position)) => {
+                // Try to move towards the target position.
+                velocity_controller.reset();
+                let difference = target_position - position;
+                if control_due {
+                    torque = position_controller.update(
+                        target_position,
+                        position,
+                        hoverboard.motor_velocity(),
+                        torque_limits,
+                        control_dt_ms,
+                    );
+                }
+
+                // Set LEDs based on position difference
+                if difference.abs() < 3 {
+                    hoverboard.leds.green.set_high().unwrap();
+                    hoverboard.leds.orange.set_low().unwrap();
+                    hoverboard.leds.red.set_low().unwrap();
+                } else if difference > 0 {
+                    hoverboard.leds.green.set_low().unwrap();
+                    hoverboard.leds.orange.set_high().unwrap();
+                    hoverboard.leds.red.set_low().unwrap();
+                } else {
+                    hoverboard.leds.green.set_low().unwrap();
+                    hoverboard.leds.orange.set_low().unwrap();
+                    hoverboard.leds.red.set_high().unwrap();
+                }
+                if difference.abs() < 5 {
+                    hoverboard.leds.side.set_low().unwrap();
+                } else {
+                    hoverboard.leds.side.set_high().unwrap();
+                }
+            }
+            Some(Target::Velocity(target_velocity)) => {
+                // Try to turn at the target velocity.
+                position_controller.reset();
+                if control_due {
+                    torque = velocity_controller.update(
+                        target_velocity,
+                        hoverboard.motor_velocity(),
+                        torque_limits,
+                        control_dt_ms,
+                    );
+                }
 
-            // Set LEDs based on position difference
-            if difference.abs() < 3 {
-                hoverboard.leds.green.set_high().unwrap();
+                hoverboard.leds.green.set_low().unwrap();
                 hoverboard.leds.orange.set_low().unwrap();
                 hoverboard.leds.red.set_low().unwrap();
-            } else if difference > 0 {
+                hoverboard.leds.side.set_high().unwrap();
+            }
+            Some(Target::Torque(target_torque)) => {
+                // Apply the requested torque directly, but still within the limits.
+                position_controller.reset();
+                velocity_controller.reset();
+                torque = clamp(target_torque, &torque_limits.into());
+
                 hoverboard.leds.green.set_low().unwrap();
                 hoverboard.leds.orange.set_high().unwrap();
                 hoverboard.leds.red.set_low().unwrap();
-            } else {
+                hoverboard.leds.side.set_high().unwrap();
+            }
+            None => {
+                torque = 0;
+                position_controller.reset();
+                velocity_controller.reset();
+
                 hoverboard.leds.green.set_low().unwrap();
                 hoverboard.leds.orange.set_low().unwrap();
-                hoverboard.leds.red.set_high().unwrap();
-            }
-            if difference.abs() < 5 {
+                hoverboard.leds.red.set_low().unwrap();
                 hoverboard.leds.side.set_low().unwrap();
-            } else {
-                hoverboard.leds.side.set_high().unwrap();
             }
-        } else {
-            torque = 0;
-
-            hoverboard.leds.green.set_low().unwrap();
-            hoverboard.leds.orange.set_low().unwrap();
-            hoverboard.leds.red.set_low().unwrap();
-            hoverboard.leds.side.set_low().unwrap();
         }
 
-        // Drive the motor.
-        hoverboard.set_motor_power(torque);
+        // Drive the motor, ramping down if the battery has hit the cutoff. While calibrating, the
+        // motor is instead driven open-loop.
+        let hall_state = hoverboard.hall_state();
+        match calibration
+            .as_mut()
+            .map(|calibration| calibration.poll(current_time, hall_state))
+        {
+            Some(CalibrationStep::Drive(angle)) => hoverboard.drive_open_loop(
+                angle,
+                battery_monitor.limit_torque(CALIBRATION_POWER, current_time),
+            ),
+            Some(CalibrationStep::Done(result)) => {
+                hoverboard.stop_open_loop();
+                calibration = None;
+                // Don't jump to a target which was set while calibrating.
+                target = None;
+                match result {
+                    Ok(hall_mapping) => {
+                        log!(
+                            hoverboard.response_tx(),
+                            Info,
+                            "Hall mapping {}",
+                            hall_mapping
+                        );
+                        hoverboard.set_hall_mapping(hall_mapping);
+                    }
+                    Err(e) => log!(hoverboard.response_tx(), Error, "Calibration failed: {}", e),
+                }
+                send_response(hoverboard.response_tx(), Response::MotorCalibration(result));
+            }
+            None => hoverboard.set_motor_power(battery_monitor.limit_torque(torque, current_time)),
+        }
 
-        let current_time = systick.millis_since_start();
         if current_time > next_note_time {
             // Play the next note on the buzzer, or turn it off if there is none.
             let note = note_queue.take().unwrap_or_default();
             if note.frequency.is_some() {
-                log!(hoverboard.response_tx(), "Playing {}", note);
+                log!(hoverboard.response_tx(), Debug, "Playing {}", note);
             }
             hoverboard
                 .buzzer
@@ -277,43 +603,97 @@ fn main() -> ! {
             next_note_time = current_time + note.duration_ms;
         }
 
-        // If the power button is pressed, turn off.
-        if hoverboard.power_button.is_high().unwrap() {
-            log!(hoverboard.response_tx(), "Power button pressed");
-            #[cfg(feature = "secondary")]
+        check_power_button(&mut hoverboard, &mut watchdog);
+    }
+}
+
+/// Reads whatever is available on either USART, and if that completes the first valid command then
+/// returns the role it implies: primary if it came from the host on USART0, or secondary if the
+/// primary forwarded it on USART1. What was read from that USART is then in the command buffer, to
+/// be processed as usual.
+fn probe_role(
+    hoverboard: &mut Hoverboard,
+    command_buffer: &mut [u8; 32],
+    command_len: &mut usize,
+    forwarded_buffer: &mut [u8; 32],
+    forwarded_len: &mut usize,
+) -> Option<Role> {
+    if probe_command(
+        &mut hoverboard.serial_remote_rx,
+        command_buffer,
+        command_len,
+        false,
+    ) {
+        return Some(Role::Primary);
+    }
+    if probe_command(
+        &mut hoverboard.serial_rx,
+        forwarded_buffer,
+        forwarded_len,
+        true,
+    ) {
+        *command_buffer = *forwarded_buffer;
+        *command_len = *forwarded_len;
+        return Some(Role::Secondary);
+    }
+    None
+}
+
+/// If the power button is pressed, waits for it to be released and then turns off.
+fn check_power_button(hoverboard: &mut Hoverboard, watchdog: &mut FreeWatchdog) {
+    if hoverboard.power_button.is_high().unwrap() {
+        log!(hoverboard.response_tx(), Info, "Power button pressed");
+        if role() == Some(Role::Secondary) {
             hoverboard.buzzer.set_frequency(Some(POWER_OFF_FREQUENCY));
-            // Wait until it is released.
-            while hoverboard.power_button.is_high().unwrap() {
-                watchdog.feed();
-            }
-            log!(hoverboard.response_tx(), "Power button released");
-            #[cfg(feature = "secondary")]
-            {
-                log!(hoverboard.response_tx(), "Telling primary to power off");
-                // Tell primary to power off, but only here in response to the power button press.
-                SideResponse {
-                    side: THIS_SIDE,
-                    response: Response::PowerOff,
-                }
-                .write_to(&mut hoverboard.serial_writer)
-                .unwrap()
-            }
-            poweroff(&mut hoverboard);
         }
+        // Wait until it is released.
+        while hoverboard.power_button.is_high().unwrap() {
+            watchdog.feed();
+        }
+        log!(hoverboard.response_tx(), Info, "Power button released");
+        poweroff_both_sides(hoverboard);
+    }
+}
+
+/// Powers off this side, after telling the other side to power off too.
+fn poweroff_both_sides(hoverboard: &mut Hoverboard) {
+    if role() == Some(Role::Secondary) {
+        log!(
+            hoverboard.response_tx(),
+            Info,
+            "Telling primary to power off"
+        );
+        // Tell primary to power off, but only here rather than in `poweroff`, as that is also
+        // called when the primary tells us to power off.
+        SideResponse {
+            side: this_side(),
+            response: Response::PowerOff,
+        }
+        .write_to(&mut hoverboard.serial_writer)
+        .unwrap()
     }
+    poweroff(hoverboard);
 }
 
 pub fn poweroff(hoverboard: &mut Hoverboard) {
-    #[cfg(feature = "primary")]
-    {
-        log!(hoverboard.response_tx(), "Telling secondary to power off");
-        // Ensure secondary powers off before we do.
-        Command::PowerOff
-            .write_to(&mut hoverboard.serial_writer)
-            .unwrap();
-        hoverboard.serial_writer.bflush().unwrap();
+    if role() == Some(Role::Primary) {
+        log!(
+            hoverboard.response_tx(),
+            Info,
+            "Telling secondary to power off"
+        );
+        // Ensure secondary powers off before we do. Like other commands forwarded to it, this is
+        // always framed.
+        DirectedCommand {
+            side: this_side().opposite(),
+            command: Command::PowerOff,
+            sequence: None,
+        }
+        .write_with_encoding(Encoding::Framed, &mut hoverboard.serial_writer)
+        .unwrap();
+        hoverboard.serial_writer.flush().unwrap();
     }
-    log!(hoverboard.response_tx(), "Power off");
+    log!(hoverboard.response_tx(), Info, "Power off");
     hoverboard.power_latch.set_low().unwrap();
-    log!(hoverboard.response_tx(), "Powered off");
+    log!(hoverboard.response_tx(), Info, "Powered off");
 }
@@ -1,198 +1,467 @@
-use crate::hoverboard::util::buffered_tx::BufferedSerialWriter;
+use crate::config::{apply_config, current_config, ConfigStore, DEFAULT_CONFIG};
 use crate::hoverboard::util::circular_buffer::CircularBuffer;
-use crate::hoverboard::Hoverboard;
+use crate::hoverboard::{Hoverboard, SerialWriter};
 use crate::poweroff;
-use core::{fmt::Debug, ops::Deref};
-use embedded_hal::digital::{InputPin, OutputPin};
-use embedded_io::Write;
-use gd32f1x0_hal::{
-    pac::{self, usart0},
-    serial::{Rx, Tx},
+use crate::reporting::Reporting;
+use arrayvec::ArrayString;
+use control::{
+    BatteryMonitor, Calibration, CommsMonitor, LinkMonitor, PositionController, Target,
+    VelocityController, GAIN_SCALE,
 };
-#[allow(unused_imports)]
+use core::fmt::Arguments;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_io::{Read, ReadReady};
+use messages::frame::FRAME_START;
 use messages::{
-    Command, DirectedCommand, Note, ProtocolError, Response, Side, SideResponse, TorqueLimits,
+    Command, DirectedCommand, Encoding, FirmwareVersion, Gains, LogLevel, Note, ProtocolError,
+    ProtocolVersion, Response, Role, Side, SideResponse, TorqueLimits,
 };
-#[allow(unused_imports)]
 use nb::Error::{Other, WouldBlock};
 
 #[macro_export]
 macro_rules! log {
-    ($dst:expr, $($arg:tt)*) => (
-		{
-            ::messages::SideResponse {
-                side: $crate::protocol::THIS_SIDE,
-                response: ::messages::Response::log_from_fmt(format_args!($($arg)*))
-            }.write_to($dst).unwrap()
-		}
+    ($dst:expr, $level:ident, $($arg:tt)*) => (
+        $crate::protocol::send_log($dst, ::messages::LogLevel::$level, format_args!($($arg)*))
+    );
+}
+
+/// The role of this board, as returned by `Role::to_byte`, or 0 if it isn't known yet.
+static ROLE: AtomicU8 = AtomicU8::new(0);
+
+pub fn role() -> Option<Role> {
+    Role::parse(ROLE.load(Ordering::Relaxed)).ok()
+}
+
+pub fn set_role(role: Role) {
+    ROLE.store(role.to_byte(), Ordering::Relaxed);
+}
+
+/// Returns which side this board is on. Nothing is sent until the role is known, so it doesn't
+/// matter what this returns before then.
+pub fn this_side() -> Side {
+    role().unwrap_or(Role::Primary).side()
+}
+
+/// Whether messages on the command USART are framed. This starts unframed, and switches to framed
+/// when the first valid framed command is received.
+static FRAMED: AtomicBool = AtomicBool::new(false);
+
+pub fn encoding() -> Encoding {
+    if FRAMED.load(Ordering::Relaxed) {
+        Encoding::Framed
+    } else {
+        Encoding::Unframed
+    }
+}
+
+fn set_encoding(encoding: Encoding) {
+    FRAMED.store(encoding == Encoding::Framed, Ordering::Relaxed);
+}
+
+/// The most verbose level of log message to send, as `LogLevel as u8`.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Returns whether log messages at the given level should be sent.
+fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Sends the given log message from this side, if its level is enabled. This is only called by the
+/// `log!` macro; it isn't inlined, so that each log doesn't add much code.
+#[inline(never)]
+pub fn send_log(serial: &mut SerialWriter, level: LogLevel, args: Arguments) {
+    if log_enabled(level) {
+        send_response(serial, Response::log_from_fmt(level, args));
+    }
+}
+
+/// The number of responses which have been dropped and not yet reported.
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Returns whether the given response may be dropped if there isn't room for it in the transmit
+/// buffer, rather than waiting. These are logs, and reports which are superseded by the next one.
+fn droppable(response: &Response) -> bool {
+    matches!(
+        response,
+        Response::Log { .. }
+            | Response::Position(_)
+            | Response::Velocity(_)
+            | Response::Telemetry(_)
+            | Response::ImuReadings { .. }
+    )
+}
+
+/// Send the given response from this side, in the current encoding.
+pub fn send_response(serial: &mut SerialWriter, response: Response) {
+    write_response(
+        serial,
+        &SideResponse {
+            side: this_side(),
+            response,
+        },
     );
 }
 
-#[cfg(feature = "primary")]
-pub const THIS_SIDE: Side = Side::Right;
-#[cfg(feature = "secondary")]
-pub const THIS_SIDE: Side = Side::Left;
+/// Writes the given response in the current encoding. If it is droppable and there isn't room for
+/// all of it in the transmit buffer, it is counted to report later instead, so that a slow host
+/// can't stall the main loop.
+fn write_response(serial: &mut SerialWriter, side_response: &SideResponse) {
+    let encoding = encoding();
+    if droppable(&side_response.response)
+        && serial.space() < side_response.length_with_encoding(encoding)
+    {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        side_response.write_with_encoding(encoding, serial).unwrap();
+    }
+}
 
-pub fn send_position<W: Write>(serial: &mut W, position: i64)
-where
-    W::Error: Debug,
-{
-    SideResponse {
-        side: THIS_SIDE,
-        response: Response::Position(position),
+/// Reports how many responses have been dropped since this was last sent, if any, once there is
+/// room for it in the transmit buffer.
+pub fn report_dropped(serial: &mut SerialWriter) {
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    if dropped == 0 {
+        return;
     }
-    .write_to(serial)
-    .unwrap();
+    let side_response = SideResponse {
+        side: this_side(),
+        response: Response::Dropped(dropped),
+    };
+    let encoding = encoding();
+    if serial.space() >= side_response.length_with_encoding(encoding) {
+        side_response.write_with_encoding(encoding, serial).unwrap();
+        DROPPED.fetch_sub(dropped, Ordering::Relaxed);
+    }
+}
+
+pub fn send_position(serial: &mut SerialWriter, position: i64) {
+    send_response(serial, Response::Position(position));
 }
 
-fn send_battery_readings<W: Write>(
-    serial: &mut W,
+fn send_battery_readings(
+    serial: &mut SerialWriter,
     battery_voltage: u16,
     backup_battery_voltage: u16,
-    motor_current: u16,
-) where
-    W::Error: Debug,
-{
-    SideResponse {
-        side: THIS_SIDE,
-        response: Response::BatteryReadings {
+    motor_current: i32,
+) {
+    send_response(
+        serial,
+        Response::BatteryReadings {
             battery_voltage,
             backup_battery_voltage,
             motor_current,
         },
-    }
-    .write_to(serial)
-    .unwrap();
+    );
 }
 
-fn send_charge_state<W: Write>(serial: &mut W, charger_connected: bool)
-where
-    W::Error: Debug,
-{
-    SideResponse {
-        side: THIS_SIDE,
-        response: Response::ChargeState { charger_connected },
+fn send_version(serial: &mut SerialWriter) {
+    send_response(
+        serial,
+        Response::Version(FirmwareVersion {
+            protocol_version: ProtocolVersion::LATEST.to_byte(),
+            crate_version: ArrayString::from(env!("CARGO_PKG_VERSION")).unwrap_or_default(),
+            git_hash: ArrayString::from(env!("GIT_HASH")).unwrap_or_default(),
+            // Commands are only processed once the role is known.
+            role: role().unwrap(),
+        }),
+    );
+}
+
+pub fn send_imu_readings(hoverboard: &mut Hoverboard) {
+    match hoverboard.imu_readings() {
+        Ok(readings) => send_response(
+            hoverboard.response_tx(),
+            Response::ImuReadings {
+                accel: readings.accel,
+                gyro: readings.gyro,
+            },
+        ),
+        Err(e) => log!(
+            hoverboard.response_tx(),
+            Error,
+            "Error reading IMU: {:?}",
+            e
+        ),
     }
-    .write_to(serial)
-    .unwrap();
 }
 
-/// Process the given response from the secondary board.
-#[cfg(feature = "primary")]
-pub fn process_response(response: &[u8], hoverboard: &mut Hoverboard) -> bool {
-    match SideResponse::parse_exact(response) {
-        Ok(side_response) => {
-            side_response.write_to(hoverboard.response_tx()).unwrap();
+fn send_charge_state(serial: &mut SerialWriter, charger_connected: bool) {
+    send_response(serial, Response::ChargeState { charger_connected });
+}
+
+/// Process the given response from the secondary board, returning the number of bytes which were
+/// used or should be dropped, or 0 if not enough was read yet.
+///
+/// The secondary only switches to framed responses once a framed command has been forwarded to it,
+/// so accept either encoding from it and re-encode in the one the host is using.
+/// Heartbeats from the secondary are recorded rather than passed on.
+pub fn process_response(
+    response: &[u8],
+    hoverboard: &mut Hoverboard,
+    link_monitor: &mut LinkMonitor,
+) -> usize {
+    match SideResponse::parse_any_encoding(response) {
+        Ok((side_response, length)) => {
+            if side_response.response == Response::Heartbeat {
+                link_monitor.heartbeat_received();
+                return length;
+            }
+            write_response(hoverboard.response_tx(), &side_response);
             if side_response.response == Response::PowerOff {
                 poweroff(hoverboard);
             }
-            true
+            length
         }
-        Err(WouldBlock) => false,
-        Err(Other(protocol_error)) => {
+        Err(WouldBlock) => 0,
+        Err(Other((protocol_error, length))) => {
             log!(
                 hoverboard.response_tx(),
+                Warn,
                 "Unrecognised response {}",
                 protocol_error
             );
-            true
+            length
         }
     }
 }
 
-#[cfg(feature = "primary")]
-fn forward_command(hoverboard: &mut Hoverboard, command: &DirectedCommand) {
-    command.write_to(&mut hoverboard.serial_writer).unwrap();
+/// Forwards the given command to the secondary, if this is the primary and the link to it is up.
+///
+/// Commands are always forwarded framed, whichever encoding the host is using, so that the
+/// secondary can tell them apart from anything else while it is working out its role.
+fn forward_command(
+    hoverboard: &mut Hoverboard,
+    link_monitor: &LinkMonitor,
+    command: &DirectedCommand,
+) {
+    if role() == Some(Role::Primary) && link_monitor.is_lost() {
+        log!(
+            hoverboard.response_tx(),
+            Warn,
+            "Link to secondary lost, not forwarding"
+        );
+        if let Some(sequence) = command.sequence {
+            send_response(
+                hoverboard.response_tx(),
+                Response::Nack(sequence, ProtocolError::LinkLost),
+            );
+        }
+    } else if role() == Some(Role::Primary) {
+        command
+            .write_with_encoding(Encoding::Framed, &mut hoverboard.serial_writer)
+            .unwrap();
+    } else {
+        log!(hoverboard.response_tx(), Warn, "Secondary can't forward.");
+        if let Some(sequence) = command.sequence {
+            send_response(
+                hoverboard.response_tx(),
+                Response::Nack(sequence, ProtocolError::InvalidSide(command.side.to_byte())),
+            );
+        }
+    }
 }
 
-#[cfg(feature = "secondary")]
-fn forward_command(hoverboard: &mut Hoverboard, _command: &DirectedCommand) {
-    log!(hoverboard.response_tx(), "Secondary can't forward.");
+/// Sends a heartbeat to the other side: as a command from the primary, or a response from the
+/// secondary.
+pub fn send_heartbeat(hoverboard: &mut Hoverboard) {
+    match role() {
+        Some(Role::Primary) => DirectedCommand {
+            side: this_side().opposite(),
+            command: Command::Heartbeat,
+            sequence: None,
+        }
+        .write_with_encoding(Encoding::Framed, &mut hoverboard.serial_writer)
+        .unwrap(),
+        Some(Role::Secondary) => send_response(hoverboard.response_tx(), Response::Heartbeat),
+        None => {}
+    }
 }
 
-/// Process the given command, returning true if a command was successfully parsed or false if not
-/// enough was read yet.
+/// Reads a byte into the given buffer if one is available, and returns whether the buffer then
+/// starts with a complete valid command. Bytes which can't be the start of one are dropped.
+///
+/// This is used to work out the role from which USART commands arrive on. If `framed_only` is set
+/// then unframed commands are ignored, so that the unframed responses which an older secondary
+/// sends can't be mistaken for commands.
+pub fn probe_command<R: Read + ReadReady>(
+    rx: &mut R,
+    buffer: &mut [u8],
+    length: &mut usize,
+    framed_only: bool,
+) -> bool {
+    if !rx.read_ready().unwrap_or(false) {
+        return false;
+    }
+    if !matches!(rx.read(&mut buffer[*length..*length + 1]), Ok(1)) {
+        *length = 0;
+        return false;
+    }
+    *length += 1;
+
+    while *length > 0 {
+        let framed = buffer[0] == FRAME_START;
+        let used = if framed_only && !framed {
+            1
+        } else {
+            let encoding = if framed {
+                Encoding::Framed
+            } else {
+                Encoding::Unframed
+            };
+            match DirectedCommand::parse_with_encoding(encoding, &buffer[..*length]) {
+                Ok(_) => return true,
+                Err(WouldBlock) if *length < buffer.len() => return false,
+                // Drop the first byte, in case a corrupt frame length is hiding a valid command.
+                Err(WouldBlock) => 1,
+                Err(Other((_, used))) => used,
+            }
+        };
+        buffer.copy_within(used..*length, 0);
+        *length -= used;
+    }
+    false
+}
+
+/// Process the given command, returning the number of bytes which were used or should be dropped,
+/// or 0 if not enough was read yet.
+///
+/// A valid framed command switches to the framed encoding, after which unframed commands are
+/// ignored.
+#[allow(clippy::too_many_arguments)]
 pub fn process_command<const L: usize>(
     command: &[u8],
     hoverboard: &mut Hoverboard,
     torque_limits: &mut TorqueLimits,
-    target_position: &mut Option<i64>,
-    spring_constant: &mut i64,
+    target: &mut Option<Target>,
+    position_controller: &mut PositionController,
+    velocity_controller: &mut VelocityController,
+    calibration: &mut Option<Calibration>,
+    config_store: &mut ConfigStore,
+    reporting: &mut Reporting,
+    battery_monitor: &mut BatteryMonitor,
+    link_monitor: &mut LinkMonitor,
+    comms_monitor: &mut CommsMonitor,
     note_queue: &mut CircularBuffer<Note, L>,
-) -> bool {
-    let message = match DirectedCommand::parse(command) {
-        Ok(message) => message,
-        Err(nb::Error::WouldBlock) => return false,
-        Err(err) => {
+) -> usize {
+    let encoding = if command.first() == Some(&FRAME_START) {
+        Encoding::Framed
+    } else {
+        encoding()
+    };
+    let (message, length) = match DirectedCommand::parse_with_encoding(encoding, command) {
+        Ok(result) => result,
+        Err(WouldBlock) => return 0,
+        Err(Other((err, length))) => {
             log!(
                 hoverboard.response_tx(),
+                Warn,
                 "Unrecognised command {} or problem {:?}",
                 command[0],
                 err
             );
+            if let Some(sequence) = DirectedCommand::parse_sequence(encoding, command) {
+                send_response(hoverboard.response_tx(), Response::Nack(sequence, err));
+            }
             // Make sure the buffer progresses here, and we don't get stuck with the same duff
             // input bytes at the start of our buffer forever.
-            return true;
+            return length;
         }
     };
+    if encoding != self::encoding() {
+        set_encoding(encoding);
+        log!(hoverboard.response_tx(), Info, "Encoding {:?}", encoding);
+    }
+    // Heartbeats come from the primary rather than the host, so don't show that the host is there.
+    if message.command != Command::Heartbeat {
+        comms_monitor.command_received();
+    }
 
-    if message.side == THIS_SIDE {
-        handle_command(
+    if message.side == this_side() {
+        let result = handle_command(
             message.command,
             hoverboard,
             torque_limits,
-            target_position,
-            spring_constant,
+            target,
+            position_controller,
+            velocity_controller,
+            calibration,
+            config_store,
+            reporting,
+            battery_monitor,
+            link_monitor,
+            comms_monitor,
             note_queue,
         );
+        if let Some(sequence) = message.sequence {
+            let response = match result {
+                Ok(()) => Response::Ack(sequence),
+                Err(e) => Response::Nack(sequence, e),
+            };
+            send_response(hoverboard.response_tx(), response);
+        }
     } else {
-        forward_command(hoverboard, &message);
+        // The other side will acknowledge the command itself, if necessary.
+        forward_command(hoverboard, link_monitor, &message);
     }
-    true
+    length
 }
 
+/// Runs the given command, returning an error if it was refused or failed.
+#[allow(clippy::too_many_arguments)]
 pub fn handle_command<const L: usize>(
     command: Command,
     hoverboard: &mut Hoverboard,
     torque_limits: &mut TorqueLimits,
-    target_position: &mut Option<i64>,
-    spring_constant: &mut i64,
+    target: &mut Option<Target>,
+    position_controller: &mut PositionController,
+    velocity_controller: &mut VelocityController,
+    calibration: &mut Option<Calibration>,
+    config_store: &mut ConfigStore,
+    reporting: &mut Reporting,
+    battery_monitor: &mut BatteryMonitor,
+    link_monitor: &mut LinkMonitor,
+    comms_monitor: &mut CommsMonitor,
     note_queue: &mut CircularBuffer<Note, L>,
-) {
+) -> Result<(), ProtocolError> {
     match command {
         Command::SetSideLed(on) => {
             if on {
-                log!(hoverboard.response_tx(), "side LED on");
+                log!(hoverboard.response_tx(), Debug, "side LED on");
                 hoverboard.leds.side.set_high().unwrap()
             } else {
-                log!(hoverboard.response_tx(), "side LED off");
+                log!(hoverboard.response_tx(), Debug, "side LED off");
                 hoverboard.leds.side.set_low().unwrap()
             }
         }
         Command::SetOrangeLed(on) => {
             if on {
-                log!(hoverboard.response_tx(), "orange on");
+                log!(hoverboard.response_tx(), Debug, "orange on");
                 hoverboard.leds.orange.set_high().unwrap()
             } else {
-                log!(hoverboard.response_tx(), "orange off");
+                log!(hoverboard.response_tx(), Debug, "orange off");
                 hoverboard.leds.orange.set_low().unwrap()
             }
         }
         Command::SetRedLed(on) => {
             if on {
-                log!(hoverboard.response_tx(), "red on");
+                log!(hoverboard.response_tx(), Debug, "red on");
                 hoverboard.leds.red.set_high().unwrap()
             } else {
-                log!(hoverboard.response_tx(), "red off");
+                log!(hoverboard.response_tx(), Debug, "red off");
                 hoverboard.leds.red.set_low().unwrap()
             }
         }
         Command::SetGreenLed(on) => {
             if on {
-                log!(hoverboard.response_tx(), "green on");
+                log!(hoverboard.response_tx(), Debug, "green on");
                 hoverboard.leds.green.set_high().unwrap()
             } else {
-                log!(hoverboard.response_tx(), "green off");
+                log!(hoverboard.response_tx(), Debug, "green off");
                 hoverboard.leds.green.set_low().unwrap()
             }
         }
@@ -200,6 +469,7 @@ pub fn handle_command<const L: usize>(
             if !note_queue.add(note) {
                 log!(
                     hoverboard.response_tx(),
+                    Warn,
                     "Note queue full, dropping {}",
                     note
                 );
@@ -219,76 +489,269 @@ pub fn handle_command<const L: usize>(
             send_charge_state(hoverboard.response_tx(), charger_connected);
         }
         Command::SetMaxTorque(limits) => {
-            log!(hoverboard.response_tx(), "Max torque {:?}", limits);
+            log!(hoverboard.response_tx(), Info, "Max torque {:?}", limits);
             *torque_limits = limits;
         }
         Command::SetSpringConstant(spring) => {
-            log!(hoverboard.response_tx(), "Spring constant {}", spring);
-            *spring_constant = spring as i64;
+            log!(hoverboard.response_tx(), Info, "Spring constant {}", spring);
+            position_controller.set_gains(Gains {
+                kp: spring.saturating_mul(GAIN_SCALE as u16),
+                ..position_controller.gains()
+            });
+        }
+        Command::SetGains { kp, ki, kd } => {
+            let gains = Gains { kp, ki, kd };
+            log!(hoverboard.response_tx(), Info, "Gains {}", gains);
+            position_controller.set_gains(gains);
+        }
+        Command::SetVelocityGains { kp, ki, kd } => {
+            let gains = Gains { kp, ki, kd };
+            log!(hoverboard.response_tx(), Info, "Velocity gains {}", gains);
+            velocity_controller.set_gains(gains);
         }
         Command::RemoveTarget => {
-            log!(hoverboard.response_tx(), "No target position");
-            *target_position = None;
+            log!(hoverboard.response_tx(), Debug, "No target position");
+            *target = None;
+        }
+        Command::SetTarget(target_position) => {
+            *target = Some(Target::Position(target_position));
         }
-        Command::SetTarget(target) => {
-            *target_position = Some(target);
+        Command::SetVelocity(velocity) => {
+            *target = Some(Target::Velocity(velocity));
+        }
+        Command::SetTorque(torque) => {
+            *target = Some(Target::Torque(torque));
         }
         Command::Recenter => {
-            log!(hoverboard.response_tx(), "Recenter");
+            log!(hoverboard.response_tx(), Info, "Recenter");
             hoverboard.recenter_motor();
-            *target_position = Some(0);
+            *target = Some(Target::Position(0));
         }
         Command::IncrementTarget => {
-            let target = target_position.unwrap_or(0) + 10;
-            log!(hoverboard.response_tx(), "Target position {}", target);
-            *target_position = Some(target);
+            let target_position = target.and_then(Target::position).unwrap_or(0) + 10;
+            log!(
+                hoverboard.response_tx(),
+                Debug,
+                "Target position {}",
+                target_position
+            );
+            *target = Some(Target::Position(target_position));
         }
         Command::DecrementTarget => {
-            let target = target_position.unwrap_or(0) - 10;
-            log!(hoverboard.response_tx(), "Target position {}", target);
-            *target_position = Some(target);
+            let target_position = target.and_then(Target::position).unwrap_or(0) - 10;
+            log!(
+                hoverboard.response_tx(),
+                Debug,
+                "Target position {}",
+                target_position
+            );
+            *target = Some(Target::Position(target_position));
         }
         Command::PowerOff => poweroff(hoverboard),
         Command::TestMotor => {
-            log!(hoverboard.response_tx(), "Setting motor PWM for test");
+            log!(hoverboard.response_tx(), Info, "Setting motor PWM for test");
             log!(
                 hoverboard.response_tx(),
+                Info,
                 "yellow (PA8/PB13) = 0%, blue (PA9/PB14) = 25%, green (PA10/PB15) = 50%"
             );
             hoverboard.set_motor_pwm_for_test(0, 25, 50);
         }
+        Command::SetMotorTuning(tuning) => {
+            let tuning = hoverboard.set_motor_tuning(tuning);
+            log!(hoverboard.response_tx(), Info, "Motor tuning {}", tuning);
+        }
+        Command::GetMotorTuning => {
+            let tuning = hoverboard.motor_tuning();
+            send_response(hoverboard.response_tx(), Response::MotorTuning(tuning));
+        }
+        Command::SaveConfig => {
+            if target.is_some() || calibration.is_some() {
+                log!(
+                    hoverboard.response_tx(),
+                    Warn,
+                    "Not saving config while the motor is being driven"
+                );
+                return Err(ProtocolError::MotorBusy);
+            }
+            let config = current_config(
+                hoverboard,
+                config_store.role(),
+                *torque_limits,
+                position_controller,
+                velocity_controller,
+                battery_monitor,
+            );
+            if let Err(e) = config_store.save(&config) {
+                log!(
+                    hoverboard.response_tx(),
+                    Error,
+                    "Saving config failed: {:?}",
+                    e
+                );
+                return Err(ProtocolError::FlashFailed);
+            }
+            log!(hoverboard.response_tx(), Info, "Config saved");
+        }
+        Command::LoadConfig => match config_store.load() {
+            Some(config) => {
+                apply_config(
+                    &config,
+                    hoverboard,
+                    torque_limits,
+                    position_controller,
+                    velocity_controller,
+                    battery_monitor,
+                );
+                log!(hoverboard.response_tx(), Info, "Config loaded");
+            }
+            None => log!(hoverboard.response_tx(), Info, "No saved config"),
+        },
+        Command::FactoryReset => {
+            if target.is_some() || calibration.is_some() {
+                log!(
+                    hoverboard.response_tx(),
+                    Warn,
+                    "Not erasing config while the motor is being driven"
+                );
+                return Err(ProtocolError::MotorBusy);
+            }
+            if let Err(e) = config_store.erase() {
+                log!(
+                    hoverboard.response_tx(),
+                    Error,
+                    "Erasing config failed: {:?}",
+                    e
+                );
+                return Err(ProtocolError::FlashFailed);
+            }
+            apply_config(
+                &DEFAULT_CONFIG,
+                hoverboard,
+                torque_limits,
+                position_controller,
+                velocity_controller,
+                battery_monitor,
+            );
+            log!(hoverboard.response_tx(), Info, "Config reset to defaults");
+        }
+        Command::SetRole(role) => {
+            log!(
+                hoverboard.response_tx(),
+                Info,
+                "Role to save {:?}, from the next start",
+                role
+            );
+            config_store.set_role(role);
+        }
+        Command::CalibrateMotor => {
+            log!(hoverboard.response_tx(), Info, "Calibrating motor");
+            *target = None;
+            *calibration = Some(Calibration::default());
+        }
+        Command::GetVersion => send_version(hoverboard.response_tx()),
+        Command::ReportImu => send_imu_readings(hoverboard),
+        Command::StreamImu(interval_ms) => {
+            log!(
+                hoverboard.response_tx(),
+                Info,
+                "IMU interval {} ms",
+                interval_ms
+            );
+            reporting.set_imu_interval(interval_ms);
+        }
+        Command::SetTelemetry {
+            interval_ms,
+            fields,
+        } => {
+            log!(
+                hoverboard.response_tx(),
+                Info,
+                "Telemetry interval {} ms, fields {:#04x}",
+                interval_ms,
+                fields.bits()
+            );
+            reporting.set_telemetry(interval_ms, fields);
+        }
+        Command::SetCurrentLimit(current_limit_ma) => {
+            log!(
+                hoverboard.response_tx(),
+                Info,
+                "Current limit {} mA",
+                current_limit_ma
+            );
+            hoverboard.set_current_limit(current_limit_ma);
+        }
+        Command::SetCommutation(commutation) => {
+            log!(
+                hoverboard.response_tx(),
+                Info,
+                "Commutation {:?}",
+                commutation
+            );
+            hoverboard.set_commutation(commutation);
+        }
+        Command::ClearFault => {
+            if hoverboard.clear_fault() {
+                log!(hoverboard.response_tx(), Info, "Fault cleared");
+            }
+        }
+        Command::ReportHallDiagnostics => {
+            let (invalid_states, skipped_sectors) = hoverboard.hall_diagnostics();
+            send_response(
+                hoverboard.response_tx(),
+                Response::HallDiagnostics {
+                    invalid_states,
+                    skipped_sectors,
+                },
+            );
+        }
+        Command::SetBatteryThresholds {
+            warning_mv,
+            cutoff_mv,
+        } => {
+            log!(
+                hoverboard.response_tx(),
+                Info,
+                "Battery warning {} mV, cutoff {} mV",
+                warning_mv,
+                cutoff_mv
+            );
+            battery_monitor.set_thresholds(warning_mv, cutoff_mv);
+        }
+        Command::Heartbeat => {
+            // Only the primary sends these, so ignore any which come to it from the host.
+            if role() == Some(Role::Secondary) {
+                link_monitor.heartbeat_received();
+            }
+        }
+        Command::SetCommsTimeout {
+            timeout_ms,
+            safe_torque,
+        } => {
+            log!(
+                hoverboard.response_tx(),
+                Info,
+                "Comms timeout {} ms, safe torque {}",
+                timeout_ms,
+                safe_torque
+            );
+            comms_monitor.set_timeout(timeout_ms, safe_torque);
+        }
+        Command::KeepAlive => {}
+        Command::SetLogLevel(level) => {
+            set_log_level(level);
+            log!(hoverboard.response_tx(), Info, "Log level {:?}", level);
+        }
+        Command::GetConfig => send_response(
+            hoverboard.response_tx(),
+            Response::Config {
+                torque_limits: *torque_limits,
+                gains: position_controller.gains(),
+                velocity_gains: velocity_controller.gains(),
+                target: *target,
+            },
+        ),
     }
-}
-
-pub trait HoverboardExt {
-    type CommandUsart: Deref<Target = usart0::RegisterBlock>;
-
-    fn command_rx(&mut self) -> &mut Rx<Self::CommandUsart>;
-    fn response_tx(&mut self) -> &mut BufferedSerialWriter<Tx<Self::CommandUsart>>;
-}
-
-#[cfg(feature = "primary")]
-impl HoverboardExt for Hoverboard {
-    type CommandUsart = pac::Usart0;
-
-    fn command_rx(&mut self) -> &mut Rx<pac::Usart0> {
-        &mut self.serial_remote_rx
-    }
-
-    fn response_tx(&mut self) -> &mut BufferedSerialWriter<Tx<pac::Usart0>> {
-        &mut self.serial_remote_writer
-    }
-}
-
-#[cfg(feature = "secondary")]
-impl HoverboardExt for Hoverboard {
-    type CommandUsart = pac::Usart1;
-
-    fn command_rx(&mut self) -> &mut Rx<pac::Usart1> {
-        &mut self.serial_rx
-    }
-
-    fn response_tx(&mut self) -> &mut BufferedSerialWriter<Tx<pac::Usart1>> {
-        &mut self.serial_writer
-    }
+    Ok(())
 }
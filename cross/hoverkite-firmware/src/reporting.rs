@@ -0,0 +1,92 @@
+use crate::hoverboard::Hoverboard;
+use crate::protocol::{send_imu_readings, send_position, send_response};
+use embedded_hal::digital::InputPin;
+use messages::{BatteryReadings, Response, Telemetry, TelemetryFields};
+
+/// When telemetry is off, the minimum time between velocity updates.
+const VELOCITY_REPORT_INTERVAL_MS: u32 = 100;
+
+/// Keeps track of which periodic reports the host has asked for, and when they are next due.
+#[derive(Debug, Default)]
+pub struct Reporting {
+    /// How often to send IMU readings, or 0 to not send them.
+    imu_interval_ms: u32,
+    /// The timestamp at which to next send IMU readings.
+    next_imu_time: u32,
+    /// How often to send telemetry, or 0 to instead send the position whenever it changes.
+    telemetry_interval_ms: u32,
+    telemetry_fields: TelemetryFields,
+    /// The timestamp at which to next send telemetry.
+    next_telemetry_time: u32,
+    /// The position when it was last sent.
+    last_position: i64,
+    /// The velocity when it was last sent.
+    last_velocity: i32,
+    /// The timestamp at which to next check whether to send the velocity, when telemetry is off.
+    next_velocity_time: u32,
+}
+
+impl Reporting {
+    pub fn set_imu_interval(&mut self, interval_ms: u16) {
+        self.imu_interval_ms = interval_ms.into();
+        self.next_imu_time = 0;
+    }
+
+    pub fn set_telemetry(&mut self, interval_ms: u16, fields: TelemetryFields) {
+        self.telemetry_interval_ms = interval_ms.into();
+        self.telemetry_fields = fields;
+        self.next_telemetry_time = 0;
+    }
+
+    /// Send any reports which are due.
+    pub fn poll(&mut self, hoverboard: &mut Hoverboard, current_time: u32) {
+        if self.imu_interval_ms != 0 && current_time >= self.next_imu_time {
+            send_imu_readings(hoverboard);
+            self.next_imu_time = current_time + self.imu_interval_ms;
+        }
+
+        let position = hoverboard.motor_position();
+        if self.telemetry_interval_ms == 0 {
+            // Log if the position has changed.
+            if position != self.last_position {
+                send_position(hoverboard.response_tx(), position);
+                self.last_position = position;
+            }
+            if current_time >= self.next_velocity_time {
+                let velocity = hoverboard.motor_velocity();
+                if velocity != self.last_velocity {
+                    send_response(hoverboard.response_tx(), Response::Velocity(velocity));
+                    self.last_velocity = velocity;
+                }
+                self.next_velocity_time = current_time + VELOCITY_REPORT_INTERVAL_MS;
+            }
+        } else if current_time >= self.next_telemetry_time {
+            let telemetry = self.telemetry(hoverboard, position);
+            send_response(hoverboard.response_tx(), Response::Telemetry(telemetry));
+            self.next_telemetry_time = current_time + self.telemetry_interval_ms;
+        }
+    }
+
+    fn telemetry(&self, hoverboard: &mut Hoverboard, position: i64) -> Telemetry {
+        let fields = self.telemetry_fields;
+        let mut telemetry = Telemetry::default();
+        if fields.contains(TelemetryFields::POSITION) {
+            telemetry.position = Some(position);
+        }
+        if fields.contains(TelemetryFields::VELOCITY) {
+            telemetry.velocity = Some(hoverboard.motor_velocity());
+        }
+        if fields.contains(TelemetryFields::BATTERY) {
+            let readings = hoverboard.adc_readings();
+            telemetry.battery = Some(BatteryReadings {
+                battery_voltage: readings.battery_voltage,
+                backup_battery_voltage: readings.backup_battery_voltage,
+                motor_current: readings.motor_current,
+            });
+        }
+        if fields.contains(TelemetryFields::CHARGER) {
+            telemetry.charger_connected = Some(hoverboard.charge_state.is_low().unwrap());
+        }
+        telemetry
+    }
+}
@@ -12,6 +12,18 @@ const CONFIG_FILENAME: &str = "hovercontrol.toml";
 pub struct Config {
     pub right_port: String,
     pub left_port: Option<String>,
+    /// Whether to send commands bare rather than framed with a CRC.
+    #[serde(default)]
+    pub unframed: bool,
+    /// How often each side should report its IMU readings, in milliseconds.
+    pub imu_interval_ms: Option<u16>,
+    /// How often each side should report telemetry, in milliseconds.
+    pub telemetry_interval_ms: Option<u16>,
+    /// Whether to use sinusoidal rather than block commutation for the motors.
+    #[serde(default)]
+    pub sinusoidal_commutation: bool,
+    /// How long each side should go without a command before stopping, in milliseconds.
+    pub comms_timeout_ms: Option<u16>,
     pub mqtt: Option<MqttConfig>,
 }
 
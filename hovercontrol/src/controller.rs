@@ -1,8 +1,12 @@
 use crate::homie::Homie;
 use eyre::{Report, WrapErr};
 use gilrs::{Axis, Button, Event, EventType, Gilrs};
-use messages::client::{Hoverkite, MIN_TIME_BETWEEN_TARGET_UPDATES};
-use messages::{Command, Response, Side, SideResponse, TorqueLimits};
+use log::{warn, Level, LevelFilter};
+use messages::client::Hoverkite;
+use messages::{
+    Command, Commutation, Gains, LogLevel, Response, Side, SideResponse, TelemetryFields,
+    TorqueLimits,
+};
 use std::thread;
 use std::time::Duration;
 
@@ -18,12 +22,42 @@ pub const DEFAULT_MAX_TORQUE: TorqueLimits = TorqueLimits {
 pub const MAX_MAX_TORQUE: i16 = 300;
 const MAX_TORQUE_STEP: i16 = 10;
 
-pub const DEFAULT_SPRING_CONSTANT: u16 = 10;
-pub const MAX_SPRING_CONSTANT: u16 = 50;
-pub const SPRING_CONSTANT_STEP: u16 = 2;
+pub const DEFAULT_GAINS: Gains = Gains {
+    kp: 1000,
+    ki: 0,
+    kd: 20,
+};
+pub const MAX_KP: u16 = 5000;
+pub const KP_STEP: u16 = 200;
 
 const CENTRE_STEP: i64 = 20;
 
+/// In velocity mode, the velocity in steps per second for each step of stick offset.
+const VELOCITY_PER_OFFSET: i64 = 4;
+/// In torque mode, the torque for each step of stick offset.
+const TORQUE_PER_OFFSET: i64 = 4;
+
+/// How the sticks control the motors.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Mode {
+    /// The sticks move the target position either side of the centre.
+    Position,
+    /// The sticks set the target velocity.
+    Velocity,
+    /// The sticks set the torque directly.
+    Torque,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Position => "position",
+            Self::Velocity => "velocity",
+            Self::Torque => "torque",
+        }
+    }
+}
+
 pub struct Controller {
     hoverkite: Hoverkite,
     gilrs: Gilrs,
@@ -32,13 +66,32 @@ pub struct Controller {
     offset_right: i64,
     centre_left: i64,
     centre_right: i64,
+    /// The last reported position of each motor.
+    position_left: i64,
+    position_right: i64,
+    mode: Mode,
     scale: f32,
     max_torque: TorqueLimits,
-    spring_constant: u16,
+    gains: Gains,
+    /// How often to ask each side to report its IMU readings, if at all.
+    imu_interval_ms: Option<u16>,
+    /// How often to ask each side to report telemetry, if at all.
+    telemetry_interval_ms: Option<u16>,
+    commutation: Commutation,
+    /// How long each side should go without a command before stopping, if at all.
+    comms_timeout_ms: Option<u16>,
 }
 
 impl Controller {
-    pub fn new(hoverkite: Hoverkite, gilrs: Gilrs, homie: Homie) -> Self {
+    pub fn new(
+        hoverkite: Hoverkite,
+        gilrs: Gilrs,
+        homie: Homie,
+        imu_interval_ms: Option<u16>,
+        telemetry_interval_ms: Option<u16>,
+        commutation: Commutation,
+        comms_timeout_ms: Option<u16>,
+    ) -> Self {
         Self {
             hoverkite,
             gilrs,
@@ -47,16 +100,50 @@ impl Controller {
             offset_right: 0,
             centre_left: 0,
             centre_right: 0,
+            position_left: 0,
+            position_right: 0,
+            mode: Mode::Position,
             scale: DEFAULT_SCALE,
             max_torque: DEFAULT_MAX_TORQUE,
-            spring_constant: DEFAULT_SPRING_CONSTANT,
+            gains: DEFAULT_GAINS,
+            imu_interval_ms,
+            telemetry_interval_ms,
+            commutation,
+            comms_timeout_ms,
         }
     }
 
     pub fn run(&mut self) -> Result<(), Report> {
-        self.send_max_torque()?;
-        thread::sleep(MIN_TIME_BETWEEN_TARGET_UPDATES);
-        self.send_spring_constant()?;
+        self.hoverkite
+            .handshake()
+            .wrap_err("Handshake with hoverboard failed")?;
+        self.sync_config()?;
+        // There's no point in the boards sending logs which won't be shown.
+        self.hoverkite
+            .set_log_level(firmware_log_level(log::max_level()))
+            .wrap_err("Failed to set log level")?;
+        self.hoverkite
+            .send_command_confirmed_to_present(Command::SetCommutation(self.commutation))
+            .wrap_err("Failed to set commutation")?;
+        if let Some(imu_interval_ms) = self.imu_interval_ms {
+            self.hoverkite
+                .send_command_confirmed_to_present(Command::StreamImu(imu_interval_ms))
+                .wrap_err("Failed to start IMU streaming")?;
+        }
+        if let Some(interval_ms) = self.telemetry_interval_ms {
+            let command = Command::SetTelemetry {
+                interval_ms,
+                fields: TelemetryFields::ALL,
+            };
+            self.hoverkite
+                .send_command_confirmed_to_present(command)
+                .wrap_err("Failed to start telemetry")?;
+        }
+        if let Some(timeout_ms) = self.comms_timeout_ms {
+            self.hoverkite
+                .set_comms_timeout(timeout_ms, 0)
+                .wrap_err("Failed to set comms timeout")?;
+        }
 
         loop {
             for response in self.hoverkite.poll()? {
@@ -71,9 +158,90 @@ impl Controller {
         }
     }
 
-    fn handle_response(&self, response: &SideResponse) {
+    /// Reads the current control parameters from the board, so that changes from the gamepad start
+    /// from whatever the board is actually doing.
+    ///
+    /// The right side is taken as authoritative; if the left side disagrees then it is brought into
+    /// line.
+    fn sync_config(&mut self) -> Result<(), Report> {
+        let right = if self.hoverkite.is_present(Side::Right) {
+            Some(
+                self.hoverkite
+                    .get_config(Side::Right)
+                    .wrap_err("Failed to read config from right side")?,
+            )
+        } else {
+            None
+        };
+        let left = if self.hoverkite.is_present(Side::Left) {
+            Some(
+                self.hoverkite
+                    .get_config(Side::Left)
+                    .wrap_err("Failed to read config from left side")?,
+            )
+        } else {
+            None
+        };
+
+        // Take the config from the right side if it is there, otherwise the left.
+        let Some(config) = right.or(left) else {
+            return Ok(());
+        };
+        self.max_torque = config.torque_limits;
+        self.gains = config.gains;
+        if let (Some(left), Some(right)) = (left, right) {
+            if left.torque_limits != right.torque_limits {
+                warn!(
+                    "Left max torque {} differs from right {}, updating left",
+                    left.torque_limits, right.torque_limits
+                );
+                self.hoverkite.set_max_torque(Side::Left, self.max_torque)?;
+            }
+            if left.gains != right.gains {
+                warn!(
+                    "Left gains {} differ from right {}, updating both",
+                    left.gains, right.gains
+                );
+                self.hoverkite.set_gains(self.gains)?;
+            }
+            if left.velocity_gains != right.velocity_gains {
+                warn!(
+                    "Left velocity gains {} differ from right {}, updating both",
+                    left.velocity_gains, right.velocity_gains
+                );
+                self.hoverkite.set_velocity_gains(right.velocity_gains)?;
+            }
+        }
+        self.homie.send_max_torque(self.max_torque);
+        self.homie.send_gains(self.gains);
+
+        if let Some(target) = left.and_then(|left| left.target?.position()) {
+            self.centre_left = target;
+            self.homie.send_centre(Side::Left, target);
+            self.homie.send_target(Side::Left, target);
+        }
+        if let Some(target) = right.and_then(|right| right.target?.position()) {
+            self.centre_right = target;
+            self.homie.send_centre(Side::Right, target);
+            self.homie.send_target(Side::Right, target);
+        }
+        Ok(())
+    }
+
+    fn handle_response(&mut self, response: &SideResponse) {
         print_response(response);
 
+        if let Some(position) = match response.response {
+            Response::Position(position) => Some(position),
+            Response::Telemetry(telemetry) => telemetry.position,
+            _ => None,
+        } {
+            match response.side {
+                Side::Left => self.position_left = position,
+                Side::Right => self.position_right = position,
+            }
+        }
+
         // Send stats from the response to Homie, if appropriate.
         match response.response {
             Response::Position(position) => self.homie.send_position(response.side, position),
@@ -91,6 +259,33 @@ impl Controller {
                 self.homie
                     .send_charge_state(response.side, charger_connected);
             }
+            Response::ImuReadings { accel, gyro } => {
+                self.homie.send_imu_readings(response.side, accel, gyro)
+            }
+            Response::Velocity(velocity) => self.homie.send_velocity(response.side, velocity),
+            Response::Fault(fault) => self.homie.send_fault(response.side, Some(fault)),
+            Response::LowBattery { .. } => self.homie.send_low_battery(response.side, true),
+            Response::BatteryRecovered { .. } => self.homie.send_low_battery(response.side, false),
+            Response::Telemetry(telemetry) => {
+                if let Some(position) = telemetry.position {
+                    self.homie.send_position(response.side, position);
+                }
+                if let Some(velocity) = telemetry.velocity {
+                    self.homie.send_velocity(response.side, velocity);
+                }
+                if let Some(battery) = telemetry.battery {
+                    self.homie.send_battery_readings(
+                        response.side,
+                        battery.battery_voltage,
+                        battery.backup_battery_voltage,
+                        battery.motor_current,
+                    );
+                }
+                if let Some(charger_connected) = telemetry.charger_connected {
+                    self.homie
+                        .send_charge_state(response.side, charger_connected);
+                }
+            }
             _ => {}
         }
     }
@@ -99,11 +294,11 @@ impl Controller {
         match event {
             EventType::AxisChanged(Axis::LeftStickY, value, _code) => {
                 self.offset_left = (self.scale * value) as i64;
-                self.send_target(Side::Left)?;
+                self.send_setpoint(Side::Left)?;
             }
             EventType::AxisChanged(Axis::RightStickY, value, _code) => {
                 self.offset_right = (self.scale * value) as i64;
-                self.send_target(Side::Right)?;
+                self.send_setpoint(Side::Right)?;
             }
             EventType::ButtonPressed(Button::DPadLeft, _code) => {
                 if self.scale > 1.0 {
@@ -119,36 +314,36 @@ impl Controller {
                 println!("Scale {}", self.scale);
                 self.homie.send_scale(self.scale);
             }
-            EventType::ButtonPressed(Button::DPadUp, _code) => {
-                if -self.max_torque.negative < MAX_MAX_TORQUE {
-                    self.max_torque.negative -= MAX_TORQUE_STEP;
-                    self.send_max_torque()?;
-                }
+            EventType::ButtonPressed(Button::DPadUp, _code)
+                if -self.max_torque.negative < MAX_MAX_TORQUE =>
+            {
+                self.max_torque.negative -= MAX_TORQUE_STEP;
+                self.send_max_torque()?;
             }
-            EventType::ButtonPressed(Button::DPadDown, _code) => {
-                if -self.max_torque.negative > MAX_TORQUE_STEP {
-                    self.max_torque.negative += MAX_TORQUE_STEP;
-                    self.send_max_torque()?;
-                }
+            EventType::ButtonPressed(Button::DPadDown, _code)
+                if -self.max_torque.negative > MAX_TORQUE_STEP =>
+            {
+                self.max_torque.negative += MAX_TORQUE_STEP;
+                self.send_max_torque()?;
             }
             EventType::ButtonPressed(Button::LeftTrigger, _code) => {
                 self.centre_left += CENTRE_STEP;
-                self.send_target(Side::Left)?;
+                self.send_setpoint(Side::Left)?;
                 self.homie.send_centre(Side::Left, self.centre_left);
             }
             EventType::ButtonPressed(Button::LeftTrigger2, _code) => {
                 self.centre_left -= CENTRE_STEP;
-                self.send_target(Side::Left)?;
+                self.send_setpoint(Side::Left)?;
                 self.homie.send_centre(Side::Left, self.centre_left);
             }
             EventType::ButtonPressed(Button::RightTrigger, _code) => {
                 self.centre_right += CENTRE_STEP;
-                self.send_target(Side::Right)?;
+                self.send_setpoint(Side::Right)?;
                 self.homie.send_centre(Side::Right, self.centre_right);
             }
             EventType::ButtonPressed(Button::RightTrigger2, _code) => {
                 self.centre_right -= CENTRE_STEP;
-                self.send_target(Side::Right)?;
+                self.send_setpoint(Side::Right)?;
                 self.homie.send_centre(Side::Right, self.centre_right);
             }
             EventType::ButtonPressed(Button::LeftThumb, _code) => {
@@ -176,24 +371,32 @@ impl Controller {
                 self.hoverkite
                     .send_command(Side::Right, Command::RemoveTarget)?;
             }
-            EventType::ButtonPressed(Button::West, _code) => {
-                if self.spring_constant > SPRING_CONSTANT_STEP {
-                    self.spring_constant -= SPRING_CONSTANT_STEP;
-                    self.send_spring_constant()?;
-                }
+            EventType::ButtonPressed(Button::West, _code) if self.gains.kp > KP_STEP => {
+                self.gains.kp -= KP_STEP;
+                self.send_gains()?;
+            }
+            EventType::ButtonPressed(Button::North, _code) if self.gains.kp < MAX_KP => {
+                self.gains.kp += KP_STEP;
+                self.send_gains()?;
             }
-            EventType::ButtonPressed(Button::North, _code) => {
-                if self.spring_constant < MAX_SPRING_CONSTANT {
-                    self.spring_constant += SPRING_CONSTANT_STEP;
-                    self.send_spring_constant()?;
+            EventType::ButtonPressed(Button::Select, _code) => {
+                for side in [Side::Left, Side::Right] {
+                    self.hoverkite.send_command(side, Command::ClearFault)?;
+                    self.homie.send_fault(side, None);
                 }
             }
+            EventType::ButtonPressed(Button::Start, _code) => self.next_mode()?,
             EventType::ButtonPressed(Button::Mode, _code) => {
                 // Power off
                 self.hoverkite.send_command(Side::Left, Command::PowerOff)?;
                 self.hoverkite
                     .send_command(Side::Right, Command::PowerOff)?;
             }
+            // The torque limit and gain buttons do nothing at the end of their range.
+            EventType::ButtonPressed(
+                Button::DPadUp | Button::DPadDown | Button::West | Button::North,
+                _code,
+            ) => {}
             EventType::ButtonPressed(button, code) => {
                 println!("Button {:?} pressed: {:?}", button, code);
             }
@@ -203,21 +406,64 @@ impl Controller {
     }
 
     fn send_max_torque(&mut self) -> Result<(), Report> {
-        self.hoverkite.set_max_torque(Side::Left, self.max_torque)?;
-        self.hoverkite
-            .set_max_torque(Side::Right, self.max_torque)?;
+        for side in [Side::Left, Side::Right] {
+            if self.hoverkite.is_present(side) {
+                self.hoverkite.set_max_torque(side, self.max_torque)?;
+            }
+        }
         self.homie.send_max_torque(self.max_torque);
         Ok(())
     }
 
-    fn send_spring_constant(&mut self) -> Result<(), Report> {
+    fn send_gains(&mut self) -> Result<(), Report> {
         self.hoverkite
-            .set_spring_constant(self.spring_constant)
-            .wrap_err("Failed to set spring constant")?;
-        self.homie.send_spring_constant(self.spring_constant);
+            .set_gains(self.gains)
+            .wrap_err("Failed to set gains")?;
+        self.homie.send_gains(self.gains);
         Ok(())
     }
 
+    /// Switches from position to velocity to torque mode, and then back to position mode.
+    ///
+    /// When switching back to position mode, the centres are moved so that the motors hold
+    /// wherever they have got to.
+    fn next_mode(&mut self) -> Result<(), Report> {
+        self.mode = match self.mode {
+            Mode::Position => Mode::Velocity,
+            Mode::Velocity => Mode::Torque,
+            Mode::Torque => {
+                self.centre_left = self.position_left - self.offset_left;
+                self.centre_right = self.position_right - self.offset_right;
+                self.homie.send_centre(Side::Left, self.centre_left);
+                self.homie.send_centre(Side::Right, self.centre_right);
+                Mode::Position
+            }
+        };
+        println!("Mode {}", self.mode.name());
+        self.homie.send_mode(self.mode.name());
+        self.send_setpoint(Side::Left)?;
+        self.send_setpoint(Side::Right)
+    }
+
+    /// Sends the target position, velocity or torque for the given side, depending on the mode.
+    fn send_setpoint(&mut self, side: Side) -> Result<(), Report> {
+        let offset = match side {
+            Side::Left => self.offset_left,
+            Side::Right => self.offset_right,
+        };
+        match self.mode {
+            Mode::Position => self.send_target(side),
+            Mode::Velocity => self
+                .hoverkite
+                .set_velocity(side, (offset * VELOCITY_PER_OFFSET) as i32)
+                .wrap_err("Failed to set velocity"),
+            Mode::Torque => self
+                .hoverkite
+                .set_torque(side, (offset * TORQUE_PER_OFFSET) as i16)
+                .wrap_err("Failed to set torque"),
+        }
+    }
+
     fn send_target(&mut self, side: Side) -> Result<(), Report> {
         let target = match side {
             Side::Left => self.centre_left + self.offset_left,
@@ -231,16 +477,44 @@ impl Controller {
     }
 }
 
+/// Returns the level at which to log a message from the firmware on the host.
+fn host_log_level(level: LogLevel) -> Level {
+    match level {
+        LogLevel::Error => Level::Error,
+        LogLevel::Warn => Level::Warn,
+        LogLevel::Info => Level::Info,
+        LogLevel::Debug => Level::Debug,
+    }
+}
+
+/// Returns the most verbose level at which the firmware should send logs, so that it only sends
+/// those which the host will show. Errors are always sent.
+fn firmware_log_level(filter: LevelFilter) -> LogLevel {
+    match filter {
+        LevelFilter::Off | LevelFilter::Error => LogLevel::Error,
+        LevelFilter::Warn => LogLevel::Warn,
+        LevelFilter::Info => LogLevel::Info,
+        LevelFilter::Debug | LevelFilter::Trace => LogLevel::Debug,
+    }
+}
+
 fn print_response(side_response: &SideResponse) {
     match side_response.response {
-        Response::Log(log) => println!("{:?}: '{}'", side_response.side, log),
+        Response::Log { level, message } => {
+            log::log!(
+                host_log_level(level),
+                "{:?}: {}",
+                side_response.side,
+                message
+            )
+        }
         Response::Position(position) => println!("{:?} at {}", side_response.side, position),
         Response::BatteryReadings {
             battery_voltage,
             backup_battery_voltage,
             motor_current,
         } => println!(
-            "{:?} battery voltage: {} mV, backup: {} mV, current {} mV",
+            "{:?} battery voltage: {} mV, backup: {} mV, current {} mA",
             side_response.side, battery_voltage, backup_battery_voltage, motor_current
         ),
         Response::ChargeState { charger_connected } => println!(
@@ -253,5 +527,69 @@ fn print_response(side_response: &SideResponse) {
             }
         ),
         Response::PowerOff => println!("{:?} powering off", side_response.side),
+        Response::Version(ref version) => println!("{:?}: {}", side_response.side, version),
+        Response::Ack(sequence) => println!("{:?} acknowledged {}", side_response.side, sequence),
+        Response::Nack(sequence, error) => {
+            println!("{:?} rejected {}: {}", side_response.side, sequence, error)
+        }
+        Response::Config {
+            torque_limits,
+            gains,
+            velocity_gains,
+            target,
+        } => println!(
+            "{:?} max torque: {}, gains: {}, velocity gains: {}, target: {:?}",
+            side_response.side, torque_limits, gains, velocity_gains, target
+        ),
+        Response::ImuReadings { accel, gyro } => println!(
+            "{:?} accelerometer: {:?}, gyroscope: {:?}",
+            side_response.side, accel, gyro
+        ),
+        Response::Telemetry(telemetry) => println!("{:?} {}", side_response.side, telemetry),
+        Response::Velocity(velocity) => {
+            println!("{:?} velocity {} steps/s", side_response.side, velocity)
+        }
+        Response::Fault(fault) => println!("{:?} fault: {}", side_response.side, fault),
+        Response::LowBattery {
+            battery_voltage,
+            cutoff,
+        } => println!(
+            "{:?} battery {}: {} mV",
+            side_response.side,
+            if cutoff { "critical" } else { "low" },
+            battery_voltage
+        ),
+        Response::BatteryRecovered { battery_voltage } => println!(
+            "{:?} battery recovered: {} mV",
+            side_response.side, battery_voltage
+        ),
+        Response::HallDiagnostics {
+            invalid_states,
+            skipped_sectors,
+        } => println!(
+            "{:?} Hall sensors: {} invalid states, {} skipped sectors",
+            side_response.side, invalid_states, skipped_sectors
+        ),
+        Response::MotorCalibration(Ok(hall_mapping)) => println!(
+            "{:?} calibrated Hall mapping: {}",
+            side_response.side, hall_mapping
+        ),
+        Response::MotorCalibration(Err(e)) => {
+            println!("{:?} calibration failed: {}", side_response.side, e)
+        }
+        Response::MotorTuning(tuning) => {
+            println!("{:?} motor tuning: {}", side_response.side, tuning)
+        }
+        Response::Heartbeat => {}
+        Response::LinkLost => println!("{:?} lost link to other side", side_response.side),
+        Response::LinkRestored => {
+            println!("{:?} link to other side restored", side_response.side)
+        }
+        Response::CommsTimeout => {
+            println!("{:?} timed out waiting for a command", side_response.side)
+        }
+        Response::Dropped(count) => {
+            println!("{:?} dropped {} responses", side_response.side, count)
+        }
     }
 }
@@ -1,17 +1,17 @@
 use crate::config::{get_mqtt_options, MqttConfig};
 use crate::controller::{
-    DEFAULT_MAX_TORQUE, DEFAULT_SCALE, DEFAULT_SPRING_CONSTANT, MAX_MAX_TORQUE, MAX_SCALE,
-    MAX_SPRING_CONSTANT, SPRING_CONSTANT_STEP,
+    DEFAULT_GAINS, DEFAULT_MAX_TORQUE, DEFAULT_SCALE, KP_STEP, MAX_KP, MAX_MAX_TORQUE, MAX_SCALE,
 };
 use eyre::Report;
 use homie_device::{HomieDevice, Node, Property};
 use log::{error, trace};
-use messages::{Side, TorqueLimits};
+use messages::{Fault, Gains, Side, TorqueLimits};
 use tokio::runtime::Runtime;
 
 const HOMIE_PREFIX: &str = "homie";
 const HOMIE_DEVICE_ID: &str = "hoverkite";
 const HOMIE_DEVICE_NAME: &str = "Hoverkite";
+const AXES: [&str; 3] = ["x", "y", "z"];
 
 pub struct Homie {
     homie: Option<HomieDevice>,
@@ -42,13 +42,32 @@ impl Homie {
         self.send_property(node_id(side), "position", position);
     }
 
+    pub fn send_velocity(&self, side: Side, velocity: i32) {
+        self.send_property(node_id(side), "velocity", velocity);
+    }
+
+    pub fn send_fault(&self, side: Side, fault: Option<Fault>) {
+        let fault = fault.map_or_else(|| "none".to_owned(), |fault| fault.to_string());
+        self.send_property(node_id(side), "fault", fault);
+    }
+
+    pub fn send_low_battery(&self, side: Side, low_battery: bool) {
+        self.send_property(node_id(side), "low_battery", low_battery);
+    }
+
     pub fn send_max_torque(&self, max_torque: TorqueLimits) {
         self.send_property("general", "max_torque", max_torque.positive);
         self.send_property("general", "min_torque", max_torque.negative);
     }
 
-    pub fn send_spring_constant(&self, spring_constant: u16) {
-        self.send_property("general", "spring_constant", spring_constant);
+    pub fn send_gains(&self, gains: Gains) {
+        self.send_property("general", "kp", gains.kp);
+        self.send_property("general", "ki", gains.ki);
+        self.send_property("general", "kd", gains.kd);
+    }
+
+    pub fn send_mode(&self, mode: &str) {
+        self.send_property("general", "mode", mode);
     }
 
     pub fn send_scale(&self, scale: f32) {
@@ -60,18 +79,29 @@ impl Homie {
         side: Side,
         battery_voltage: u16,
         backup_battery_voltage: u16,
-        motor_current: u16,
+        motor_current: i32,
     ) {
         let node_id = node_id(side);
         self.send_property(node_id, "battery_voltage", battery_voltage);
         self.send_property(node_id, "backup_battery_voltage", backup_battery_voltage);
-        self.send_property(node_id, "motor_current", motor_current);
+        // Homie expects SI units, but the board reports milliamps.
+        self.send_property(node_id, "motor_current", motor_current as f32 / 1000.0);
     }
 
     pub fn send_charge_state(&self, side: Side, charger_connected: bool) {
         self.send_property(node_id(side), "charger_connected", charger_connected)
     }
 
+    pub fn send_imu_readings(&self, side: Side, accel: [i16; 3], gyro: [i16; 3]) {
+        let node_id = node_id(side);
+        for (axis, value) in AXES.iter().zip(accel) {
+            self.send_property(node_id, &format!("accel_{}", axis), value);
+        }
+        for (axis, value) in AXES.iter().zip(gyro) {
+            self.send_property(node_id, &format!("gyro_{}", axis), value);
+        }
+    }
+
     fn send_property(&self, node_id: &str, property_id: &str, value: impl ToString) {
         if let Some(homie) = &self.homie {
             self.runtime.block_on(async {
@@ -102,10 +132,11 @@ async fn make_homie_device(config: MqttConfig) -> Result<HomieDevice, Report> {
         }
     });
 
-    let motor_properties = vec![
+    let mut motor_properties = vec![
         Property::integer("centre", "Centre", false, true, None, None),
         Property::integer("target", "Target position", false, true, None, None),
         Property::integer("position", "Actual position", false, true, None, None),
+        Property::integer("velocity", "Velocity", false, true, Some("steps/s"), None),
         Property::integer(
             "battery_voltage",
             "Battery voltage",
@@ -122,9 +153,36 @@ async fn make_homie_device(config: MqttConfig) -> Result<HomieDevice, Report> {
             Some("mV"),
             None,
         ),
-        Property::integer("motor_current", "Motor current", false, true, None, None),
+        Property::float(
+            "motor_current",
+            "Motor current",
+            false,
+            true,
+            Some("A"),
+            None,
+        ),
         Property::boolean("charger_connected", "Charger connected", false, true, None),
+        Property::string("fault", "Fault", false, true, None),
+        Property::boolean("low_battery", "Low battery", false, true, None),
     ];
+    for axis in AXES {
+        motor_properties.push(Property::integer(
+            &format!("accel_{}", axis),
+            &format!("Acceleration {}", axis.to_uppercase()),
+            false,
+            true,
+            None,
+            None,
+        ));
+        motor_properties.push(Property::integer(
+            &format!("gyro_{}", axis),
+            &format!("Angular velocity {}", axis.to_uppercase()),
+            false,
+            true,
+            None,
+            None,
+        ));
+    }
     homie
         .add_node(Node {
             id: "left".to_owned(),
@@ -148,13 +206,15 @@ async fn make_homie_device(config: MqttConfig) -> Result<HomieDevice, Report> {
             node_type: "general".to_owned(),
             properties: vec![
                 Property::integer(
-                    "spring_constant",
-                    "Spring constant",
+                    "kp",
+                    "Proportional gain",
                     false,
                     true,
                     None,
-                    Some(SPRING_CONSTANT_STEP.into()..MAX_SPRING_CONSTANT.into()),
+                    Some(KP_STEP.into()..MAX_KP.into()),
                 ),
+                Property::integer("ki", "Integral gain", false, true, None, None),
+                Property::integer("kd", "Derivative gain", false, true, None, None),
                 Property::integer(
                     "min_torque",
                     "Min torque",
@@ -171,6 +231,7 @@ async fn make_homie_device(config: MqttConfig) -> Result<HomieDevice, Report> {
                     None,
                     Some(0..MAX_MAX_TORQUE.into()),
                 ),
+                Property::string("mode", "Stick mode", false, true, None),
                 Property::float(
                     "scale",
                     "Scale",
@@ -182,10 +243,20 @@ async fn make_homie_device(config: MqttConfig) -> Result<HomieDevice, Report> {
             ],
         })
         .await?;
+    homie.publish_value("left", "fault", "none").await?;
+    homie.publish_value("right", "fault", "none").await?;
+    homie.publish_value("left", "low_battery", false).await?;
+    homie.publish_value("right", "low_battery", false).await?;
     homie.publish_value("left", "centre", 0).await?;
     homie.publish_value("right", "centre", 0).await?;
     homie
-        .publish_value("general", "spring_constant", DEFAULT_SPRING_CONSTANT)
+        .publish_value("general", "kp", DEFAULT_GAINS.kp)
+        .await?;
+    homie
+        .publish_value("general", "ki", DEFAULT_GAINS.ki)
+        .await?;
+    homie
+        .publish_value("general", "kd", DEFAULT_GAINS.kd)
         .await?;
     homie
         .publish_value("general", "min_torque", DEFAULT_MAX_TORQUE.negative)
@@ -193,6 +264,7 @@ async fn make_homie_device(config: MqttConfig) -> Result<HomieDevice, Report> {
     homie
         .publish_value("general", "max_torque", DEFAULT_MAX_TORQUE.positive)
         .await?;
+    homie.publish_value("general", "mode", "position").await?;
     homie
         .publish_value("general", "scale", DEFAULT_SCALE)
         .await?;
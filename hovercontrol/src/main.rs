@@ -9,6 +9,7 @@ use eyre::Report;
 use gilrs::Gilrs;
 use log::error;
 use messages::client::Hoverkite;
+use messages::{Commutation, Encoding};
 
 const BAUD_RATE: u32 = 115_200;
 
@@ -34,12 +35,27 @@ fn main() -> Result<(), Report> {
             .map_err(|e| error!("Failed to open left serial port {}: {}", name, e))
             .ok()
     });
-    let hoverkite = Hoverkite::new(right_port, left_port);
+    let mut hoverkite = Hoverkite::new(right_port, left_port);
+    if config.unframed {
+        hoverkite.set_encoding(Encoding::Unframed);
+    }
 
     let gilrs = Gilrs::new().unwrap();
 
     let homie = Homie::connect_and_start(config.mqtt)?;
 
-    let mut controller = Controller::new(hoverkite, gilrs, homie);
+    let mut controller = Controller::new(
+        hoverkite,
+        gilrs,
+        homie,
+        config.imu_interval_ms,
+        config.telemetry_interval_ms,
+        if config.sinusoidal_commutation {
+            Commutation::Sinusoidal
+        } else {
+            Commutation::Block
+        },
+        config.comms_timeout_ms,
+    );
     controller.run()
 }
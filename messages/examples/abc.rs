@@ -146,7 +146,7 @@ fn parse_fraction(s: &str) -> Result<f32, Report> {
 }
 
 fn get_semitone(note: Note, accidental: Option<Accidental>, key_signature: i8) -> i32 {
-    let accidental = accidental.unwrap_or_else(|| match note {
+    let accidental = accidental.unwrap_or(match note {
         Note::B if key_signature >= 7 => Accidental::Sharp,
         Note::E if key_signature >= 6 => Accidental::Sharp,
         Note::A if key_signature >= 5 => Accidental::Sharp,
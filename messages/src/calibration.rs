@@ -0,0 +1,160 @@
+//! The mapping from Hall sensor states to motor sectors, and the result of finding it.
+
+use crate::ProtocolError;
+use core::fmt::{self, Display, Formatter};
+
+/// Which raw Hall sensor state the motor is in for each sector of its electrical rotation, with
+/// sensor A in bit 2, B in bit 1 and C in bit 0.
+///
+/// Driving the phases for a sector with positive power moves the motor towards the next sector.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct HallMapping {
+    states: [u8; 6],
+}
+
+impl Default for HallMapping {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl HallMapping {
+    /// The mapping for the motor wiring of the original boards.
+    pub const DEFAULT: Self = Self {
+        states: [0b001, 0b101, 0b100, 0b110, 0b010, 0b011],
+    };
+
+    /// Returns the mapping with the given state for each sector, or an error if they aren't the six
+    /// valid states in an order the motor could go through them.
+    pub fn new(states: [u8; 6]) -> Result<Self, ProtocolError> {
+        for (i, &state) in states.iter().enumerate() {
+            if state == 0 || state > 6 {
+                return Err(ProtocolError::InvalidByte(state));
+            }
+            // Only one sensor changes between adjacent sectors.
+            if (state ^ states[(i + 1) % 6]).count_ones() != 1 {
+                return Err(ProtocolError::InvalidByte(states[(i + 1) % 6]));
+            }
+        }
+        Ok(Self { states })
+    }
+
+    /// Returns the raw Hall sensor state for each sector.
+    pub fn states(&self) -> [u8; 6] {
+        self.states
+    }
+
+    /// Returns the sector for the given raw Hall sensor state, or `None` if it is invalid.
+    pub fn sector(&self, state: u8) -> Option<u8> {
+        self.states
+            .iter()
+            .position(|&sector_state| sector_state == state)
+            .map(|sector| sector as u8)
+    }
+
+    /// Returns whether this mapping goes through the Hall sensor states in the opposite order to
+    /// the default mapping, so the motor turns the other way for the same power.
+    pub fn is_reversed(&self) -> bool {
+        let default_sector = usize::from(Self::DEFAULT.sector(self.states[0]).unwrap());
+        Self::DEFAULT.states[(default_sector + 1) % 6] != self.states[1]
+    }
+}
+
+impl Display for HallMapping {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (sector, state) in self.states.iter().enumerate() {
+            if sector != 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:03b}", state)?;
+        }
+        if self.is_reversed() {
+            f.write_str(" (reversed)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A reason why motor calibration failed.
+#[derive(displaydoc::Display, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CalibrationError {
+    /// Hall sensors in invalid state `{0:03b}`
+    InvalidState(u8),
+    /// Hall sensors gave different states for the same rotor position
+    Inconsistent,
+    /// Hall sensors didn't go through all six states in order; is the motor free to turn?
+    NotMoving,
+}
+
+impl CalibrationError {
+    /// Encodes the error as two bytes, to be sent in a response.
+    pub fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Self::InvalidState(state) => [b'i', state],
+            Self::Inconsistent => [b'c', 0],
+            Self::NotMoving => [b'm', 0],
+        }
+    }
+
+    /// Decodes an error encoded by `to_bytes`.
+    pub fn from_bytes(bytes: [u8; 2]) -> Result<Self, ProtocolError> {
+        match bytes {
+            [b'i', state] => Ok(Self::InvalidState(state)),
+            [b'c', _] => Ok(Self::Inconsistent),
+            [b'm', _] => Ok(Self::NotMoving),
+            [code, _] => Err(ProtocolError::InvalidByte(code)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(
+            HallMapping::new(HallMapping::DEFAULT.states()),
+            Ok(HallMapping::DEFAULT)
+        );
+        assert!(!HallMapping::DEFAULT.is_reversed());
+    }
+
+    #[test]
+    fn sector() {
+        assert_eq!(HallMapping::DEFAULT.sector(0b001), Some(0));
+        assert_eq!(HallMapping::DEFAULT.sector(0b011), Some(5));
+        assert_eq!(HallMapping::DEFAULT.sector(0b000), None);
+        assert_eq!(HallMapping::DEFAULT.sector(0b111), None);
+    }
+
+    #[test]
+    fn rotated_is_not_reversed() {
+        let mapping = HallMapping::new([0b100, 0b110, 0b010, 0b011, 0b001, 0b101]).unwrap();
+        assert!(!mapping.is_reversed());
+        assert_eq!(mapping.sector(0b001), Some(4));
+    }
+
+    #[test]
+    fn reversed() {
+        let mapping = HallMapping::new([0b001, 0b011, 0b010, 0b110, 0b100, 0b101]).unwrap();
+        assert!(mapping.is_reversed());
+        assert_eq!(mapping.to_string(), "001 011 010 110 100 101 (reversed)");
+    }
+
+    #[test]
+    fn invalid_state() {
+        assert_eq!(
+            HallMapping::new([0b001, 0b101, 0b100, 0b110, 0b111, 0b011]),
+            Err(ProtocolError::InvalidByte(0b111))
+        );
+    }
+
+    #[test]
+    fn out_of_order() {
+        assert_eq!(
+            HallMapping::new([0b001, 0b100, 0b101, 0b110, 0b010, 0b011]),
+            Err(ProtocolError::InvalidByte(0b100))
+        );
+    }
+}
@@ -1,7 +1,11 @@
-use super::{Command, DirectedCommand, Note, Side, SideResponse, TorqueLimits};
-use log::{error, trace};
+use super::{
+    Command, DirectedCommand, Encoding, FirmwareVersion, Gains, LogLevel, MotorTuning, Note,
+    ProtocolVersion, Response, Side, SideResponse, Target, TorqueLimits,
+};
+use log::{error, info, trace, warn};
 use serialport::SerialPort;
 use slice_deque::SliceDeque;
+use std::collections::VecDeque;
 use std::io;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
@@ -10,6 +14,29 @@ use std::time::{Duration, Instant};
 /// avoid overwhelming it or overflowing its receive buffer.
 pub const MIN_TIME_BETWEEN_TARGET_UPDATES: Duration = Duration::from_millis(100);
 const NOTE_SEND_SLEEP_DURATION: Duration = Duration::from_millis(50);
+/// How long to wait for both sides to report their version during the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait for a command to be acknowledged before sending it again.
+const ACK_TIMEOUT: Duration = Duration::from_millis(200);
+/// How many times to send a command before giving up on it being acknowledged.
+const MAX_SEND_ATTEMPTS: usize = 3;
+/// How long to sleep between polling the serial ports while waiting for a particular response.
+const RESPONSE_POLL_INTERVAL: Duration = Duration::from_millis(2);
+/// How many keepalives to send within each comms timeout, so that one being lost or delayed
+/// doesn't cause a timeout.
+const KEEPALIVES_PER_TIMEOUT: u32 = 3;
+
+/// The control parameters which one side of the device is currently using.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ControllerConfig {
+    pub torque_limits: TorqueLimits,
+    /// The position controller gains.
+    pub gains: Gains,
+    /// The velocity controller gains.
+    pub velocity_gains: Gains,
+    /// What the motor is being asked to do, or `None` if the motor is off.
+    pub target: Option<Target>,
+}
 
 /// A client to talk to a Hoverkite device over one or two serial ports.
 pub struct Hoverkite {
@@ -19,12 +46,26 @@ pub struct Hoverkite {
     right_last_command_time: Instant,
     /// The time that the last command was sent to the left port.
     left_last_command_time: Instant,
-    /// A pending target command that still needs to be sent but wasn't because of the minimum time
-    /// between updates.
-    right_target_pending: Option<i64>,
-    left_target_pending: Option<i64>,
+    /// A pending target position, velocity or torque command that still needs to be sent but
+    /// wasn't because of the minimum time between updates.
+    right_setpoint_pending: Option<Command>,
+    left_setpoint_pending: Option<Command>,
     right_buffer: SliceDeque<u8>,
     left_buffer: SliceDeque<u8>,
+    /// How to encode commands and decode responses.
+    encoding: Encoding,
+    /// The sequence number to use for the next command which should be acknowledged.
+    next_sequence: u8,
+    /// Responses which were received while waiting for some other response, which should be
+    /// returned from the next call to `poll`.
+    pending_responses: VecDeque<SideResponse>,
+    /// How long to go without sending a command to a side before sending it a keepalive, or `None`
+    /// if the comms timeout is disabled.
+    keepalive_interval: Option<Duration>,
+    /// Whether each side answered the handshake. Commands are still sent to a side which didn't,
+    /// but nothing waits for it to answer.
+    left_present: bool,
+    right_present: bool,
 }
 
 impl Hoverkite {
@@ -39,41 +80,241 @@ impl Hoverkite {
             left_port,
             right_last_command_time: Instant::now(),
             left_last_command_time: Instant::now(),
-            right_target_pending: None,
-            left_target_pending: None,
+            right_setpoint_pending: None,
+            left_setpoint_pending: None,
             right_buffer: SliceDeque::new(),
             left_buffer: SliceDeque::new(),
+            encoding: Encoding::Framed,
+            next_sequence: 0,
+            pending_responses: VecDeque::new(),
+            keepalive_interval: None,
+            left_present: true,
+            right_present: true,
+        }
+    }
+
+    /// Sets how to encode commands and decode responses. The default is framed.
+    ///
+    /// Every firmware which answers the handshake understands framed commands, and switches to
+    /// framed responses as soon as it receives one, after which it ignores unframed commands. The
+    /// unframed encoding is only useful for talking to a board which has just started.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Asks both sides for their firmware version, and checks that they both support the latest
+    /// protocol version, as this client only understands the latest message layouts.
+    ///
+    /// A side which doesn't respond in time is treated as absent from then on, so that a single
+    /// board can be used on its own. Returns an error if either side runs firmware whose protocol
+    /// this client doesn't understand. Any other responses received in the meantime will be
+    /// returned by the next call to `poll`.
+    pub fn handshake(&mut self) -> Result<(), io::Error> {
+        self.send_command(Side::Left, Command::GetVersion)?;
+        self.send_command(Side::Right, Command::GetVersion)?;
+
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let mut left_version = None;
+        let mut right_version = None;
+        while (left_version.is_none() || right_version.is_none()) && Instant::now() <= deadline {
+            for response in self.read_responses()? {
+                match response.response {
+                    Response::Version(version) => {
+                        info!("{:?}: {}", response.side, version);
+                        match response.side {
+                            Side::Left => left_version = Some(version),
+                            Side::Right => right_version = Some(version),
+                        }
+                    }
+                    _ => self.pending_responses.push_back(response),
+                }
+            }
+            sleep(RESPONSE_POLL_INTERVAL);
+        }
+        self.left_present = left_version.is_some();
+        self.right_present = right_version.is_some();
+
+        for (side, version) in [(Side::Left, &left_version), (Side::Right, &right_version)] {
+            match version {
+                Some(version) => self.check_version(side, version)?,
+                None => warn!(
+                    "{:?} side didn't report its firmware version, treating it as absent",
+                    side
+                ),
+            }
+        }
+        if let (Some(left_version), Some(right_version)) = (left_version, right_version) {
+            if left_version.crate_version != right_version.crate_version
+                || left_version.git_hash != right_version.git_hash
+            {
+                warn!(
+                    "Sides are running different firmware: left {} ({}), right {} ({})",
+                    left_version.crate_version,
+                    left_version.git_hash,
+                    right_version.crate_version,
+                    right_version.git_hash
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the given side answered the handshake, or true if there hasn't been one.
+    pub fn is_present(&self, side: Side) -> bool {
+        match side {
+            Side::Left => self.left_present,
+            Side::Right => self.right_present,
+        }
+    }
+
+    fn check_version(&self, side: Side, version: &FirmwareVersion) -> Result<(), io::Error> {
+        match ProtocolVersion::parse(version.protocol_version) {
+            Ok(protocol_version) if protocol_version >= ProtocolVersion::LATEST => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} side speaks protocol version {}, but we need {}",
+                    side,
+                    version.protocol_version,
+                    ProtocolVersion::LATEST.to_byte()
+                ),
+            )),
+        }
+    }
+
+    /// Asks the given side for the control parameters it is currently using, and waits for the
+    /// answer.
+    ///
+    /// The request is sent again if no answer arrives in time. Any other responses received in the
+    /// meantime will be returned by the next call to `poll`.
+    pub fn get_config(&mut self, side: Side) -> Result<ControllerConfig, io::Error> {
+        for attempt in 1..=MAX_SEND_ATTEMPTS {
+            self.send_command(side, Command::GetConfig)?;
+
+            let deadline = Instant::now() + ACK_TIMEOUT;
+            while Instant::now() < deadline {
+                let mut config = None;
+                for response in self.read_responses()? {
+                    match response.response {
+                        Response::Config {
+                            torque_limits,
+                            gains,
+                            velocity_gains,
+                            target,
+                        } if response.side == side && config.is_none() => {
+                            config = Some(ControllerConfig {
+                                torque_limits,
+                                gains,
+                                velocity_gains,
+                                target,
+                            })
+                        }
+                        _ => self.pending_responses.push_back(response),
+                    }
+                }
+                if let Some(config) = config {
+                    return Ok(config);
+                }
+                sleep(RESPONSE_POLL_INTERVAL);
+            }
+            warn!(
+                "{:?} side didn't report its config (attempt {}/{})",
+                side, attempt, MAX_SEND_ATTEMPTS
+            );
         }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{:?} side never reported its config", side),
+        ))
     }
 
     /// Sends any pending target commands, reads from both serial ports, and returns any available
     /// responses.
+    ///
+    /// If the comms timeout is enabled then this also sends a keepalive to any side which hasn't
+    /// had a command for a while, so it should be called often enough to keep the device running.
     pub fn poll(&mut self) -> Result<Vec<SideResponse>, io::Error> {
-        self.send_pending_targets()?;
+        self.send_pending_setpoints()?;
+        self.send_keepalives()?;
 
+        let mut responses: Vec<_> = self.pending_responses.drain(..).collect();
+        responses.extend(self.read_responses()?);
+        Ok(responses)
+    }
+
+    /// Reads from both serial ports, and returns any available responses.
+    fn read_responses(&mut self) -> Result<Vec<SideResponse>, io::Error> {
         let mut responses = vec![];
         if let Some(port) = &mut self.left_port {
-            responses.extend(read_port(port, &mut self.left_buffer)?);
+            responses.extend(read_port(port, &mut self.left_buffer, self.encoding)?);
         }
         if let Some(port) = &mut self.right_port {
-            responses.extend(read_port(port, &mut self.right_buffer)?);
+            responses.extend(read_port(port, &mut self.right_buffer, self.encoding)?);
         }
 
         Ok(responses)
     }
 
-    fn send_pending_targets(&mut self) -> Result<(), io::Error> {
-        if let Some(target_pending) = self.left_target_pending {
+    fn send_pending_setpoints(&mut self) -> Result<(), io::Error> {
+        if let Some(setpoint_pending) = self.left_setpoint_pending {
             // Just retry. If the rate limit is still in effect then this will be a no-op.
-            self.set_target(Side::Left, target_pending)?;
+            self.send_setpoint(Side::Left, setpoint_pending)?;
         }
-        if let Some(target_pending) = self.right_target_pending {
-            self.set_target(Side::Right, target_pending)?;
+        if let Some(setpoint_pending) = self.right_setpoint_pending {
+            self.send_setpoint(Side::Right, setpoint_pending)?;
+        }
+        Ok(())
+    }
+
+    fn send_keepalives(&mut self) -> Result<(), io::Error> {
+        if let Some(keepalive_interval) = self.keepalive_interval {
+            let now = Instant::now();
+            if now >= self.left_last_command_time + keepalive_interval {
+                self.send_command(Side::Left, Command::KeepAlive)?;
+            }
+            if now >= self.right_last_command_time + keepalive_interval {
+                self.send_command(Side::Right, Command::KeepAlive)?;
+            }
         }
         Ok(())
     }
 
-    /// Sets the maximum torque on both sides.
+    /// Sets both sides to stop chasing their targets if they don't receive a command for the given
+    /// number of milliseconds, and to apply the given torque instead, or remove the target if it is
+    /// 0. A timeout of 0 disables this.
+    ///
+    /// While the timeout is enabled, `poll` sends keepalives automatically.
+    pub fn set_comms_timeout(
+        &mut self,
+        timeout_ms: u16,
+        safe_torque: i16,
+    ) -> Result<(), io::Error> {
+        println!(
+            "Comms timeout: {} ms, safe torque {}",
+            timeout_ms, safe_torque
+        );
+        let command = Command::SetCommsTimeout {
+            timeout_ms,
+            safe_torque,
+        };
+        self.send_command_confirmed_to_present(command)?;
+        self.keepalive_interval = if timeout_ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(timeout_ms.into()) / KEEPALIVES_PER_TIMEOUT)
+        };
+        Ok(())
+    }
+
+    /// Sets both sides to only send log messages at the given level or more severe.
+    pub fn set_log_level(&mut self, level: LogLevel) -> Result<(), io::Error> {
+        let command = Command::SetLogLevel(level);
+        self.send_command_confirmed_to_present(command)?;
+        Ok(())
+    }
+
+    /// Sets the maximum torque on the given side.
     pub fn set_max_torque(
         &mut self,
         side: Side,
@@ -81,16 +322,39 @@ impl Hoverkite {
     ) -> Result<(), io::Error> {
         println!("{:?} max torque: {}", side, max_torque);
         let command = Command::SetMaxTorque(max_torque);
-        self.send_command(side, command)?;
+        self.send_command_confirmed(side, command)?;
+        Ok(())
+    }
+
+    /// Sets the position controller gains to the given values on both sides.
+    pub fn set_gains(&mut self, gains: Gains) -> Result<(), io::Error> {
+        println!("Gains: {}", gains);
+        let command = Command::SetGains {
+            kp: gains.kp,
+            ki: gains.ki,
+            kd: gains.kd,
+        };
+        self.send_command_confirmed_to_present(command)?;
         Ok(())
     }
 
-    /// Sets the spring constant to the given value on both sides.
-    pub fn set_spring_constant(&mut self, spring_constant: u16) -> Result<(), io::Error> {
-        println!("Spring constant: {}", spring_constant);
-        let command = Command::SetSpringConstant(spring_constant);
-        self.send_command(Side::Left, command)?;
-        self.send_command(Side::Right, command)?;
+    /// Sets the velocity controller gains to the given values on both sides.
+    pub fn set_velocity_gains(&mut self, gains: Gains) -> Result<(), io::Error> {
+        println!("Velocity gains: {}", gains);
+        let command = Command::SetVelocityGains {
+            kp: gains.kp,
+            ki: gains.ki,
+            kd: gains.kd,
+        };
+        self.send_command_confirmed_to_present(command)?;
+        Ok(())
+    }
+
+    /// Sets how the motor power is turned into PWM output on both sides.
+    pub fn set_motor_tuning(&mut self, tuning: MotorTuning) -> Result<(), io::Error> {
+        println!("Motor tuning: {}", tuning);
+        let command = Command::SetMotorTuning(tuning);
+        self.send_command_confirmed_to_present(command)?;
         Ok(())
     }
 
@@ -113,32 +377,142 @@ impl Hoverkite {
     /// These commands are automatically rate-limited, to avoid overflowing the hoverboard's receive
     /// buffer.
     pub fn set_target(&mut self, side: Side, target: i64) -> Result<(), io::Error> {
+        self.send_setpoint(side, Command::SetTarget(target))
+    }
+
+    /// Sets the target velocity for the given side, in steps per second.
+    ///
+    /// Like `set_target`, this is rate limited, and replaces any target position, velocity or
+    /// torque which is still waiting to be sent.
+    pub fn set_velocity(&mut self, side: Side, velocity: i32) -> Result<(), io::Error> {
+        self.send_setpoint(side, Command::SetVelocity(velocity))
+    }
+
+    /// Sets the torque to apply on the given side, without any feedback.
+    ///
+    /// Like `set_target`, this is rate limited, and replaces any target position, velocity or
+    /// torque which is still waiting to be sent.
+    pub fn set_torque(&mut self, side: Side, torque: i16) -> Result<(), io::Error> {
+        self.send_setpoint(side, Command::SetTorque(torque))
+    }
+
+    /// Sends the given target position, velocity or torque command, or saves it to send later if a
+    /// command was sent to the same side too recently.
+    fn send_setpoint(&mut self, side: Side, command: Command) -> Result<(), io::Error> {
         let now = Instant::now();
         match side {
             Side::Left => {
                 if now < self.left_last_command_time + MIN_TIME_BETWEEN_TARGET_UPDATES {
-                    self.left_target_pending = Some(target);
+                    self.left_setpoint_pending = Some(command);
                     return Ok(());
                 } else {
-                    self.left_target_pending = None;
+                    self.left_setpoint_pending = None;
                 }
             }
             Side::Right => {
                 if now < self.right_last_command_time + MIN_TIME_BETWEEN_TARGET_UPDATES {
-                    self.right_target_pending = Some(target);
+                    self.right_setpoint_pending = Some(command);
                     return Ok(());
                 } else {
-                    self.right_target_pending = None;
+                    self.right_setpoint_pending = None;
                 }
             }
         };
-        println!("Target {:?} {}", side, target);
-        self.send_command(side, Command::SetTarget(target))
+        match command {
+            Command::SetTarget(target) => println!("Target {:?} {}", side, target),
+            Command::SetVelocity(velocity) => println!("Velocity {:?} {}", side, velocity),
+            Command::SetTorque(torque) => println!("Torque {:?} {}", side, torque),
+            _ => {}
+        }
+        self.send_command(side, command)
     }
 
     /// Sends the given command to the given side.
     pub fn send_command(&mut self, side: Side, command: Command) -> Result<(), io::Error> {
-        trace!("Sending command to {:?}: {:?}", side, command);
+        self.send_directed_command(DirectedCommand {
+            side,
+            command,
+            sequence: None,
+        })
+    }
+
+    /// Sends the given command to the given side, and waits for the board to acknowledge it.
+    ///
+    /// If no acknowledgement arrives in time then the command is sent again, so it may be run more
+    /// than once if only the acknowledgement was lost. Returns an error if the board rejects the
+    /// command or never acknowledges it. Any other responses received in the meantime will be
+    /// returned by the next call to `poll`.
+    ///
+    /// Returns a `NotConnected` error without sending anything if the side didn't answer the
+    /// handshake.
+    pub fn send_command_confirmed(
+        &mut self,
+        side: Side,
+        command: Command,
+    ) -> Result<(), io::Error> {
+        if !self.is_present(side) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("{:?} side is absent, so can't confirm {:?}", side, command),
+            ));
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        for attempt in 1..=MAX_SEND_ATTEMPTS {
+            self.send_directed_command(DirectedCommand {
+                side,
+                command,
+                sequence: Some(sequence),
+            })?;
+
+            let deadline = Instant::now() + ACK_TIMEOUT;
+            while Instant::now() < deadline {
+                let mut result = None;
+                for response in self.read_responses()? {
+                    match response.response {
+                        Response::Ack(acked) if acked == sequence => result = Some(Ok(())),
+                        Response::Nack(rejected, e) if rejected == sequence => {
+                            result = Some(Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("{:?} side rejected {:?}: {}", response.side, command, e),
+                            )))
+                        }
+                        _ => self.pending_responses.push_back(response),
+                    }
+                }
+                if let Some(result) = result {
+                    return result;
+                }
+                sleep(RESPONSE_POLL_INTERVAL);
+            }
+            warn!(
+                "{:?} side didn't acknowledge {:?} (attempt {}/{})",
+                side, command, attempt, MAX_SEND_ATTEMPTS
+            );
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{:?} side never acknowledged {:?}", side, command),
+        ))
+    }
+
+    /// Sends the given command to each side which answered the handshake, and waits for each to
+    /// acknowledge it, as for `send_command_confirmed`.
+    pub fn send_command_confirmed_to_present(&mut self, command: Command) -> Result<(), io::Error> {
+        for side in [Side::Left, Side::Right] {
+            if self.is_present(side) {
+                self.send_command_confirmed(side, command)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_directed_command(&mut self, side_command: DirectedCommand) -> Result<(), io::Error> {
+        let side = side_command.side;
+        trace!("Sending command to {:?}: {:?}", side, side_command);
         match side {
             Side::Left => {
                 self.left_last_command_time = Instant::now();
@@ -147,7 +521,6 @@ impl Hoverkite {
                 self.right_last_command_time = Instant::now();
             }
         };
-        let side_command = DirectedCommand { side, command };
         let port = match (side, self.left_port.as_mut(), self.right_port.as_mut()) {
             (Side::Left, Some(port), _) => port,
             (Side::Left, None, Some(port)) => port,
@@ -161,7 +534,7 @@ impl Hoverkite {
                 return Ok(());
             }
         };
-        side_command.write_to_std(port)?;
+        side_command.write_with_encoding_to_std(self.encoding, port)?;
         Ok(())
     }
 }
@@ -169,6 +542,7 @@ impl Hoverkite {
 fn read_port(
     port: &mut Box<dyn SerialPort>,
     buffer: &mut SliceDeque<u8>,
+    encoding: Encoding,
 ) -> Result<Option<SideResponse>, io::Error> {
     if port.bytes_to_read()? > 0 {
         let mut temp = [0; 100];
@@ -176,7 +550,7 @@ fn read_port(
         buffer.extend(&temp[0..bytes_read]);
     }
 
-    match SideResponse::parse(buffer) {
+    match SideResponse::parse_with_encoding(encoding, buffer) {
         Ok((response, len)) => {
             buffer.drain(..len);
             return Ok(Some(response));
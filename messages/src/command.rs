@@ -1,5 +1,6 @@
+use crate::frame::{self, write_framed, Encoding, WriteTo};
 use crate::util::{ascii_to_bool, bool_to_ascii};
-use crate::{ProtocolError, Side};
+use crate::{Commutation, LogLevel, ProtocolError, Role, Side, TelemetryFields};
 use core::convert::TryInto;
 use core::fmt::{self, Display, Formatter};
 use core::mem::size_of;
@@ -57,6 +58,78 @@ impl From<TorqueLimits> for RangeInclusive<i16> {
     }
 }
 
+/// Gains for the position controller, in hundredths.
+///
+/// The proportional gain is in units of torque per step of position error, the integral gain in
+/// torque per step of error per second, and the derivative gain in torque per step per second of
+/// velocity.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Gains {
+    pub kp: u16,
+    pub ki: u16,
+    pub kd: u16,
+}
+
+impl Display for Gains {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "kp {}, ki {}, kd {}", self.kp, self.ki, self.kd)
+    }
+}
+
+/// Settings for how the motor power is turned into PWM output.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MotorTuning {
+    /// The number of PWM cycles to wait before each step when ramping the motor power up, or 0 to
+    /// step it every cycle.
+    pub ramp_cycles_per_step: u16,
+    /// Whether to also wait when ramping the motor power down, rather than stepping it every cycle.
+    pub ramp_down: bool,
+    /// The motor power below which the motor isn't driven at all.
+    pub dead_zone: u16,
+    /// The PWM frequency in Hertz.
+    pub pwm_frequency_hz: u32,
+}
+
+impl MotorTuning {
+    /// The number of bytes used to encode the tuning in a command or response.
+    pub(crate) const LENGTH: usize = 9;
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+        bytes[..2].copy_from_slice(&self.ramp_cycles_per_step.to_le_bytes());
+        bytes[2] = bool_to_ascii(self.ramp_down);
+        bytes[3..5].copy_from_slice(&self.dead_zone.to_le_bytes());
+        bytes[5..].copy_from_slice(&self.pwm_frequency_hz.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8; Self::LENGTH]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            ramp_cycles_per_step: u16::from_le_bytes(bytes[..2].try_into().unwrap()),
+            ramp_down: ascii_to_bool(bytes[2])?,
+            dead_zone: u16::from_le_bytes(bytes[3..5].try_into().unwrap()),
+            pwm_frequency_hz: u32::from_le_bytes(bytes[5..].try_into().unwrap()),
+        })
+    }
+}
+
+impl Display for MotorTuning {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ramp {} cycles/step {}, dead zone {}, PWM {} Hz",
+            self.ramp_cycles_per_step,
+            if self.ramp_down {
+                "both ways"
+            } else {
+                "up only"
+            },
+            self.dead_zone,
+            self.pwm_frequency_hz
+        )
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Command {
     SetSideLed(bool),
@@ -67,6 +140,8 @@ pub enum Command {
     ReportBattery,
     ReportCharger,
     SetMaxTorque(TorqueLimits),
+    /// Set the proportional gain of the position controller, in units of torque per step. This is
+    /// superseded by `SetGains`, and leaves the other gains unchanged.
     SetSpringConstant(u16),
     SetTarget(i64),
     RemoveTarget,
@@ -75,6 +150,81 @@ pub enum Command {
     DecrementTarget,
     PowerOff,
     TestMotor,
+    GetVersion,
+    GetConfig,
+    ReportImu,
+    /// Report IMU readings periodically, at the given interval in milliseconds. 0 stops reporting.
+    StreamImu(u16),
+    /// Send the given telemetry fields periodically, at the given interval in milliseconds. 0 stops
+    /// telemetry, and position is then sent whenever it changes instead.
+    SetTelemetry {
+        interval_ms: u16,
+        fields: TelemetryFields,
+    },
+    /// Set the maximum motor current in milliamps, above which the motor power will be reduced.
+    SetCurrentLimit(u16),
+    /// Clear any latched fault, allowing the motor to run again.
+    ClearFault,
+    /// Set the battery voltages in millivolts below which to warn, and to power off. 0 disables
+    /// the corresponding check.
+    SetBatteryThresholds {
+        warning_mv: u16,
+        cutoff_mv: u16,
+    },
+    /// Set the gains of the position controller, in hundredths. See `Gains` for the units.
+    SetGains {
+        kp: u16,
+        ki: u16,
+        kd: u16,
+    },
+    /// Set the gains of the velocity controller, in hundredths. The derivative gain is unused, as
+    /// the velocity estimate is too noisy to differentiate.
+    SetVelocityGains {
+        kp: u16,
+        ki: u16,
+        kd: u16,
+    },
+    /// Turn the motor at the given velocity in steps per second, until a target position is set or
+    /// removed.
+    SetVelocity(i32),
+    /// Apply the given torque to the motor, within the torque limits, until a target position or
+    /// velocity is set or the target is removed.
+    SetTorque(i16),
+    /// Choose how the motor phases are driven.
+    SetCommutation(Commutation),
+    /// Report how many Hall sensor errors there have been.
+    ReportHallDiagnostics,
+    /// Step the motor slowly through a couple of turns open-loop to find which Hall sensor state
+    /// goes with each sector, and use that from then on.
+    CalibrateMotor,
+    /// Change how the motor power is turned into PWM output.
+    SetMotorTuning(MotorTuning),
+    /// Report the motor tuning currently in use.
+    GetMotorTuning,
+    /// Save the control parameters, motor tuning and Hall sensor mapping to flash, to be used
+    /// again after powering on.
+    SaveConfig,
+    /// Load the configuration last saved to flash, and use it.
+    LoadConfig,
+    /// Erase the configuration saved in flash, and go back to the defaults.
+    FactoryReset,
+    /// Set the role to save with the configuration, which takes effect the next time the board
+    /// starts, or clear it so that the board works out its role again then.
+    SetRole(Option<Role>),
+    /// Sent periodically by the primary to the secondary, to show that the link between them is
+    /// working.
+    Heartbeat,
+    /// Stop chasing the target if no command arrives from the host for the given number of
+    /// milliseconds, and instead ramp down to the given torque, or to 0 and then remove the target
+    /// if it is 0. A timeout of 0 disables this.
+    SetCommsTimeout {
+        timeout_ms: u16,
+        safe_torque: i16,
+    },
+    /// Does nothing, but counts as a command for the comms timeout.
+    KeepAlive,
+    /// Only send log messages at the given level or more severe.
+    SetLogLevel(LogLevel),
 }
 
 impl Command {
@@ -119,13 +269,88 @@ impl Command {
             Self::DecrementTarget => writer.write_all(b"-")?,
             Self::PowerOff => writer.write_all(b"p")?,
             Self::TestMotor => writer.write_all(b"t")?,
+            Self::GetVersion => writer.write_all(b"v")?,
+            Self::GetConfig => writer.write_all(b"G")?,
+            Self::ReportImu => writer.write_all(b"i")?,
+            Self::StreamImu(interval_ms) => {
+                writer.write_all(b"I")?;
+                writer.write_all(&interval_ms.to_le_bytes())?;
+            }
+            Self::SetTelemetry {
+                interval_ms,
+                fields,
+            } => {
+                writer.write_all(b"Y")?;
+                writer.write_all(&interval_ms.to_le_bytes())?;
+                writer.write_all(&[fields.bits()])?;
+            }
+            Self::SetCurrentLimit(current_limit_ma) => {
+                writer.write_all(b"a")?;
+                writer.write_all(&current_limit_ma.to_le_bytes())?;
+            }
+            Self::ClearFault => writer.write_all(b"x")?,
+            Self::SetBatteryThresholds {
+                warning_mv,
+                cutoff_mv,
+            } => {
+                writer.write_all(b"u")?;
+                writer.write_all(&warning_mv.to_le_bytes())?;
+                writer.write_all(&cutoff_mv.to_le_bytes())?;
+            }
+            Self::SetGains { kp, ki, kd } => {
+                writer.write_all(b"P")?;
+                writer.write_all(&kp.to_le_bytes())?;
+                writer.write_all(&ki.to_le_bytes())?;
+                writer.write_all(&kd.to_le_bytes())?;
+            }
+            Self::SetVelocityGains { kp, ki, kd } => {
+                writer.write_all(b"E")?;
+                writer.write_all(&kp.to_le_bytes())?;
+                writer.write_all(&ki.to_le_bytes())?;
+                writer.write_all(&kd.to_le_bytes())?;
+            }
+            Self::SetVelocity(velocity) => {
+                writer.write_all(b"V")?;
+                writer.write_all(&velocity.to_le_bytes())?;
+            }
+            Self::SetTorque(torque) => {
+                writer.write_all(b"Q")?;
+                writer.write_all(&torque.to_le_bytes())?;
+            }
+            Self::SetCommutation(commutation) => {
+                writer.write_all(&[b'm', commutation.to_byte()])?
+            }
+            Self::ReportHallDiagnostics => writer.write_all(b"h")?,
+            Self::CalibrateMotor => writer.write_all(b"k")?,
+            Self::SetMotorTuning(tuning) => {
+                writer.write_all(b"w")?;
+                writer.write_all(&tuning.to_bytes())?;
+            }
+            Self::GetMotorTuning => writer.write_all(b"W")?,
+            Self::SaveConfig => writer.write_all(b"s")?,
+            Self::LoadConfig => writer.write_all(b"z")?,
+            Self::FactoryReset => writer.write_all(b"Z")?,
+            Self::SetRole(role) => writer.write_all(&[b'A', role.map_or(b'n', Role::to_byte)])?,
+            Self::Heartbeat => writer.write_all(b"j")?,
+            Self::SetCommsTimeout {
+                timeout_ms,
+                safe_torque,
+            } => {
+                writer.write_all(b"d")?;
+                writer.write_all(&timeout_ms.to_le_bytes())?;
+                writer.write_all(&safe_torque.to_le_bytes())?;
+            }
+            Self::KeepAlive => writer.write_all(b"y")?,
+            Self::SetLogLevel(level) => writer.write_all(&[b'q', level.to_byte()])?,
         };
         Ok(())
     }
 
     pub fn parse(buf: &[u8]) -> nb::Result<Self, ProtocolError> {
         let command = match *buf {
-            [] | [b'l'] | [b'o'] | [b'r'] | [b'g'] => return Err(WouldBlock),
+            [] | [b'l'] | [b'o'] | [b'r'] | [b'g'] | [b'm'] | [b'q'] | [b'A'] => {
+                return Err(WouldBlock)
+            }
             [b'l', on] => Self::SetSideLed(ascii_to_bool(on)?),
             [b'o', on] => Self::SetOrangeLed(ascii_to_bool(on)?),
             [b'r', on] => Self::SetRedLed(ascii_to_bool(on)?),
@@ -183,6 +408,129 @@ impl Command {
             [b'-'] => Self::DecrementTarget,
             [b'p'] => Self::PowerOff,
             [b't'] => Self::TestMotor,
+            [b'v'] => Self::GetVersion,
+            [b'G'] => Self::GetConfig,
+            [b'i'] => Self::ReportImu,
+            [b'I', ref rest @ ..] => {
+                if rest.len() < size_of::<u16>() {
+                    return Err(WouldBlock);
+                }
+                let bytes = rest
+                    .try_into()
+                    .map_err(|_| Other(ProtocolError::MessageTooLong))?;
+                Self::StreamImu(u16::from_le_bytes(bytes))
+            }
+            [b'Y', ref rest @ ..] => {
+                if rest.len() < 3 {
+                    return Err(WouldBlock);
+                }
+                if rest.len() > 3 {
+                    return Err(Other(ProtocolError::MessageTooLong));
+                }
+                Self::SetTelemetry {
+                    interval_ms: u16::from_le_bytes(rest[..2].try_into().unwrap()),
+                    fields: TelemetryFields::from_bits(rest[2])?,
+                }
+            }
+            [b'a', ref rest @ ..] => {
+                if rest.len() < size_of::<u16>() {
+                    return Err(WouldBlock);
+                }
+                let bytes = rest
+                    .try_into()
+                    .map_err(|_| Other(ProtocolError::MessageTooLong))?;
+                Self::SetCurrentLimit(u16::from_le_bytes(bytes))
+            }
+            [b'x'] => Self::ClearFault,
+            [b'h'] => Self::ReportHallDiagnostics,
+            [b'k'] => Self::CalibrateMotor,
+            [b'W'] => Self::GetMotorTuning,
+            [b's'] => Self::SaveConfig,
+            [b'z'] => Self::LoadConfig,
+            [b'Z'] => Self::FactoryReset,
+            [b'j'] => Self::Heartbeat,
+            [b'y'] => Self::KeepAlive,
+            [b'w', ref rest @ ..] => {
+                if rest.len() < MotorTuning::LENGTH {
+                    return Err(WouldBlock);
+                }
+                let bytes = rest
+                    .try_into()
+                    .map_err(|_| Other(ProtocolError::MessageTooLong))?;
+                Self::SetMotorTuning(MotorTuning::from_bytes(bytes)?)
+            }
+            [b'u', ref rest @ ..] => {
+                if rest.len() < 4 {
+                    return Err(WouldBlock);
+                }
+                if rest.len() > 4 {
+                    return Err(Other(ProtocolError::MessageTooLong));
+                }
+                Self::SetBatteryThresholds {
+                    warning_mv: u16::from_le_bytes(rest[..2].try_into().unwrap()),
+                    cutoff_mv: u16::from_le_bytes(rest[2..4].try_into().unwrap()),
+                }
+            }
+            [b'd', ref rest @ ..] => {
+                if rest.len() < 4 {
+                    return Err(WouldBlock);
+                }
+                if rest.len() > 4 {
+                    return Err(Other(ProtocolError::MessageTooLong));
+                }
+                Self::SetCommsTimeout {
+                    timeout_ms: u16::from_le_bytes(rest[..2].try_into().unwrap()),
+                    safe_torque: i16::from_le_bytes(rest[2..4].try_into().unwrap()),
+                }
+            }
+            [b'P', ref rest @ ..] => {
+                if rest.len() < 6 {
+                    return Err(WouldBlock);
+                }
+                if rest.len() > 6 {
+                    return Err(Other(ProtocolError::MessageTooLong));
+                }
+                Self::SetGains {
+                    kp: u16::from_le_bytes(rest[..2].try_into().unwrap()),
+                    ki: u16::from_le_bytes(rest[2..4].try_into().unwrap()),
+                    kd: u16::from_le_bytes(rest[4..6].try_into().unwrap()),
+                }
+            }
+            [b'E', ref rest @ ..] => {
+                if rest.len() < 6 {
+                    return Err(WouldBlock);
+                }
+                if rest.len() > 6 {
+                    return Err(Other(ProtocolError::MessageTooLong));
+                }
+                Self::SetVelocityGains {
+                    kp: u16::from_le_bytes(rest[..2].try_into().unwrap()),
+                    ki: u16::from_le_bytes(rest[2..4].try_into().unwrap()),
+                    kd: u16::from_le_bytes(rest[4..6].try_into().unwrap()),
+                }
+            }
+            [b'V', ref rest @ ..] => {
+                if rest.len() < size_of::<i32>() {
+                    return Err(WouldBlock);
+                }
+                let bytes = rest
+                    .try_into()
+                    .map_err(|_| Other(ProtocolError::MessageTooLong))?;
+                Self::SetVelocity(i32::from_le_bytes(bytes))
+            }
+            [b'Q', ref rest @ ..] => {
+                if rest.len() < size_of::<i16>() {
+                    return Err(WouldBlock);
+                }
+                let bytes = rest
+                    .try_into()
+                    .map_err(|_| Other(ProtocolError::MessageTooLong))?;
+                Self::SetTorque(i16::from_le_bytes(bytes))
+            }
+            [b'm', commutation] => Self::SetCommutation(Commutation::parse(commutation)?),
+            [b'q', level] => Self::SetLogLevel(LogLevel::parse(level)?),
+            [b'A', b'n'] => Self::SetRole(None),
+            [b'A', role] => Self::SetRole(Some(Role::parse(role)?)),
             [c] => return Err(Other(ProtocolError::InvalidCommand(c))),
             [..] => return Err(Other(ProtocolError::MessageTooLong)),
         };
@@ -195,17 +543,64 @@ impl Command {
 pub struct DirectedCommand {
     pub side: Side,
     pub command: Command,
+    /// If this is set then the board will acknowledge the command with the same sequence number
+    /// once it has run it, or reject it if it couldn't be parsed or run.
+    pub sequence: Option<u8>,
 }
 
 impl DirectedCommand {
     pub fn parse(buf: &[u8]) -> nb::Result<Self, ProtocolError> {
-        if let [side, ref rest @ ..] = *buf {
-            Ok(DirectedCommand {
+        match *buf {
+            [] | [b'#'] | [b'#', _] => Err(WouldBlock),
+            [b'#', sequence, side, ref rest @ ..] => Ok(DirectedCommand {
                 side: Side::parse(side)?,
                 command: Command::parse(rest)?,
-            })
+                sequence: Some(sequence),
+            }),
+            [side, ref rest @ ..] => Ok(DirectedCommand {
+                side: Side::parse(side)?,
+                command: Command::parse(rest)?,
+                sequence: None,
+            }),
+        }
+    }
+
+    /// Gets the sequence number of the command at the start of the given buffer, if it has one.
+    ///
+    /// This is useful to reject a command which couldn't be parsed.
+    pub fn parse_sequence(encoding: Encoding, buf: &[u8]) -> Option<u8> {
+        let payload = if encoding == Encoding::Framed {
+            frame::parse(buf).ok()?.0
         } else {
-            Err(WouldBlock)
+            buf
+        };
+        match *payload {
+            [b'#', sequence, ..] => Some(sequence),
+            _ => None,
+        }
+    }
+
+    /// Parses a command in the given encoding from the start of the buffer.
+    ///
+    /// On success returns the command and the number of bytes it used. On error returns the number
+    /// of bytes which should be dropped before trying again.
+    pub fn parse_with_encoding(
+        encoding: Encoding,
+        buf: &[u8],
+    ) -> nb::Result<(Self, usize), (ProtocolError, usize)> {
+        if encoding == Encoding::Framed {
+            let (payload, length) = frame::parse(buf)?;
+            match Self::parse(payload) {
+                Ok(command) => Ok((command, length)),
+                Err(WouldBlock) => Err(Other((ProtocolError::MessageTooShort, length))),
+                Err(Other(e)) => Err(Other((e, length))),
+            }
+        } else {
+            match Self::parse(buf) {
+                Ok(command) => Ok((command, buf.len())),
+                Err(WouldBlock) => Err(WouldBlock),
+                Err(Other(e)) => Err(Other((e, buf.len()))),
+            }
         }
     }
 
@@ -213,14 +608,47 @@ impl DirectedCommand {
     where
         W: embedded_io::Write,
     {
+        if let Some(sequence) = self.sequence {
+            writer.write_all(&[b'#', sequence])?;
+        }
         writer.write_all(&[self.side.to_byte()])?;
         self.command.write_to(writer)
     }
 
+    /// Writes the command in the given encoding.
+    pub fn write_with_encoding<W>(&self, encoding: Encoding, writer: &mut W) -> Result<(), W::Error>
+    where
+        W: embedded_io::Write,
+    {
+        if encoding == Encoding::Framed {
+            write_framed(self, writer)
+        } else {
+            self.write_to(writer)
+        }
+    }
+
     #[cfg(feature = "std")]
     pub fn write_to_std(&self, writer: impl std::io::Write) -> std::io::Result<()> {
         self.write_to(&mut embedded_io_adapters::std::FromStd::new(writer))
     }
+
+    #[cfg(feature = "std")]
+    pub fn write_with_encoding_to_std(
+        &self,
+        encoding: Encoding,
+        writer: impl std::io::Write,
+    ) -> std::io::Result<()> {
+        self.write_with_encoding(
+            encoding,
+            &mut embedded_io_adapters::std::FromStd::new(writer),
+        )
+    }
+}
+
+impl WriteTo for DirectedCommand {
+    fn write_to<W: embedded_io::Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        DirectedCommand::write_to(self, writer)
+    }
 }
 
 #[cfg(feature = "std")]
@@ -272,6 +700,22 @@ mod tests {
             command.write_to_std(&mut buf).unwrap();
             assert_eq!(buf, [b'T', 42, 0, 0, 0, 0, 0, 0, 0]);
         }
+
+        #[test]
+        fn set_telemetry_unknown_field() {
+            assert_eq!(
+                Command::parse(b"Y\x64\x00\x80"),
+                Err(Other(ProtocolError::InvalidByte(0x80)))
+            );
+        }
+
+        #[test]
+        fn set_role_invalid() {
+            assert_eq!(
+                Command::parse(b"AX"),
+                Err(Other(ProtocolError::InvalidByte(b'X')))
+            );
+        }
     }
 
     mod side_command {
@@ -282,6 +726,7 @@ mod tests {
             let command = DirectedCommand {
                 side: Side::Left,
                 command: Command::PowerOff,
+                sequence: None,
             };
             let mut buf = vec![];
             command.write_to_std(&mut buf).unwrap();
@@ -293,6 +738,7 @@ mod tests {
             let command = DirectedCommand {
                 side: Side::Left,
                 command: Command::PowerOff,
+                sequence: None,
             };
 
             let mut buf = vec![];
@@ -308,6 +754,7 @@ mod tests {
             let command = DirectedCommand {
                 side: Side::Left,
                 command: Command::PowerOff,
+                sequence: None,
             };
 
             let mut buf = vec![];
@@ -324,10 +771,127 @@ mod tests {
         #[test]
         fn parse_error_if_bogus_payload() {
             assert_eq!(
-                DirectedCommand::parse(&[b'R', b'!']),
+                DirectedCommand::parse(b"R!"),
                 Err(Other(ProtocolError::InvalidCommand(b'!')))
             )
         }
+
+        #[test]
+        fn sequence_round_trip() {
+            let command = DirectedCommand {
+                side: Side::Right,
+                command: Command::SetTarget(-42),
+                sequence: Some(7),
+            };
+            let mut buf = vec![];
+            command.write_to_std(&mut buf).unwrap();
+            assert_eq!(buf[..4], [b'#', 7, b'R', b'T']);
+            for prefix_length in 0..buf.len() {
+                assert_eq!(
+                    DirectedCommand::parse(&buf[..prefix_length]),
+                    Err(WouldBlock)
+                );
+            }
+            assert_eq!(DirectedCommand::parse(&buf), Ok(command));
+        }
+
+        #[test]
+        fn parse_sequence() {
+            assert_eq!(
+                DirectedCommand::parse_sequence(Encoding::Unframed, b"#\x05R!"),
+                Some(5)
+            );
+            assert_eq!(
+                DirectedCommand::parse_sequence(Encoding::Unframed, b"R!"),
+                None
+            );
+
+            let command = DirectedCommand {
+                side: Side::Left,
+                command: Command::PowerOff,
+                sequence: Some(9),
+            };
+            let mut buf = vec![];
+            command
+                .write_with_encoding_to_std(Encoding::Framed, &mut buf)
+                .unwrap();
+            assert_eq!(
+                DirectedCommand::parse_sequence(Encoding::Framed, &buf),
+                Some(9)
+            );
+        }
+    }
+
+    mod framed {
+        use super::*;
+        use crate::frame::FRAME_START;
+
+        fn framed(command: Command) -> Vec<u8> {
+            let command = DirectedCommand {
+                side: Side::Right,
+                command,
+                sequence: None,
+            };
+            let mut buf = vec![];
+            command
+                .write_with_encoding_to_std(Encoding::Framed, &mut buf)
+                .unwrap();
+            buf
+        }
+
+        #[test]
+        fn round_trip() {
+            let buf = framed(Command::SetTarget(-42));
+            assert_eq!(buf[0], FRAME_START);
+            assert_eq!(
+                DirectedCommand::parse_with_encoding(Encoding::Framed, &buf),
+                Ok((
+                    DirectedCommand {
+                        side: Side::Right,
+                        command: Command::SetTarget(-42),
+                        sequence: None,
+                    },
+                    buf.len()
+                ))
+            );
+        }
+
+        #[test]
+        fn corrupted_target_rejected() {
+            let mut buf = framed(Command::SetTarget(-42));
+            buf[5] ^= 0x40;
+            assert_eq!(
+                DirectedCommand::parse_with_encoding(Encoding::Framed, &buf),
+                Err(Other((ProtocolError::ChecksumMismatch, 1)))
+            );
+        }
+
+        #[test]
+        fn unframed_rejected() {
+            assert_eq!(
+                DirectedCommand::parse_with_encoding(Encoding::Framed, b"Lp"),
+                Err(Other((ProtocolError::InvalidByte(b'L'), 2)))
+            );
+        }
+
+        #[test]
+        fn unframed_encoding() {
+            assert_eq!(
+                DirectedCommand::parse_with_encoding(Encoding::Unframed, b"Lp"),
+                Ok((
+                    DirectedCommand {
+                        side: Side::Left,
+                        command: Command::PowerOff,
+                        sequence: None,
+                    },
+                    2
+                ))
+            );
+            assert_eq!(
+                DirectedCommand::parse_with_encoding(Encoding::Unframed, b"L"),
+                Err(WouldBlock)
+            );
+        }
     }
 
     // TODO: see if it's possible to verify this round-trip property
@@ -338,6 +902,15 @@ mod tests {
         use test_case::test_case;
         use Command::*;
 
+        fn tuning() -> MotorTuning {
+            MotorTuning {
+                ramp_cycles_per_step: 5,
+                ramp_down: true,
+                dead_zone: 10,
+                pwm_frequency_hz: 16000,
+            }
+        }
+
         #[test_case(SetSideLed(true))]
         #[test_case(SetOrangeLed(false))]
         #[test_case(SetRedLed(true))]
@@ -355,6 +928,32 @@ mod tests {
         #[test_case(DecrementTarget)]
         #[test_case(PowerOff)]
         #[test_case(TestMotor)]
+        #[test_case(GetVersion)]
+        #[test_case(GetConfig)]
+        #[test_case(ReportImu)]
+        #[test_case(StreamImu(100))]
+        #[test_case(SetTelemetry { interval_ms: 50, fields: TelemetryFields::POSITION | TelemetryFields::BATTERY })]
+        #[test_case(SetCurrentLimit(15000))]
+        #[test_case(ClearFault)]
+        #[test_case(SetBatteryThresholds { warning_mv: 33000, cutoff_mv: 30000 })]
+        #[test_case(SetGains { kp: 1000, ki: 50, kd: 20 })]
+        #[test_case(SetVelocityGains { kp: 50, ki: 200, kd: 0 })]
+        #[test_case(SetVelocity(-300))]
+        #[test_case(SetTorque(-150))]
+        #[test_case(SetCommutation(Commutation::Sinusoidal))]
+        #[test_case(ReportHallDiagnostics)]
+        #[test_case(CalibrateMotor)]
+        #[test_case(SetMotorTuning(tuning()))]
+        #[test_case(GetMotorTuning)]
+        #[test_case(SaveConfig)]
+        #[test_case(LoadConfig)]
+        #[test_case(FactoryReset)]
+        #[test_case(SetRole(Some(Role::Secondary)))]
+        #[test_case(SetRole(None))]
+        #[test_case(Heartbeat)]
+        #[test_case(SetCommsTimeout { timeout_ms: 500, safe_torque: -20 })]
+        #[test_case(KeepAlive)]
+        #[test_case(SetLogLevel(LogLevel::Debug))]
         fn would_block_if_missing_byte(command: Command) {
             let mut buf = vec![];
             command.write_to_std(&mut buf).unwrap();
@@ -381,6 +980,32 @@ mod tests {
         #[test_case(DecrementTarget)]
         #[test_case(PowerOff)]
         #[test_case(TestMotor)]
+        #[test_case(GetVersion)]
+        #[test_case(GetConfig)]
+        #[test_case(ReportImu)]
+        #[test_case(StreamImu(100))]
+        #[test_case(SetTelemetry { interval_ms: 50, fields: TelemetryFields::POSITION | TelemetryFields::BATTERY })]
+        #[test_case(SetCurrentLimit(15000))]
+        #[test_case(ClearFault)]
+        #[test_case(SetBatteryThresholds { warning_mv: 33000, cutoff_mv: 30000 })]
+        #[test_case(SetGains { kp: 1000, ki: 50, kd: 20 })]
+        #[test_case(SetVelocityGains { kp: 50, ki: 200, kd: 0 })]
+        #[test_case(SetVelocity(-300))]
+        #[test_case(SetTorque(-150))]
+        #[test_case(SetCommutation(Commutation::Sinusoidal))]
+        #[test_case(ReportHallDiagnostics)]
+        #[test_case(CalibrateMotor)]
+        #[test_case(SetMotorTuning(tuning()))]
+        #[test_case(GetMotorTuning)]
+        #[test_case(SaveConfig)]
+        #[test_case(LoadConfig)]
+        #[test_case(FactoryReset)]
+        #[test_case(SetRole(Some(Role::Secondary)))]
+        #[test_case(SetRole(None))]
+        #[test_case(Heartbeat)]
+        #[test_case(SetCommsTimeout { timeout_ms: 500, safe_torque: -20 })]
+        #[test_case(KeepAlive)]
+        #[test_case(SetLogLevel(LogLevel::Debug))]
         fn parse_error_if_extra_byte(command: Command) {
             let mut buf = vec![];
             command.write_to_std(&mut buf).unwrap();
@@ -410,10 +1035,37 @@ mod tests {
         #[test_case(DecrementTarget)]
         #[test_case(PowerOff)]
         #[test_case(TestMotor)]
+        #[test_case(GetVersion)]
+        #[test_case(GetConfig)]
+        #[test_case(ReportImu)]
+        #[test_case(StreamImu(100))]
+        #[test_case(SetTelemetry { interval_ms: 50, fields: TelemetryFields::POSITION | TelemetryFields::BATTERY })]
+        #[test_case(SetCurrentLimit(15000))]
+        #[test_case(ClearFault)]
+        #[test_case(SetBatteryThresholds { warning_mv: 33000, cutoff_mv: 30000 })]
+        #[test_case(SetGains { kp: 1000, ki: 50, kd: 20 })]
+        #[test_case(SetVelocityGains { kp: 50, ki: 200, kd: 0 })]
+        #[test_case(SetVelocity(-300))]
+        #[test_case(SetTorque(-150))]
+        #[test_case(SetCommutation(Commutation::Sinusoidal))]
+        #[test_case(ReportHallDiagnostics)]
+        #[test_case(CalibrateMotor)]
+        #[test_case(SetMotorTuning(tuning()))]
+        #[test_case(GetMotorTuning)]
+        #[test_case(SaveConfig)]
+        #[test_case(LoadConfig)]
+        #[test_case(FactoryReset)]
+        #[test_case(SetRole(Some(Role::Secondary)))]
+        #[test_case(SetRole(None))]
+        #[test_case(Heartbeat)]
+        #[test_case(SetCommsTimeout { timeout_ms: 500, safe_torque: -20 })]
+        #[test_case(KeepAlive)]
+        #[test_case(SetLogLevel(LogLevel::Debug))]
         fn round_trip_equality(command: Command) {
             let command = DirectedCommand {
                 side: Side::Left,
                 command,
+                sequence: None,
             };
             let mut buf = vec![];
             command.write_to_std(&mut buf).unwrap();
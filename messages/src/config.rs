@@ -0,0 +1,355 @@
+//! The format of the configuration which the firmware keeps in flash, so that it persists across
+//! power cycles.
+//!
+//! The configuration is stored as fixed-length records, each with a sequence number and checksum.
+//! A new record is written after the newest one each time the configuration is saved, rather than
+//! erasing and rewriting the same place every time, to spread the wear over the flash. The region
+//! used should be at least two pages, so that there is always a complete record in one page while
+//! the other is being erased.
+//!
+//! Each record is longer than the fields in it need, so that more can be added later without
+//! changing how records are laid out in flash.
+
+use crate::frame::Crc16;
+use crate::{Commutation, Gains, HallMapping, MotorTuning, ProtocolError, Role, TorqueLimits};
+use core::convert::TryInto;
+
+/// The length in bytes of each record, including the header and checksum.
+pub const RECORD_LENGTH: usize = 64;
+
+/// The byte at the start of every record.
+const RECORD_MAGIC: u8 = b'H';
+
+/// The version of the record format. This should be increased whenever the format changes, so that
+/// records in the old format are ignored.
+const FORMAT_VERSION: u8 = 3;
+
+/// The offset of the checksum at the end of each record.
+const CRC_OFFSET: usize = RECORD_LENGTH - 2;
+
+/// The value of erased flash.
+const ERASED: u8 = 0xff;
+
+/// The configuration which is saved to flash.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StoredConfig {
+    pub torque_limits: TorqueLimits,
+    /// The position controller gains.
+    pub gains: Gains,
+    /// The velocity controller gains.
+    pub velocity_gains: Gains,
+    /// The maximum motor current in milliamps.
+    pub current_limit_ma: u16,
+    /// The battery voltage in millivolts below which to warn.
+    pub battery_warning_mv: u16,
+    /// The battery voltage in millivolts below which to power off.
+    pub battery_cutoff_mv: u16,
+    pub commutation: Commutation,
+    pub motor_tuning: MotorTuning,
+    pub hall_mapping: HallMapping,
+    /// Whether the board is the primary or secondary, or `None` to work it out at boot. This is
+    /// only used at boot, as the role can't change while running.
+    pub role: Option<Role>,
+}
+
+impl StoredConfig {
+    /// Encodes the config as a record with the given sequence number.
+    pub fn to_record(&self, sequence: u16) -> [u8; RECORD_LENGTH] {
+        let mut record = [0; RECORD_LENGTH];
+        record[0] = RECORD_MAGIC;
+        record[1] = FORMAT_VERSION;
+        record[2..4].copy_from_slice(&sequence.to_le_bytes());
+        record[4..6].copy_from_slice(&self.torque_limits.negative.to_le_bytes());
+        record[6..8].copy_from_slice(&self.torque_limits.positive.to_le_bytes());
+        record[8..10].copy_from_slice(&self.gains.kp.to_le_bytes());
+        record[10..12].copy_from_slice(&self.gains.ki.to_le_bytes());
+        record[12..14].copy_from_slice(&self.gains.kd.to_le_bytes());
+        record[14] = self.commutation.to_byte();
+        record[15..24].copy_from_slice(&self.motor_tuning.to_bytes());
+        record[24..30].copy_from_slice(&self.hall_mapping.states());
+        record[30..32].copy_from_slice(&self.velocity_gains.kp.to_le_bytes());
+        record[32..34].copy_from_slice(&self.velocity_gains.ki.to_le_bytes());
+        record[34..36].copy_from_slice(&self.velocity_gains.kd.to_le_bytes());
+        record[36..38].copy_from_slice(&self.current_limit_ma.to_le_bytes());
+        record[38..40].copy_from_slice(&self.battery_warning_mv.to_le_bytes());
+        record[40..42].copy_from_slice(&self.battery_cutoff_mv.to_le_bytes());
+        record[42] = self.role.map_or(0, Role::to_byte);
+        let mut crc = Crc16::new();
+        crc.update(&record[..CRC_OFFSET]);
+        record[CRC_OFFSET..].copy_from_slice(&crc.finish().to_le_bytes());
+        record
+    }
+
+    /// Decodes a record written by `to_record`, returning the config and its sequence number.
+    pub fn from_record(record: &[u8; RECORD_LENGTH]) -> Result<(Self, u16), ProtocolError> {
+        if record[0] != RECORD_MAGIC {
+            return Err(ProtocolError::InvalidByte(record[0]));
+        }
+        if record[1] != FORMAT_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(record[1]));
+        }
+        let mut crc = Crc16::new();
+        crc.update(&record[..CRC_OFFSET]);
+        if crc.finish() != u16::from_le_bytes(record[CRC_OFFSET..].try_into().unwrap()) {
+            return Err(ProtocolError::ChecksumMismatch);
+        }
+
+        let sequence = u16::from_le_bytes(record[2..4].try_into().unwrap());
+        let config = Self {
+            torque_limits: TorqueLimits {
+                negative: i16::from_le_bytes(record[4..6].try_into().unwrap()),
+                positive: i16::from_le_bytes(record[6..8].try_into().unwrap()),
+            },
+            gains: Gains {
+                kp: u16::from_le_bytes(record[8..10].try_into().unwrap()),
+                ki: u16::from_le_bytes(record[10..12].try_into().unwrap()),
+                kd: u16::from_le_bytes(record[12..14].try_into().unwrap()),
+            },
+            velocity_gains: Gains {
+                kp: u16::from_le_bytes(record[30..32].try_into().unwrap()),
+                ki: u16::from_le_bytes(record[32..34].try_into().unwrap()),
+                kd: u16::from_le_bytes(record[34..36].try_into().unwrap()),
+            },
+            current_limit_ma: u16::from_le_bytes(record[36..38].try_into().unwrap()),
+            battery_warning_mv: u16::from_le_bytes(record[38..40].try_into().unwrap()),
+            battery_cutoff_mv: u16::from_le_bytes(record[40..42].try_into().unwrap()),
+            commutation: Commutation::parse(record[14])?,
+            motor_tuning: MotorTuning::from_bytes(record[15..24].try_into().unwrap())?,
+            hall_mapping: HallMapping::new(record[24..30].try_into().unwrap())?,
+            role: match record[42] {
+                0 => None,
+                role => Some(Role::parse(role)?),
+            },
+        };
+        Ok((config, sequence))
+    }
+}
+
+/// Where a record is stored within the region of flash used for config, and its sequence number.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RecordSlot {
+    /// The offset of the record from the start of the region, in bytes.
+    pub offset: usize,
+    pub sequence: u16,
+}
+
+/// Finds the newest valid record in the given region of flash, and returns the config from it along
+/// with where it is.
+pub fn find_latest(region: &[u8]) -> Option<(StoredConfig, RecordSlot)> {
+    let mut latest: Option<(StoredConfig, RecordSlot)> = None;
+    for (index, record) in region.chunks_exact(RECORD_LENGTH).enumerate() {
+        if let Ok((config, sequence)) = StoredConfig::from_record(record.try_into().unwrap()) {
+            // Sequence numbers wrap around, so compare them relative to each other.
+            if latest.is_none_or(|(_, slot)| (sequence.wrapping_sub(slot.sequence) as i16) > 0) {
+                let offset = index * RECORD_LENGTH;
+                latest = Some((config, RecordSlot { offset, sequence }));
+            }
+        }
+    }
+    latest
+}
+
+/// Works out where in the given region of flash to write the next record, and with what sequence
+/// number. Also returns whether the page containing it must be erased first.
+///
+/// The region must be a whole number of pages of the given length, and each page a whole number of
+/// records.
+pub fn next_slot(region: &[u8], page_length: usize) -> (RecordSlot, bool) {
+    let (offset, sequence) = match find_latest(region) {
+        Some((_, latest)) => (
+            (latest.offset + RECORD_LENGTH) % region.len(),
+            latest.sequence.wrapping_add(1),
+        ),
+        None => (0, 0),
+    };
+    let slot_erased = is_erased(&region[offset..offset + RECORD_LENGTH]);
+    if offset % page_length != 0 && slot_erased {
+        return (RecordSlot { offset, sequence }, false);
+    }
+
+    // Either the next slot starts a new page, or something has been partly written to it. In either
+    // case, start at the beginning of the next page, so that the page with the latest record is
+    // never erased.
+    let offset = offset.next_multiple_of(page_length) % region.len();
+    let page_erased = is_erased(&region[offset..offset + page_length]);
+    (RecordSlot { offset, sequence }, !page_erased)
+}
+
+fn is_erased(data: &[u8]) -> bool {
+    data.iter().all(|&byte| byte == ERASED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_LENGTH: usize = 4 * RECORD_LENGTH;
+
+    fn config(kp: u16) -> StoredConfig {
+        StoredConfig {
+            torque_limits: TorqueLimits {
+                negative: -300,
+                positive: 250,
+            },
+            gains: Gains { kp, ki: 5, kd: 20 },
+            velocity_gains: Gains {
+                kp: 60,
+                ki: 150,
+                kd: 0,
+            },
+            current_limit_ma: 12_000,
+            battery_warning_mv: 34_000,
+            battery_cutoff_mv: 31_000,
+            commutation: Commutation::Sinusoidal,
+            motor_tuning: MotorTuning {
+                ramp_cycles_per_step: 3,
+                ramp_down: true,
+                dead_zone: 12,
+                pwm_frequency_hz: 20000,
+            },
+            hall_mapping: HallMapping::new([0b001, 0b011, 0b010, 0b110, 0b100, 0b101]).unwrap(),
+            role: Some(Role::Secondary),
+        }
+    }
+
+    /// Saves the given config to the simulated flash region in the same way as the firmware.
+    fn save(region: &mut [u8], config: &StoredConfig) -> RecordSlot {
+        let (slot, erase) = next_slot(region, PAGE_LENGTH);
+        if erase {
+            let page = slot.offset / PAGE_LENGTH * PAGE_LENGTH;
+            region[page..page + PAGE_LENGTH].fill(ERASED);
+        }
+        region[slot.offset..slot.offset + RECORD_LENGTH]
+            .copy_from_slice(&config.to_record(slot.sequence));
+        slot
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let record = config(1000).to_record(42);
+        assert_eq!(StoredConfig::from_record(&record), Ok((config(1000), 42)));
+    }
+
+    #[test]
+    fn record_without_role() {
+        let config = StoredConfig {
+            role: None,
+            ..config(1000)
+        };
+        let record = config.to_record(42);
+        assert_eq!(StoredConfig::from_record(&record), Ok((config, 42)));
+    }
+
+    #[test]
+    fn corrupt_record() {
+        let mut record = config(1000).to_record(42);
+        record[9] ^= 0x01;
+        assert_eq!(
+            StoredConfig::from_record(&record),
+            Err(ProtocolError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn erased_record() {
+        assert_eq!(
+            StoredConfig::from_record(&[ERASED; RECORD_LENGTH]),
+            Err(ProtocolError::InvalidByte(ERASED))
+        );
+    }
+
+    #[test]
+    fn old_format() {
+        let mut record = config(1000).to_record(42);
+        record[1] = 0;
+        assert_eq!(
+            StoredConfig::from_record(&record),
+            Err(ProtocolError::UnsupportedVersion(0))
+        );
+    }
+
+    #[test]
+    fn empty_region() {
+        let region = [ERASED; 2 * PAGE_LENGTH];
+        assert_eq!(find_latest(&region), None);
+        assert_eq!(
+            next_slot(&region, PAGE_LENGTH),
+            (
+                RecordSlot {
+                    offset: 0,
+                    sequence: 0
+                },
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn garbage_region() {
+        let region = [0; 2 * PAGE_LENGTH];
+        assert_eq!(find_latest(&region), None);
+        assert_eq!(
+            next_slot(&region, PAGE_LENGTH),
+            (
+                RecordSlot {
+                    offset: 0,
+                    sequence: 0
+                },
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn saves_spread_over_region() {
+        let mut region = [ERASED; 2 * PAGE_LENGTH];
+        for kp in 0..20 {
+            let slot = save(&mut region, &config(kp));
+            assert_eq!(slot.offset, usize::from(kp) * RECORD_LENGTH % region.len());
+            assert_eq!(find_latest(&region), Some((config(kp), slot)));
+        }
+    }
+
+    #[test]
+    fn latest_survives_erase() {
+        let mut region = [ERASED; 2 * PAGE_LENGTH];
+        for kp in 0..8 {
+            save(&mut region, &config(kp));
+        }
+        // The next save erases the first page, but the second still has the latest record.
+        let (slot, erase) = next_slot(&region, PAGE_LENGTH);
+        assert_eq!(slot.offset, 0);
+        assert!(erase);
+        region[..PAGE_LENGTH].fill(ERASED);
+        assert_eq!(find_latest(&region).unwrap().0, config(7));
+    }
+
+    #[test]
+    fn skips_partly_written_slot() {
+        let mut region = [ERASED; 2 * PAGE_LENGTH];
+        save(&mut region, &config(1));
+        // Power was lost while writing the next record.
+        region[RECORD_LENGTH..RECORD_LENGTH + 4].fill(0);
+        assert_eq!(find_latest(&region).unwrap().0, config(1));
+        let slot = save(&mut region, &config(2));
+        assert_eq!(slot.offset, PAGE_LENGTH);
+        assert_eq!(find_latest(&region).unwrap().0, config(2));
+    }
+
+    #[test]
+    fn sequence_wraps_around() {
+        let mut region = [ERASED; 2 * PAGE_LENGTH];
+        region[..RECORD_LENGTH].copy_from_slice(&config(1).to_record(u16::MAX));
+        region[RECORD_LENGTH..2 * RECORD_LENGTH].copy_from_slice(&config(2).to_record(0));
+        assert_eq!(
+            find_latest(&region),
+            Some((
+                config(2),
+                RecordSlot {
+                    offset: RECORD_LENGTH,
+                    sequence: 0
+                }
+            ))
+        );
+    }
+}
@@ -4,14 +4,78 @@ use core::str::Utf8Error;
 pub enum ProtocolError {
     /// message too long
     MessageTooLong,
+    /// message too short
+    MessageTooShort,
     /// got an invalid side: `{0}`
     InvalidSide(u8),
     /// got an invalid command: `{0}`
     InvalidCommand(u8),
     /// got an unexpected byte: `{0}`
     InvalidByte(u8),
-    /// invalid UTF8: `{0}`
-    Utf8Error(Utf8Error),
+    /// invalid UTF8
+    InvalidUtf8 {
+        /// The length of the invalid byte sequence, or `None` if the input ended in the middle of
+        /// a character.
+        error_len: Option<u8>,
+    },
+    /// frame checksum mismatch
+    ChecksumMismatch,
+    /// unsupported protocol version: `{0}`
+    UnsupportedVersion(u8),
+    /// link to the other side is lost
+    LinkLost,
+    /// not allowed while the motor is being driven
+    MotorBusy,
+    /// writing to the flash failed
+    FlashFailed,
+}
+
+impl ProtocolError {
+    /// Encodes the error as two bytes, to be sent in a response.
+    pub fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Self::MessageTooLong => [b'L', 0],
+            Self::MessageTooShort => [b'S', 0],
+            Self::InvalidSide(side) => [b's', side],
+            Self::InvalidCommand(command) => [b'c', command],
+            Self::InvalidByte(byte) => [b'b', byte],
+            Self::InvalidUtf8 { error_len } => [b'u', error_len.unwrap_or(0)],
+            Self::ChecksumMismatch => [b'k', 0],
+            Self::UnsupportedVersion(version) => [b'v', version],
+            Self::LinkLost => [b'l', 0],
+            Self::MotorBusy => [b'm', 0],
+            Self::FlashFailed => [b'f', 0],
+        }
+    }
+
+    /// Decodes an error encoded by `to_bytes`.
+    pub fn from_bytes(bytes: [u8; 2]) -> Result<Self, ProtocolError> {
+        match bytes {
+            [b'L', _] => Ok(Self::MessageTooLong),
+            [b'S', _] => Ok(Self::MessageTooShort),
+            [b's', side] => Ok(Self::InvalidSide(side)),
+            [b'c', command] => Ok(Self::InvalidCommand(command)),
+            [b'b', byte] => Ok(Self::InvalidByte(byte)),
+            [b'u', 0] => Ok(Self::InvalidUtf8 { error_len: None }),
+            [b'u', error_len] => Ok(Self::InvalidUtf8 {
+                error_len: Some(error_len),
+            }),
+            [b'k', _] => Ok(Self::ChecksumMismatch),
+            [b'v', version] => Ok(Self::UnsupportedVersion(version)),
+            [b'l', _] => Ok(Self::LinkLost),
+            [b'm', _] => Ok(Self::MotorBusy),
+            [b'f', _] => Ok(Self::FlashFailed),
+            [code, _] => Err(Self::InvalidByte(code)),
+        }
+    }
+}
+
+impl From<Utf8Error> for ProtocolError {
+    fn from(e: Utf8Error) -> Self {
+        Self::InvalidUtf8 {
+            error_len: e.error_len().map(|length| length as u8),
+        }
+    }
 }
 
 #[cfg(feature = "std")]
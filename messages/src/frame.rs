@@ -0,0 +1,333 @@
+//! Framed encoding for commands and responses.
+//!
+//! Each frame consists of the start byte [`FRAME_START`], the payload length as a little-endian
+//! `u16`, the payload itself, and then a CRC-16 of the length and payload, also little-endian. The
+//! payload is the same bytes which would be sent for the message in the unframed encoding.
+
+use crate::ProtocolError;
+use core::convert::{Infallible, TryInto};
+use embedded_io::{ErrorType, Write};
+use nb::Error::{Other, WouldBlock};
+
+/// The byte which marks the start of every frame. This is ASCII STX, which can never be the first
+/// byte of an unframed message.
+pub const FRAME_START: u8 = 0x02;
+
+/// The maximum length of the payload of a frame. This is enough for the longest log message.
+pub const MAX_FRAME_PAYLOAD: usize = 300;
+
+/// The number of bytes in a frame other than the payload.
+const FRAME_OVERHEAD: usize = 5;
+
+/// How commands and responses are encoded on the wire.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Each message is sent as a bare sequence of bytes.
+    Unframed,
+    /// Each message is wrapped in a frame with a start byte, length and CRC-16.
+    Framed,
+}
+
+/// The version of the layouts of commands and responses.
+///
+/// This is bumped whenever the layout of a message changes, whichever encoding it is sent in. Only
+/// the latest layouts are supported, so the firmware must report at least `LATEST`.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ProtocolVersion {
+    /// The original layouts.
+    V1,
+    /// Motor current in battery readings and telemetry is a signed `i32` in milliamps, rather than
+    /// a raw `u16` ADC reading.
+    V2,
+    /// The config response has the proportional, integral and derivative gains, rather than just
+    /// the spring constant.
+    V3,
+    /// The config response has the velocity controller gains, and the setpoint for whichever mode
+    /// the motor is in rather than just the target position.
+    V4,
+    /// Log messages start with their level.
+    V5,
+}
+
+impl ProtocolVersion {
+    /// The newest version of the protocol which this crate supports.
+    pub const LATEST: Self = Self::V5;
+
+    pub fn parse(byte: u8) -> Result<Self, ProtocolError> {
+        match byte {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            3 => Ok(Self::V3),
+            4 => Ok(Self::V4),
+            5 => Ok(Self::V5),
+            _ => Err(ProtocolError::UnsupportedVersion(byte)),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+            Self::V3 => 3,
+            Self::V4 => 4,
+            Self::V5 => 5,
+        }
+    }
+}
+
+/// A message which can be written in the unframed encoding.
+pub(crate) trait WriteTo {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error>;
+}
+
+/// Writes the given message to the given writer, wrapped in a frame.
+pub(crate) fn write_framed<W: Write>(
+    message: &impl WriteTo,
+    writer: &mut W,
+) -> Result<(), W::Error> {
+    // Work out the length first, so we don't need a buffer for the whole message.
+    let mut counter = CountingWriter(0);
+    message.write_to(&mut counter).unwrap();
+    let length = (counter.0 as u16).to_le_bytes();
+
+    writer.write_all(&[FRAME_START])?;
+    let mut crc_writer = CrcWriter {
+        inner: writer,
+        crc: Crc16::new(),
+    };
+    crc_writer.write_all(&length)?;
+    message.write_to(&mut crc_writer)?;
+    let crc = crc_writer.crc.finish();
+    writer.write_all(&crc.to_le_bytes())
+}
+
+/// Parses a frame from the start of the given buffer.
+///
+/// On success returns the payload and the length of the whole frame. On error returns the number
+/// of bytes which should be dropped before trying again, so that the decoder will resynchronise on
+/// the next start byte.
+pub fn parse(buf: &[u8]) -> nb::Result<(&[u8], usize), (ProtocolError, usize)> {
+    match *buf {
+        [] => Err(WouldBlock),
+        [FRAME_START, ref rest @ ..] => {
+            if rest.len() < 2 {
+                return Err(WouldBlock);
+            }
+            let length = u16::from_le_bytes(rest[..2].try_into().unwrap()) as usize;
+            if length > MAX_FRAME_PAYLOAD {
+                return Err(Other((ProtocolError::MessageTooLong, 1)));
+            }
+            if rest.len() < length + 4 {
+                return Err(WouldBlock);
+            }
+            let checksum = u16::from_le_bytes(rest[length + 2..length + 4].try_into().unwrap());
+            let mut crc = Crc16::new();
+            crc.update(&rest[..length + 2]);
+            if crc.finish() != checksum {
+                return Err(Other((ProtocolError::ChecksumMismatch, 1)));
+            }
+            Ok((&rest[2..length + 2], length + FRAME_OVERHEAD))
+        }
+        [byte, ..] => {
+            // Skip everything up to the next possible start of a frame.
+            let skip = buf
+                .iter()
+                .position(|&b| b == FRAME_START)
+                .unwrap_or(buf.len());
+            Err(Other((ProtocolError::InvalidByte(byte), skip)))
+        }
+    }
+}
+
+/// CRC-16/CCITT-FALSE.
+pub(crate) struct Crc16(u16);
+
+impl Crc16 {
+    pub(crate) fn new() -> Self {
+        Self(0xffff)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= (byte as u16) << 8;
+            for _ in 0..8 {
+                self.0 = if self.0 & 0x8000 != 0 {
+                    (self.0 << 1) ^ 0x1021
+                } else {
+                    self.0 << 1
+                };
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u16 {
+        self.0
+    }
+}
+
+/// Writer which just counts the number of bytes written to it.
+pub(crate) struct CountingWriter(pub(crate) usize);
+
+impl ErrorType for CountingWriter {
+    type Error = Infallible;
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Writer which passes bytes through to an inner writer, keeping a CRC of everything written.
+struct CrcWriter<'a, W> {
+    inner: &'a mut W,
+    crc: Crc16,
+}
+
+impl<W: ErrorType> ErrorType for CrcWriter<'_, W> {
+    type Error = W::Error;
+}
+
+impl<W: Write> Write for CrcWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        struct Raw<'a>(&'a [u8]);
+
+        impl WriteTo for Raw<'_> {
+            fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+                writer.write_all(self.0)
+            }
+        }
+
+        let mut buf = vec![];
+        write_framed(
+            &Raw(payload),
+            &mut embedded_io_adapters::std::FromStd::new(&mut buf),
+        )
+        .unwrap();
+        buf
+    }
+
+    #[test]
+    fn crc_check_value() {
+        let mut crc = Crc16::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0x29b1);
+    }
+
+    #[test]
+    fn version_round_trip() {
+        for version in [
+            ProtocolVersion::V1,
+            ProtocolVersion::V2,
+            ProtocolVersion::V3,
+            ProtocolVersion::V4,
+            ProtocolVersion::V5,
+        ] {
+            assert_eq!(ProtocolVersion::parse(version.to_byte()), Ok(version));
+        }
+        assert_eq!(
+            ProtocolVersion::parse(42),
+            Err(ProtocolError::UnsupportedVersion(42))
+        );
+    }
+
+    #[test]
+    fn layout() {
+        let buf = frame(b"Lp");
+        assert_eq!(buf.len(), 7);
+        assert_eq!(buf[..5], [FRAME_START, 2, 0, b'L', b'p']);
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = frame(b"LT12345678");
+        assert_eq!(parse(&buf), Ok((&b"LT12345678"[..], buf.len())));
+    }
+
+    #[test]
+    fn would_block_if_missing_byte() {
+        let buf = frame(b"Lp");
+        for prefix_length in 0..buf.len() {
+            assert_eq!(parse(&buf[..prefix_length]), Err(WouldBlock));
+        }
+    }
+
+    #[test]
+    fn skip_garbage_before_start() {
+        let mut buf = b"xyz".to_vec();
+        buf.extend(frame(b"Lp"));
+        assert_eq!(
+            parse(&buf),
+            Err(Other((ProtocolError::InvalidByte(b'x'), 3)))
+        );
+        assert_eq!(parse(&buf[3..]), Ok((&b"Lp"[..], 7)));
+    }
+
+    #[test]
+    fn skip_all_garbage() {
+        assert_eq!(
+            parse(b"LTxyz"),
+            Err(Other((ProtocolError::InvalidByte(b'L'), 5)))
+        );
+    }
+
+    #[test]
+    fn corrupted_payload() {
+        let mut buf = frame(b"LT12345678");
+        buf[5] ^= 0x01;
+        assert_eq!(
+            parse(&buf),
+            Err(Other((ProtocolError::ChecksumMismatch, 1)))
+        );
+    }
+
+    #[test]
+    fn too_long() {
+        let buf = [FRAME_START, 0xff, 0xff];
+        assert_eq!(parse(&buf), Err(Other((ProtocolError::MessageTooLong, 1))));
+    }
+
+    #[test]
+    fn resynchronise_after_dropped_byte() {
+        let first = frame(b"LT12345678");
+        let second = frame(b"Rp");
+        // Drop a byte from the middle of the first frame.
+        let mut buf = first[..4].to_vec();
+        buf.extend(&first[5..]);
+        buf.extend(&second);
+
+        let mut payloads = vec![];
+        let mut start = 0;
+        while start < buf.len() {
+            match parse(&buf[start..]) {
+                Ok((payload, length)) => {
+                    payloads.push(payload.to_vec());
+                    start += length;
+                }
+                Err(Other((_, length))) => start += length,
+                Err(WouldBlock) => break,
+            }
+        }
+        assert_eq!(payloads, vec![b"Rp".to_vec()]);
+    }
+}
@@ -1,16 +1,25 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+mod calibration;
 #[cfg(feature = "std")]
 pub mod client;
 mod command;
+pub mod config;
 mod error;
+pub mod frame;
 mod response;
+mod rx;
+mod telemetry;
 mod util;
 
-pub use command::{Command, DirectedCommand, Note, TorqueLimits};
+pub use calibration::{CalibrationError, HallMapping};
+pub use command::{Command, DirectedCommand, Gains, MotorTuning, Note, TorqueLimits};
 pub use embedded_io::ErrorType;
 pub use error::ProtocolError;
-pub use response::{Response, SideResponse};
+pub use frame::{Encoding, ProtocolVersion};
+pub use response::{Fault, FirmwareVersion, Response, SideResponse};
+pub use rx::RxCounts;
+pub use telemetry::{BatteryReadings, Telemetry, TelemetryFields};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Side {
@@ -48,3 +57,120 @@ impl Side {
         }
     }
 }
+
+/// The role of a board: whether it is connected directly to the host, or via the other board.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Role {
+    Primary,
+    Secondary,
+}
+
+impl Role {
+    pub fn parse(byte: u8) -> Result<Self, ProtocolError> {
+        match byte {
+            b'P' => Ok(Self::Primary),
+            b'S' => Ok(Self::Secondary),
+            _ => Err(ProtocolError::InvalidByte(byte)),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::Primary => b'P',
+            Self::Secondary => b'S',
+        }
+    }
+
+    /// Returns which side of the hoverboard a board with this role is on. The primary is on the
+    /// right, and the secondary on the left.
+    pub fn side(self) -> Side {
+        match self {
+            Self::Primary => Side::Right,
+            Self::Secondary => Side::Left,
+        }
+    }
+}
+
+/// What the motor is being asked to do.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Target {
+    /// Hold the given position, in steps.
+    Position(i64),
+    /// Turn at the given velocity, in steps per second.
+    Velocity(i32),
+    /// Apply the given torque, without any feedback.
+    Torque(i16),
+}
+
+impl Target {
+    /// Returns the target position, if this is a position target.
+    pub fn position(self) -> Option<i64> {
+        match self {
+            Self::Position(position) => Some(position),
+            _ => None,
+        }
+    }
+}
+
+/// How the motor phases are driven from the rotor position.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Commutation {
+    /// Six-step block commutation straight from the Hall sensor sector.
+    #[default]
+    Block,
+    /// Sinusoidal phase voltages, with the rotor angle interpolated between Hall sensor edges.
+    Sinusoidal,
+}
+
+impl Commutation {
+    pub fn parse(byte: u8) -> Result<Self, ProtocolError> {
+        match byte {
+            b'b' => Ok(Self::Block),
+            b's' => Ok(Self::Sinusoidal),
+            _ => Err(ProtocolError::InvalidByte(byte)),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::Block => b'b',
+            Self::Sinusoidal => b's',
+        }
+    }
+}
+
+/// The severity of a log message. Levels compare in increasing order of verbosity, so a message is
+/// sent if its level is less than or equal to the maximum level which has been set.
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    /// Something has failed.
+    Error,
+    /// Something unexpected has happened, but it has been dealt with.
+    Warn,
+    /// A change of state or configuration.
+    #[default]
+    Info,
+    /// Detail which is only useful for debugging, such as the effect of each command.
+    Debug,
+}
+
+impl LogLevel {
+    pub fn parse(byte: u8) -> Result<Self, ProtocolError> {
+        match byte {
+            b'E' => Ok(Self::Error),
+            b'W' => Ok(Self::Warn),
+            b'I' => Ok(Self::Info),
+            b'D' => Ok(Self::Debug),
+            _ => Err(ProtocolError::InvalidByte(byte)),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::Error => b'E',
+            Self::Warn => b'W',
+            Self::Info => b'I',
+            Self::Debug => b'D',
+        }
+    }
+}
@@ -1,11 +1,17 @@
+use crate::frame::{self, write_framed, CountingWriter, Encoding, WriteTo, FRAME_START};
 use crate::util::{ascii_to_bool, bool_to_ascii};
-use crate::{ProtocolError, Side};
+use crate::{
+    CalibrationError, Gains, HallMapping, LogLevel, MotorTuning, ProtocolError, Role, Side, Target,
+    Telemetry, TorqueLimits,
+};
 use arrayvec::ArrayString;
+use core::fmt::{self, Display, Formatter};
 use core::mem::size_of;
 use core::{convert::TryInto, fmt::Write, str};
 use nb::Error::{Other, WouldBlock};
 
 const MAX_LOG_SIZE: usize = 256;
+pub const MAX_VERSION_SIZE: usize = 20;
 
 struct TruncatingWriter(ArrayString<MAX_LOG_SIZE>);
 
@@ -39,27 +45,169 @@ impl Write for TruncatingWriter {
     }
 }
 
+/// Identifies the firmware running on a board.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FirmwareVersion {
+    /// The newest version of the protocol which the firmware supports.
+    ///
+    /// This is a raw number rather than a `ProtocolVersion` so that a board running newer firmware
+    /// can still tell us which version it has.
+    pub protocol_version: u8,
+    /// The version of the firmware crate.
+    pub crate_version: ArrayString<MAX_VERSION_SIZE>,
+    /// The git commit from which the firmware was built.
+    pub git_hash: ArrayString<MAX_VERSION_SIZE>,
+    /// Whether the firmware was built as the primary or secondary.
+    pub role: Role,
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} firmware {} ({}), protocol version {}",
+            self.role, self.crate_version, self.git_hash, self.protocol_version
+        )
+    }
+}
+
+/// A fault which has stopped the motor until it is cleared.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// The motor current stayed above the limit for too long. Includes the current in milliamps at
+    /// the time.
+    OverCurrent { current_ma: i32 },
+    /// The Hall sensors stayed in an invalid state while the motor was being driven, so they are
+    /// probably disconnected. Includes the raw state of the three sensors.
+    HallSensors { state: u8 },
+}
+
+impl Fault {
+    /// Returns the code identifying the kind of fault, and a value with details about it.
+    fn to_code(self) -> (u8, i32) {
+        match self {
+            Self::OverCurrent { current_ma } => (b'c', current_ma),
+            Self::HallSensors { state } => (b'h', state.into()),
+        }
+    }
+
+    fn from_code(code: u8, value: i32) -> Result<Self, ProtocolError> {
+        match code {
+            b'c' => Ok(Self::OverCurrent { current_ma: value }),
+            b'h' => Ok(Self::HallSensors {
+                state: value
+                    .try_into()
+                    .map_err(|_| ProtocolError::InvalidByte(code))?,
+            }),
+            _ => Err(ProtocolError::InvalidByte(code)),
+        }
+    }
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::OverCurrent { current_ma } => write!(f, "over-current ({} mA)", current_ma),
+            Self::HallSensors { state } => {
+                write!(f, "Hall sensors disconnected (state {:03b})", state)
+            }
+        }
+    }
+}
+
+// Log messages are much bigger than any other response, but without an allocator they can't be
+// boxed, and responses are only ever built one at a time.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Response {
-    Log(ArrayString<MAX_LOG_SIZE>),
+    /// A message to be logged at the given level.
+    Log {
+        level: LogLevel,
+        message: ArrayString<MAX_LOG_SIZE>,
+    },
     Position(i64),
+    /// Battery voltages in millivolts, and motor current in milliamps.
     BatteryReadings {
         battery_voltage: u16,
         backup_battery_voltage: u16,
-        motor_current: u16,
+        /// Positive when the motor is drawing power from the battery, negative when regenerating.
+        motor_current: i32,
     },
     ChargeState {
         charger_connected: bool,
     },
     PowerOff,
+    Version(FirmwareVersion),
+    /// The command with the given sequence number was received and run.
+    Ack(u8),
+    /// The command with the given sequence number was rejected.
+    Nack(u8, ProtocolError),
+    /// The control parameters currently in use.
+    Config {
+        torque_limits: TorqueLimits,
+        /// The position controller gains.
+        gains: Gains,
+        /// The velocity controller gains.
+        velocity_gains: Gains,
+        /// What the motor is currently being asked to do, if anything.
+        target: Option<Target>,
+    },
+    /// Raw accelerometer and gyroscope readings from the IMU, in X, Y, Z order.
+    ImuReadings {
+        accel: [i16; 3],
+        gyro: [i16; 3],
+    },
+    Telemetry(Telemetry),
+    /// Motor velocity in Hall sensor steps per second.
+    Velocity(i32),
+    Fault(Fault),
+    /// The battery voltage in millivolts has dropped below the warning threshold, or below the
+    /// cutoff threshold in which case the board is about to power off.
+    LowBattery {
+        battery_voltage: u16,
+        cutoff: bool,
+    },
+    /// The battery voltage in millivolts has risen back above the warning threshold since the last
+    /// warning.
+    BatteryRecovered {
+        battery_voltage: u16,
+    },
+    /// Counts of Hall sensor errors since the board started.
+    HallDiagnostics {
+        /// The number of times the Hall sensors have gone into an invalid state.
+        invalid_states: u32,
+        /// The number of times the Hall sensors have skipped over a sector.
+        skipped_sectors: u32,
+    },
+    /// The result of motor calibration. If it succeeded, the new mapping is now in use.
+    MotorCalibration(Result<HallMapping, CalibrationError>),
+    /// The motor tuning currently in use.
+    MotorTuning(MotorTuning),
+    /// Sent periodically by the secondary to the primary, to show that the link between them is
+    /// working. The primary doesn't pass this on to the host.
+    Heartbeat,
+    /// No heartbeat has been received from the other side for a while, so the target has been
+    /// removed.
+    LinkLost,
+    /// Heartbeats are being received from the other side again, after the link was lost.
+    LinkRestored,
+    /// No command has been received from the host within the comms timeout, so the torque is being
+    /// ramped to the safe torque if there was a target.
+    CommsTimeout,
+    /// The given number of logs and reports have been dropped since this was last sent, because
+    /// there wasn't room for them in the transmit buffer.
+    Dropped(u32),
 }
 
 impl Response {
-    pub fn log_from_fmt(args: core::fmt::Arguments<'_>) -> Self {
+    pub fn log_from_fmt(level: LogLevel, args: core::fmt::Arguments<'_>) -> Self {
         let mut writer = TruncatingWriter(ArrayString::new());
         writer.write_fmt(args).unwrap();
 
-        Self::Log(writer.0)
+        Self::Log {
+            level,
+            message: writer.0,
+        }
     }
 
     pub fn write_to<W>(&self, writer: &mut W) -> Result<(), W::Error>
@@ -67,8 +215,8 @@ impl Response {
         W: embedded_io::Write,
     {
         match self {
-            Self::Log(message) => {
-                writer.write_all(b"\"")?;
+            Self::Log { level, message } => {
+                writer.write_all(&[b'"', level.to_byte()])?;
                 writer.write_all(message.as_bytes())?;
                 writer.write_all(b"\n")
             }
@@ -90,6 +238,99 @@ impl Response {
                 writer.write_all(&[b'C', bool_to_ascii(*charger_connected)])
             }
             Self::PowerOff => writer.write_all(b"p"),
+            Self::Version(version) => {
+                writer.write_all(&[b'V', version.protocol_version, version.role.to_byte()])?;
+                write_short_string(writer, &version.crate_version)?;
+                write_short_string(writer, &version.git_hash)
+            }
+            Self::Ack(sequence) => writer.write_all(&[b'A', *sequence]),
+            Self::Nack(sequence, error) => {
+                writer.write_all(&[b'N', *sequence])?;
+                writer.write_all(&error.to_bytes())
+            }
+            Self::Config {
+                torque_limits,
+                gains,
+                velocity_gains,
+                target,
+            } => {
+                writer.write_all(b"G")?;
+                writer.write_all(&torque_limits.negative.to_le_bytes())?;
+                writer.write_all(&torque_limits.positive.to_le_bytes())?;
+                for gains in [gains, velocity_gains] {
+                    writer.write_all(&gains.kp.to_le_bytes())?;
+                    writer.write_all(&gains.ki.to_le_bytes())?;
+                    writer.write_all(&gains.kd.to_le_bytes())?;
+                }
+                let (mode, value) = match *target {
+                    None => (b'n', 0),
+                    Some(Target::Position(position)) => (b'p', position),
+                    Some(Target::Velocity(velocity)) => (b'v', velocity.into()),
+                    Some(Target::Torque(torque)) => (b't', torque.into()),
+                };
+                writer.write_all(&[mode])?;
+                writer.write_all(&value.to_le_bytes())
+            }
+            Self::ImuReadings { accel, gyro } => {
+                writer.write_all(b"M")?;
+                for value in accel.iter().chain(gyro) {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+                Ok(())
+            }
+            Self::Telemetry(telemetry) => {
+                writer.write_all(b"Y")?;
+                telemetry.write_to(writer)
+            }
+            Self::Velocity(velocity) => {
+                writer.write_all(b"v")?;
+                writer.write_all(&velocity.to_le_bytes())
+            }
+            Self::Fault(fault) => {
+                let (code, value) = fault.to_code();
+                writer.write_all(&[b'F', code])?;
+                writer.write_all(&value.to_le_bytes())
+            }
+            Self::LowBattery {
+                battery_voltage,
+                cutoff,
+            } => {
+                writer.write_all(b"U")?;
+                writer.write_all(&battery_voltage.to_le_bytes())?;
+                writer.write_all(&[bool_to_ascii(*cutoff)])
+            }
+            Self::BatteryRecovered { battery_voltage } => {
+                writer.write_all(b"O")?;
+                writer.write_all(&battery_voltage.to_le_bytes())
+            }
+            Self::HallDiagnostics {
+                invalid_states,
+                skipped_sectors,
+            } => {
+                writer.write_all(b"H")?;
+                writer.write_all(&invalid_states.to_le_bytes())?;
+                writer.write_all(&skipped_sectors.to_le_bytes())
+            }
+            Self::MotorCalibration(Ok(mapping)) => {
+                writer.write_all(b"k1")?;
+                writer.write_all(&mapping.states())
+            }
+            Self::MotorCalibration(Err(error)) => {
+                writer.write_all(b"k0")?;
+                writer.write_all(&error.to_bytes())
+            }
+            Self::MotorTuning(tuning) => {
+                writer.write_all(b"W")?;
+                writer.write_all(&tuning.to_bytes())
+            }
+            Self::Heartbeat => writer.write_all(b"J"),
+            Self::LinkLost => writer.write_all(b"l"),
+            Self::LinkRestored => writer.write_all(b"r"),
+            Self::CommsTimeout => writer.write_all(b"T"),
+            Self::Dropped(count) => {
+                writer.write_all(b"D")?;
+                writer.write_all(&count.to_le_bytes())
+            }
         }
     }
 
@@ -98,12 +339,15 @@ impl Response {
             [] => return Err(WouldBlock),
             [b'"', ref rest @ ..] => {
                 if let Some(end) = rest.iter().position(|c| *c == b'\n') {
-                    let utf8 = str::from_utf8(&rest[..end])
-                        .map_err(|e| (ProtocolError::Utf8Error(e), end + 2))?;
+                    let (&level, message) = rest[..end]
+                        .split_first()
+                        .ok_or((ProtocolError::MessageTooShort, end + 2))?;
+                    let level = LogLevel::parse(level).map_err(|e| (e, end + 2))?;
+                    let utf8 = str::from_utf8(message).map_err(|e| (e.into(), end + 2))?;
                     let message = ArrayString::from(utf8)
                         .map_err(|_| (ProtocolError::MessageTooLong, end + 2))?;
-                    (Self::Log(message), end + 2)
-                } else if rest.len() > MAX_LOG_SIZE {
+                    (Self::Log { level, message }, end + 2)
+                } else if rest.len() > MAX_LOG_SIZE + 1 {
                     return Err(Other((ProtocolError::MessageTooLong, rest.len() + 1)));
                 } else {
                     return Err(WouldBlock);
@@ -119,19 +363,19 @@ impl Response {
             }
             [b'B', ref rest @ ..] => {
                 #[allow(clippy::comparison_chain)]
-                if rest.len() < 6 {
+                if rest.len() < 8 {
                     return Err(WouldBlock);
                 }
                 let battery_voltage = u16::from_le_bytes(rest[..2].try_into().unwrap());
                 let backup_battery_voltage = u16::from_le_bytes(rest[2..4].try_into().unwrap());
-                let motor_current = u16::from_le_bytes(rest[4..6].try_into().unwrap());
+                let motor_current = i32::from_le_bytes(rest[4..8].try_into().unwrap());
                 (
                     Self::BatteryReadings {
                         battery_voltage,
                         backup_battery_voltage,
                         motor_current,
                     },
-                    7,
+                    9,
                 )
             }
             [b'C'] => return Err(WouldBlock),
@@ -142,12 +386,208 @@ impl Response {
                 2,
             ),
             [b'p', ..] => (Self::PowerOff, 1),
+            [b'J', ..] => (Self::Heartbeat, 1),
+            [b'l', ..] => (Self::LinkLost, 1),
+            [b'r', ..] => (Self::LinkRestored, 1),
+            [b'T', ..] => (Self::CommsTimeout, 1),
+            [b'G', ref rest @ ..] => {
+                if rest.len() < 25 {
+                    return Err(WouldBlock);
+                }
+                let negative = i16::from_le_bytes(rest[..2].try_into().unwrap());
+                let positive = i16::from_le_bytes(rest[2..4].try_into().unwrap());
+                let gains = |bytes: &[u8]| Gains {
+                    kp: u16::from_le_bytes(bytes[..2].try_into().unwrap()),
+                    ki: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
+                    kd: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+                };
+                let value = i64::from_le_bytes(rest[17..25].try_into().unwrap());
+                // Velocity and torque were sign-extended to i64, so truncating them is lossless.
+                let target = match rest[16] {
+                    b'n' => None,
+                    b'p' => Some(Target::Position(value)),
+                    b'v' => Some(Target::Velocity(value as i32)),
+                    b't' => Some(Target::Torque(value as i16)),
+                    mode => return Err(Other((ProtocolError::InvalidByte(mode), 26))),
+                };
+                (
+                    Self::Config {
+                        torque_limits: TorqueLimits { negative, positive },
+                        gains: gains(&rest[4..10]),
+                        velocity_gains: gains(&rest[10..16]),
+                        target,
+                    },
+                    26,
+                )
+            }
+            [b'M', ref rest @ ..] => {
+                if rest.len() < 12 {
+                    return Err(WouldBlock);
+                }
+                let mut values = [0; 6];
+                for (value, bytes) in values.iter_mut().zip(rest[..12].chunks_exact(2)) {
+                    *value = i16::from_le_bytes(bytes.try_into().unwrap());
+                }
+                (
+                    Self::ImuReadings {
+                        accel: [values[0], values[1], values[2]],
+                        gyro: [values[3], values[4], values[5]],
+                    },
+                    13,
+                )
+            }
+            [b'Y', ref rest @ ..] => {
+                let (telemetry, length) =
+                    Telemetry::parse(rest).map_err(|e| e.map(|(e, length)| (e, length + 1)))?;
+                (Self::Telemetry(telemetry), length + 1)
+            }
+            [b'D', ref rest @ ..] => {
+                if rest.len() < size_of::<u32>() {
+                    return Err(WouldBlock);
+                }
+                let count = u32::from_le_bytes(rest[..4].try_into().unwrap());
+                (Self::Dropped(count), 5)
+            }
+            [b'v', ref rest @ ..] => {
+                if rest.len() < size_of::<i32>() {
+                    return Err(WouldBlock);
+                }
+                let velocity = i32::from_le_bytes(rest[..4].try_into().unwrap());
+                (Self::Velocity(velocity), 5)
+            }
+            [b'F', ref rest @ ..] => {
+                if rest.len() < 5 {
+                    return Err(WouldBlock);
+                }
+                let value = i32::from_le_bytes(rest[1..5].try_into().unwrap());
+                let fault = Fault::from_code(rest[0], value).map_err(|e| (e, 6))?;
+                (Self::Fault(fault), 6)
+            }
+            [b'U', ref rest @ ..] => {
+                if rest.len() < 3 {
+                    return Err(WouldBlock);
+                }
+                let battery_voltage = u16::from_le_bytes(rest[..2].try_into().unwrap());
+                let cutoff = ascii_to_bool(rest[2]).map_err(|e| (e, 4))?;
+                (
+                    Self::LowBattery {
+                        battery_voltage,
+                        cutoff,
+                    },
+                    4,
+                )
+            }
+            [b'O', ref rest @ ..] => {
+                if rest.len() < 2 {
+                    return Err(WouldBlock);
+                }
+                let battery_voltage = u16::from_le_bytes(rest[..2].try_into().unwrap());
+                (Self::BatteryRecovered { battery_voltage }, 3)
+            }
+            [b'H', ref rest @ ..] => {
+                if rest.len() < 8 {
+                    return Err(WouldBlock);
+                }
+                let invalid_states = u32::from_le_bytes(rest[..4].try_into().unwrap());
+                let skipped_sectors = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+                (
+                    Self::HallDiagnostics {
+                        invalid_states,
+                        skipped_sectors,
+                    },
+                    9,
+                )
+            }
+            [b'k'] => return Err(WouldBlock),
+            [b'k', ok, ref rest @ ..] => {
+                if ascii_to_bool(ok).map_err(|e| (e, 2))? {
+                    if rest.len() < 6 {
+                        return Err(WouldBlock);
+                    }
+                    let mapping =
+                        HallMapping::new(rest[..6].try_into().unwrap()).map_err(|e| (e, 8))?;
+                    (Self::MotorCalibration(Ok(mapping)), 8)
+                } else {
+                    if rest.len() < 2 {
+                        return Err(WouldBlock);
+                    }
+                    let error =
+                        CalibrationError::from_bytes([rest[0], rest[1]]).map_err(|e| (e, 4))?;
+                    (Self::MotorCalibration(Err(error)), 4)
+                }
+            }
+            [b'W', ref rest @ ..] => {
+                if rest.len() < MotorTuning::LENGTH {
+                    return Err(WouldBlock);
+                }
+                let tuning =
+                    MotorTuning::from_bytes(rest[..MotorTuning::LENGTH].try_into().unwrap())
+                        .map_err(|e| (e, MotorTuning::LENGTH + 1))?;
+                (Self::MotorTuning(tuning), MotorTuning::LENGTH + 1)
+            }
+            [b'A'] => return Err(WouldBlock),
+            [b'A', sequence, ..] => (Self::Ack(sequence), 2),
+            [b'N', ref rest @ ..] => {
+                if rest.len() < 3 {
+                    return Err(WouldBlock);
+                }
+                let error = ProtocolError::from_bytes([rest[1], rest[2]]).map_err(|e| (e, 4))?;
+                (Self::Nack(rest[0], error), 4)
+            }
+            [b'V', ref rest @ ..] => {
+                if rest.len() < 2 {
+                    return Err(WouldBlock);
+                }
+                let protocol_version = rest[0];
+                let role = Role::parse(rest[1]).map_err(|e| (e, 3))?;
+                let (crate_version, crate_version_length) = parse_short_string(&rest[2..])
+                    .map_err(|e| e.map(|(e, length)| (e, length + 3)))?;
+                let (git_hash, git_hash_length) =
+                    parse_short_string(&rest[2 + crate_version_length..])
+                        .map_err(|e| e.map(|(e, length)| (e, length + 3 + crate_version_length)))?;
+                (
+                    Self::Version(FirmwareVersion {
+                        protocol_version,
+                        crate_version,
+                        git_hash,
+                        role,
+                    }),
+                    3 + crate_version_length + git_hash_length,
+                )
+            }
             [c, ..] => return Err(Other((ProtocolError::InvalidCommand(c), 1))),
         };
         Ok(result)
     }
 }
 
+/// Writes a string of up to 255 bytes, prefixed by its length.
+fn write_short_string<W: embedded_io::Write, const N: usize>(
+    writer: &mut W,
+    string: &ArrayString<N>,
+) -> Result<(), W::Error> {
+    writer.write_all(&[string.len() as u8])?;
+    writer.write_all(string.as_bytes())
+}
+
+/// Parses a string written by `write_short_string`, returning it along with the number of bytes
+/// used.
+fn parse_short_string<const N: usize>(
+    buf: &[u8],
+) -> nb::Result<(ArrayString<N>, usize), (ProtocolError, usize)> {
+    let length = match buf.first() {
+        Some(&length) => length as usize,
+        None => return Err(WouldBlock),
+    };
+    if buf.len() < length + 1 {
+        return Err(WouldBlock);
+    }
+    let utf8 = str::from_utf8(&buf[1..length + 1]).map_err(|e| (e.into(), length + 1))?;
+    let string =
+        ArrayString::from(utf8).map_err(|_| (ProtocolError::MessageTooLong, length + 1))?;
+    Ok((string, length + 1))
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SideResponse {
     pub side: Side,
@@ -160,6 +600,18 @@ impl SideResponse {
         self.write_to(&mut embedded_io_adapters::std::FromStd::new(writer))
     }
 
+    #[cfg(feature = "std")]
+    pub fn write_with_encoding_to_std(
+        &self,
+        encoding: Encoding,
+        writer: impl std::io::Write,
+    ) -> std::io::Result<()> {
+        self.write_with_encoding(
+            encoding,
+            &mut embedded_io_adapters::std::FromStd::new(writer),
+        )
+    }
+
     pub fn write_to<W>(&self, writer: &mut W) -> Result<(), W::Error>
     where
         W: embedded_io::Write,
@@ -168,6 +620,25 @@ impl SideResponse {
         self.response.write_to(writer)
     }
 
+    /// Writes the response in the given encoding.
+    pub fn write_with_encoding<W>(&self, encoding: Encoding, writer: &mut W) -> Result<(), W::Error>
+    where
+        W: embedded_io::Write,
+    {
+        if encoding == Encoding::Framed {
+            write_framed(self, writer)
+        } else {
+            self.write_to(writer)
+        }
+    }
+
+    /// Returns the number of bytes which `write_with_encoding` would write.
+    pub fn length_with_encoding(&self, encoding: Encoding) -> usize {
+        let mut counter = CountingWriter(0);
+        self.write_with_encoding(encoding, &mut counter).unwrap();
+        counter.0
+    }
+
     pub fn parse_exact(buffer: &[u8]) -> nb::Result<Self, ProtocolError> {
         match Self::parse(buffer) {
             Ok((result, length)) => {
@@ -182,6 +653,35 @@ impl SideResponse {
         }
     }
 
+    /// Parses a response in the given encoding from the start of the buffer.
+    pub fn parse_with_encoding(
+        encoding: Encoding,
+        buffer: &[u8],
+    ) -> nb::Result<(Self, usize), (ProtocolError, usize)> {
+        if encoding == Encoding::Framed {
+            let (payload, length) = frame::parse(buffer)?;
+            match Self::parse_exact(payload) {
+                Ok(response) => Ok((response, length)),
+                Err(WouldBlock) => Err(Other((ProtocolError::MessageTooShort, length))),
+                Err(Other(e)) => Err(Other((e, length))),
+            }
+        } else {
+            Self::parse(buffer)
+        }
+    }
+
+    /// Parses a response in whichever encoding it appears to be in.
+    ///
+    /// This should only be used on links where corruption is unlikely, as a corrupted frame may be
+    /// misinterpreted as an unframed response.
+    pub fn parse_any_encoding(buffer: &[u8]) -> nb::Result<(Self, usize), (ProtocolError, usize)> {
+        if buffer.first() == Some(&FRAME_START) {
+            Self::parse_with_encoding(Encoding::Framed, buffer)
+        } else {
+            Self::parse_with_encoding(Encoding::Unframed, buffer)
+        }
+    }
+
     pub fn parse(buffer: &[u8]) -> nb::Result<(Self, usize), (ProtocolError, usize)> {
         if let [side, ref rest @ ..] = *buffer {
             let side = Side::parse(side).map_err(|e| (e, 1))?;
@@ -196,9 +696,16 @@ impl SideResponse {
     }
 }
 
+impl WriteTo for SideResponse {
+    fn write_to<W: embedded_io::Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        SideResponse::write_to(self, writer)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::BatteryReadings;
     use test_case::test_case;
 
     mod log {
@@ -210,9 +717,10 @@ mod tests {
         #[test_case("€" ; "width 3")]
         #[test_case("𐍈" ; "width 4")]
         fn too_long_with_unicode_widths(c: &str) {
-            let response = Response::log_from_fmt(format_args!("{}", c.repeat(500)));
+            let response =
+                Response::log_from_fmt(LogLevel::Info, format_args!("{}", c.repeat(500)));
             let log = match response {
-                Response::Log(log) => log,
+                Response::Log { message, .. } => message,
                 _ => panic!(),
             };
             assert!(&log[..].ends_with("..."))
@@ -220,9 +728,25 @@ mod tests {
 
         #[test]
         fn parse_too_long() {
-            let buf = format!("\"{}\n", "n".repeat(500));
+            let buf = format!("\"I{}\n", "n".repeat(500));
             let response = Response::parse(buf.as_bytes());
-            assert_eq!(response, Err(Other((ProtocolError::MessageTooLong, 502))));
+            assert_eq!(response, Err(Other((ProtocolError::MessageTooLong, 503))));
+        }
+
+        #[test]
+        fn parse_invalid_level() {
+            assert_eq!(
+                Response::parse(b"\"Xhello\n"),
+                Err(Other((ProtocolError::InvalidByte(b'X'), 8)))
+            );
+        }
+
+        #[test]
+        fn parse_missing_level() {
+            assert_eq!(
+                Response::parse(b"\"\n"),
+                Err(Other((ProtocolError::MessageTooShort, 2)))
+            );
         }
     }
 
@@ -258,12 +782,34 @@ mod tests {
 
     #[test_case(b"RI" ; "position")]
     #[test_case(b"LI" ; "other side position")]
-    #[test_case(b"RB12345" ; "battery readings")]
-    #[test_case(b"LB12345" ; "other side battery readings")]
+    #[test_case(b"RB1234567" ; "battery readings")]
+    #[test_case(b"LB1234567" ; "other side battery readings")]
     #[test_case(b"RC" ; "charge state")]
     #[test_case(b"LC" ; "other side charge state")]
-    #[test_case(b"R\"blah" ; "log")]
-    #[test_case(b"L\"blah" ; "other side log")]
+    #[test_case(b"R\"" ; "log without level")]
+    #[test_case(b"R\"Iblah" ; "log")]
+    #[test_case(b"L\"Iblah" ; "other side log")]
+    #[test_case(b"RV\x02P\x050.1.0\x07abcdef" ; "version")]
+    #[test_case(b"RA" ; "ack")]
+    #[test_case(b"RN\x01c" ; "nack")]
+    #[test_case(
+        b"RG\x38\xff\xc8\x00\xe8\x03\x00\x00\x14\x00\x32\x00\xc8\x00\x00\x00\
+          p\x00\x00\x00\x00\x00\x00\x00" ;
+        "config"
+    )]
+    #[test_case(b"RM\x01\x00\xfe\xff\x03\x00\xfc\xff\x05\x00\x00" ; "imu readings")]
+    #[test_case(b"RY" ; "telemetry without fields")]
+    #[test_case(b"Rv\x01\x02\x03" ; "velocity")]
+    #[test_case(b"RFc\x01\x02\x03" ; "fault")]
+    #[test_case(b"RU\x01\x02" ; "low battery")]
+    #[test_case(b"RO\x01" ; "battery recovered")]
+    #[test_case(b"RH\x01\x00\x00\x00\x02\x00\x00" ; "hall diagnostics")]
+    #[test_case(b"Rk" ; "motor calibration")]
+    #[test_case(b"Rk1\x01\x05\x04\x06\x02" ; "motor calibration mapping")]
+    #[test_case(b"Rk0i" ; "motor calibration error")]
+    #[test_case(b"RW\x05\x001\x0a\x00\x80\x3e\x00" ; "motor tuning")]
+    #[test_case(b"RY\x09\x01\x00\x00\x00\x00\x00\x00\x00" ; "telemetry")]
+    #[test_case(b"RD\x01\x02" ; "dropped")]
     fn parse_partial(partial_response: &[u8]) {
         for length in 1..=partial_response.len() {
             assert_eq!(
@@ -277,6 +823,161 @@ mod tests {
         }
     }
 
+    fn log(level: LogLevel, message: &str) -> Response {
+        Response::Log {
+            level,
+            message: ArrayString::from(message).unwrap(),
+        }
+    }
+
+    fn version() -> FirmwareVersion {
+        FirmwareVersion {
+            protocol_version: 2,
+            crate_version: ArrayString::from("0.1.0").unwrap(),
+            git_hash: ArrayString::from("abcdef0").unwrap(),
+            role: Role::Primary,
+        }
+    }
+
+    fn tuning() -> MotorTuning {
+        MotorTuning {
+            ramp_cycles_per_step: 5,
+            ramp_down: false,
+            dead_zone: 10,
+            pwm_frequency_hz: 16000,
+        }
+    }
+
+    fn telemetry() -> Telemetry {
+        Telemetry {
+            position: Some(-42),
+            velocity: Some(100),
+            battery: Some(BatteryReadings {
+                battery_voltage: 40000,
+                backup_battery_voltage: 12000,
+                motor_current: 1100,
+            }),
+            charger_connected: Some(true),
+        }
+    }
+
+    #[test]
+    fn parse_telemetry_position_and_charger() {
+        assert_eq!(
+            SideResponse::parse_exact(b"LY\x09\x2a\x00\x00\x00\x00\x00\x00\x000"),
+            Ok(SideResponse {
+                side: Side::Left,
+                response: Response::Telemetry(Telemetry {
+                    position: Some(42),
+                    charger_connected: Some(false),
+                    ..Default::default()
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid_low_battery() {
+        assert_eq!(
+            SideResponse::parse(b"RU\x01\x02x"),
+            Err(Other((ProtocolError::InvalidByte(b'x'), 5)))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_fault() {
+        assert_eq!(
+            SideResponse::parse(b"RF?\x00\x00\x00\x00"),
+            Err(Other((ProtocolError::InvalidByte(b'?'), 7)))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_telemetry() {
+        assert_eq!(
+            SideResponse::parse(b"RY\x20"),
+            Err(Other((ProtocolError::InvalidByte(0x20), 3)))
+        );
+        assert_eq!(
+            SideResponse::parse(b"RY\x08x"),
+            Err(Other((ProtocolError::InvalidByte(b'x'), 4)))
+        );
+    }
+
+    fn config(target: Option<Target>) -> Response {
+        Response::Config {
+            torque_limits: TorqueLimits {
+                negative: -200,
+                positive: 30,
+            },
+            gains: Gains {
+                kp: 1000,
+                ki: 0,
+                kd: 20,
+            },
+            velocity_gains: Gains {
+                kp: 50,
+                ki: 200,
+                kd: 0,
+            },
+            target,
+        }
+    }
+
+    #[test]
+    fn parse_invalid_config_target() {
+        assert_eq!(
+            SideResponse::parse(
+                b"RG\x38\xff\xc8\x00\xe8\x03\x00\x00\x14\x00\x32\x00\xc8\x00\x00\x00\
+                  x\x00\x00\x00\x00\x00\x00\x00\x00"
+            ),
+            Err(Other((ProtocolError::InvalidByte(b'x'), 27)))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_hall_fault_state() {
+        assert_eq!(
+            SideResponse::parse(b"RFh\x00\x01\x00\x00"),
+            Err(Other((ProtocolError::InvalidByte(b'h'), 7)))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_hall_mapping() {
+        assert_eq!(
+            SideResponse::parse(b"Rk1\x01\x05\x04\x06\x02\x07"),
+            Err(Other((ProtocolError::InvalidByte(0b111), 9)))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_nack_error() {
+        assert_eq!(
+            SideResponse::parse(b"RN\x01?\x00"),
+            Err(Other((ProtocolError::InvalidByte(b'?'), 5)))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_role() {
+        assert_eq!(
+            SideResponse::parse(b"RV\x02X\x00\x00"),
+            Err(Other((ProtocolError::InvalidByte(b'X'), 4)))
+        );
+    }
+
+    #[test]
+    fn parse_version_too_long() {
+        let mut buf = b"RV\x02P\x19".to_vec();
+        buf.extend(b"0123456789012345678901234");
+        buf.extend(b"\x00");
+        assert_eq!(
+            SideResponse::parse(&buf),
+            Err(Other((ProtocolError::MessageTooLong, 30)))
+        );
+    }
+
     #[test]
     fn parse_invalid_charge_state() {
         assert_eq!(
@@ -290,14 +991,21 @@ mod tests {
     }
 
     #[test_case(&[b'R', b'I', 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11], Response::Position(0x1122334455667788))]
-    #[test_case(&[b'R', b'B', 0x66, 0x55, 0x44, 0x33, 0x22, 0x11], Response::BatteryReadings {
+    #[test_case(&[b'R', b'B', 0x66, 0x55, 0x44, 0x33, 0xfe, 0xff, 0xff, 0xff], Response::BatteryReadings {
         battery_voltage: 0x5566,
         backup_battery_voltage: 0x3344,
-        motor_current: 0x1122,
+        motor_current: -2,
     })]
     #[test_case(b"RC0", Response::ChargeState { charger_connected: false })]
     #[test_case(b"RC1", Response::ChargeState { charger_connected: true })]
-    #[test_case(b"R\"hello\n", Response::Log(ArrayString::from("hello").unwrap()))]
+    #[test_case(b"R\"Ihello\n", log(LogLevel::Info, "hello"))]
+    #[test_case(b"R\"Ewhoops\n", log(LogLevel::Error, "whoops"))]
+    #[test_case(b"RV\x02P\x050.1.0\x07abcdef0", Response::Version(version()))]
+    #[test_case(b"RM\x01\x00\xfe\xff\x03\x00\xfc\xff\x05\x00\x00\x80", Response::ImuReadings {
+        accel: [1, -2, 3],
+        gyro: [-4, 5, i16::MIN],
+    })]
+    #[test_case(b"Rv\xfe\xff\xff\xff", Response::Velocity(-2))]
     fn parse_valid(bytes: &[u8], response: Response) {
         assert_eq!(
             SideResponse::parse(bytes),
@@ -322,13 +1030,47 @@ mod tests {
     #[test_case(Response::BatteryReadings {
         battery_voltage: 0x5566,
         backup_battery_voltage: 0x3344,
-        motor_current: 0x1122,
+        motor_current: -0x1122,
     })]
     #[test_case(Response::ChargeState { charger_connected: false })]
     #[test_case(Response::ChargeState { charger_connected: true })]
-    #[test_case(Response::Log(ArrayString::from("hello").unwrap()))]
-    #[test_case(Response::Log(ArrayString::from("emoji 👨‍👨‍👦").unwrap()))]
+    #[test_case(log(LogLevel::Info, "hello"))]
+    #[test_case(log(LogLevel::Warn, "emoji 👨‍👨‍👦"))]
     #[test_case(Response::PowerOff)]
+    #[test_case(Response::Version(version()))]
+    #[test_case(Response::Ack(42))]
+    #[test_case(Response::Nack(42, ProtocolError::InvalidCommand(b'!')))]
+    #[test_case(Response::Nack(42, ProtocolError::MessageTooLong))]
+    #[test_case(Response::Nack(42, ProtocolError::InvalidUtf8 { error_len: Some(1) }))]
+    #[test_case(Response::Nack(42, ProtocolError::InvalidUtf8 { error_len: None }))]
+    #[test_case(config(Some(Target::Position(-1234))))]
+    #[test_case(config(Some(Target::Position(i64::MIN))))]
+    #[test_case(config(Some(Target::Velocity(-300))))]
+    #[test_case(config(Some(Target::Torque(i16::MIN))))]
+    #[test_case(config(None))]
+    #[test_case(Response::ImuReadings { accel: [1, -2, 3], gyro: [-4, 5, i16::MIN] })]
+    #[test_case(Response::Telemetry(Telemetry::default()))]
+    #[test_case(Response::Telemetry(telemetry()))]
+    #[test_case(Response::Telemetry(Telemetry { velocity: Some(-7), ..Default::default() }))]
+    #[test_case(Response::Velocity(-1234))]
+    #[test_case(Response::Fault(Fault::OverCurrent { current_ma: 20000 }))]
+    #[test_case(Response::LowBattery { battery_voltage: 32000, cutoff: false })]
+    #[test_case(Response::LowBattery { battery_voltage: 29000, cutoff: true })]
+    #[test_case(Response::BatteryRecovered { battery_voltage: 34000 })]
+    #[test_case(Response::Fault(Fault::HallSensors { state: 0b111 }))]
+    #[test_case(Response::HallDiagnostics { invalid_states: 3, skipped_sectors: 0x12345678 })]
+    #[test_case(Response::MotorCalibration(Ok(HallMapping::DEFAULT)))]
+    #[test_case(Response::MotorCalibration(Err(CalibrationError::InvalidState(0b111))))]
+    #[test_case(Response::MotorCalibration(Err(CalibrationError::NotMoving)))]
+    #[test_case(Response::MotorTuning(tuning()))]
+    #[test_case(Response::Nack(42, ProtocolError::MotorBusy))]
+    #[test_case(Response::Nack(42, ProtocolError::FlashFailed))]
+    #[test_case(Response::Heartbeat)]
+    #[test_case(Response::LinkLost)]
+    #[test_case(Response::LinkRestored)]
+    #[test_case(Response::CommsTimeout)]
+    #[test_case(Response::Dropped(0x12345678))]
+    #[test_case(Response::Nack(42, ProtocolError::LinkLost))]
     fn round_trip(response: Response) {
         let side_response = SideResponse {
             side: Side::Right,
@@ -338,22 +1080,45 @@ mod tests {
         side_response.write_to_std(&mut buffer).unwrap();
 
         assert_eq!(
-            SideResponse::parse(&mut buffer),
+            side_response.length_with_encoding(Encoding::Unframed),
+            buffer.len()
+        );
+        assert_eq!(
+            SideResponse::parse(&buffer),
             Ok((side_response.clone(), buffer.len()))
         );
-        assert_eq!(SideResponse::parse_exact(&mut buffer), Ok(side_response));
+        assert_eq!(SideResponse::parse_exact(&buffer), Ok(side_response));
     }
 
     #[test_case(Response::Position(0x1122334455667788))]
     #[test_case(Response::BatteryReadings {
         battery_voltage: 0x5566,
         backup_battery_voltage: 0x3344,
-        motor_current: 0x1122,
+        motor_current: -0x1122,
     })]
     #[test_case(Response::ChargeState { charger_connected: false })]
     #[test_case(Response::ChargeState { charger_connected: true })]
-    #[test_case(Response::Log(ArrayString::from("hello").unwrap()))]
+    #[test_case(log(LogLevel::Info, "hello"))]
     #[test_case(Response::PowerOff)]
+    #[test_case(Response::Version(version()))]
+    #[test_case(Response::Ack(42))]
+    #[test_case(Response::Nack(42, ProtocolError::InvalidSide(b'x')))]
+    #[test_case(config(Some(Target::Velocity(-300))))]
+    #[test_case(Response::ImuReadings { accel: [1, -2, 3], gyro: [-4, 5, i16::MIN] })]
+    #[test_case(Response::Telemetry(telemetry()))]
+    #[test_case(Response::Velocity(1234))]
+    #[test_case(Response::Fault(Fault::OverCurrent { current_ma: -20000 }))]
+    #[test_case(Response::LowBattery { battery_voltage: 29000, cutoff: true })]
+    #[test_case(Response::BatteryRecovered { battery_voltage: 34000 })]
+    #[test_case(Response::HallDiagnostics { invalid_states: 3, skipped_sectors: 7 })]
+    #[test_case(Response::MotorCalibration(Ok(HallMapping::DEFAULT)))]
+    #[test_case(Response::MotorCalibration(Err(CalibrationError::Inconsistent)))]
+    #[test_case(Response::MotorTuning(tuning()))]
+    #[test_case(Response::Heartbeat)]
+    #[test_case(Response::LinkLost)]
+    #[test_case(Response::LinkRestored)]
+    #[test_case(Response::CommsTimeout)]
+    #[test_case(Response::Dropped(0x12345678))]
     fn parse_error_if_extra_byte(response: Response) {
         let side_response = SideResponse {
             side: Side::Right,
@@ -364,12 +1129,62 @@ mod tests {
         buffer.push(42);
 
         assert_eq!(
-            SideResponse::parse(&mut buffer),
+            SideResponse::parse(&buffer),
             Ok((side_response, buffer.len() - 1))
         );
         assert_eq!(
-            SideResponse::parse_exact(&mut buffer),
+            SideResponse::parse_exact(&buffer),
             Err(Other(ProtocolError::MessageTooLong))
         )
     }
+
+    #[test_case(Response::Position(0x1122334455667788))]
+    #[test_case(log(LogLevel::Info, "hello"))]
+    #[test_case(log(LogLevel::Debug, &"n".repeat(MAX_LOG_SIZE)))]
+    #[test_case(Response::PowerOff)]
+    fn framed_round_trip(response: Response) {
+        let side_response = SideResponse {
+            side: Side::Left,
+            response,
+        };
+        let mut buffer = Vec::new();
+        side_response
+            .write_with_encoding_to_std(Encoding::Framed, &mut buffer)
+            .unwrap();
+
+        assert_eq!(
+            side_response.length_with_encoding(Encoding::Framed),
+            buffer.len()
+        );
+        assert_eq!(
+            SideResponse::parse_with_encoding(Encoding::Framed, &buffer),
+            Ok((side_response.clone(), buffer.len()))
+        );
+        assert_eq!(
+            SideResponse::parse_any_encoding(&buffer),
+            Ok((side_response, buffer.len()))
+        );
+    }
+
+    #[test]
+    fn parse_any_encoding_unframed() {
+        assert_eq!(
+            SideResponse::parse_any_encoding(b"Rp"),
+            Ok((
+                SideResponse {
+                    side: Side::Right,
+                    response: Response::PowerOff,
+                },
+                2
+            ))
+        );
+    }
+
+    #[test]
+    fn framed_skips_unframed() {
+        assert_eq!(
+            SideResponse::parse_with_encoding(Encoding::Framed, b"Rp"),
+            Err(Other((ProtocolError::InvalidByte(b'R'), 2)))
+        );
+    }
 }
@@ -0,0 +1,141 @@
+//! Moves bytes from a serial receiver into a buffer, and keeps count of those which are lost.
+
+use embedded_io::{Read, ReadReady};
+
+/// Counts the bytes lost while receiving on a serial port, separately for each reason.
+#[derive(Debug, Default)]
+pub struct RxCounts {
+    /// The number of bytes received but dropped because the buffer was full.
+    overruns: u32,
+    /// The number of errors the USART reported, such as framing or noise errors, or bytes it lost
+    /// itself because they weren't read in time.
+    line_errors: u32,
+}
+
+impl RxCounts {
+    pub const fn new() -> Self {
+        Self {
+            overruns: 0,
+            line_errors: 0,
+        }
+    }
+
+    /// Reads everything which the reader has received, passing each byte to `add`, which should
+    /// return false if there was no room for it.
+    pub fn receive<R: Read + ReadReady>(
+        &mut self,
+        reader: &mut R,
+        mut add: impl FnMut(u8) -> bool,
+    ) {
+        while let Ok(true) = reader.read_ready() {
+            let mut byte = [0];
+            match reader.read(&mut byte) {
+                Ok(1) => {
+                    if !add(byte[0]) {
+                        self.overruns = self.overruns.saturating_add(1);
+                    }
+                }
+                Ok(_) => break,
+                Err(_) => self.line_errors = self.line_errors.saturating_add(1),
+            }
+        }
+    }
+
+    /// Returns the number of bytes dropped because the buffer was full since the last call.
+    pub fn take_overruns(&mut self) -> u32 {
+        core::mem::take(&mut self.overruns)
+    }
+
+    /// Returns the number of errors the USART has reported since the last call.
+    pub fn take_line_errors(&mut self) -> u32 {
+        core::mem::take(&mut self.line_errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_io::{ErrorKind, ErrorType};
+
+    /// A reader which returns the given results in turn, and then has nothing more to read.
+    struct FakeReader<'a> {
+        results: &'a [Result<u8, ()>],
+    }
+
+    impl ErrorType for FakeReader<'_> {
+        type Error = ErrorKind;
+    }
+
+    impl ReadReady for FakeReader<'_> {
+        fn read_ready(&mut self) -> Result<bool, ErrorKind> {
+            Ok(!self.results.is_empty())
+        }
+    }
+
+    impl Read for FakeReader<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ErrorKind> {
+            let (result, rest) = self.results.split_first().ok_or(ErrorKind::Other)?;
+            self.results = rest;
+            buffer[0] = result.map_err(|()| ErrorKind::Other)?;
+            Ok(1)
+        }
+    }
+
+    /// Receives from the given results into a buffer with room for `capacity` bytes, and returns
+    /// the length of what was buffered.
+    fn receive(counts: &mut RxCounts, results: &[Result<u8, ()>], capacity: usize) -> usize {
+        let mut buffered = 0;
+        counts.receive(&mut FakeReader { results }, |_| {
+            if buffered < capacity {
+                buffered += 1;
+                true
+            } else {
+                false
+            }
+        });
+        buffered
+    }
+
+    #[test]
+    fn buffers_everything_with_room() {
+        let mut counts = RxCounts::new();
+        assert_eq!(receive(&mut counts, &[Ok(1), Ok(2), Ok(3)], 10), 3);
+        assert_eq!(counts.take_overruns(), 0);
+        assert_eq!(counts.take_line_errors(), 0);
+    }
+
+    #[test]
+    fn counts_overruns_when_full() {
+        let mut counts = RxCounts::new();
+        assert_eq!(receive(&mut counts, &[Ok(1), Ok(2), Ok(3), Ok(4)], 1), 1);
+        assert_eq!(counts.take_overruns(), 3);
+        assert_eq!(counts.take_line_errors(), 0);
+    }
+
+    #[test]
+    fn counts_line_errors_separately() {
+        let mut counts = RxCounts::new();
+        // Reading carries on after an error.
+        assert_eq!(
+            receive(&mut counts, &[Ok(1), Err(()), Err(()), Ok(2)], 10),
+            2
+        );
+        assert_eq!(counts.take_overruns(), 0);
+        assert_eq!(counts.take_line_errors(), 2);
+    }
+
+    #[test]
+    fn take_resets_counts() {
+        let mut counts = RxCounts::new();
+        receive(&mut counts, &[Ok(1), Ok(2), Err(())], 1);
+        assert_eq!(counts.take_overruns(), 1);
+        assert_eq!(counts.take_overruns(), 0);
+        assert_eq!(counts.take_line_errors(), 1);
+        assert_eq!(counts.take_line_errors(), 0);
+
+        // Counts accumulate until they are taken.
+        receive(&mut counts, &[Ok(1), Ok(2)], 0);
+        receive(&mut counts, &[Ok(3)], 0);
+        assert_eq!(counts.take_overruns(), 3);
+    }
+}
@@ -0,0 +1,254 @@
+//! Periodic telemetry which the board can be asked to send.
+
+use crate::util::{ascii_to_bool, bool_to_ascii};
+use crate::ProtocolError;
+use core::convert::TryInto;
+use core::fmt::{self, Display, Formatter};
+use core::ops::BitOr;
+use nb::Error::{Other, WouldBlock};
+
+/// A set of values to include in telemetry reports.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TelemetryFields(u8);
+
+impl TelemetryFields {
+    pub const POSITION: Self = Self(0x01);
+    pub const VELOCITY: Self = Self(0x02);
+    pub const BATTERY: Self = Self(0x04);
+    pub const CHARGER: Self = Self(0x08);
+    pub const ALL: Self = Self(0x0f);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the set of fields with the given bits, or an error if any unknown bits are set.
+    pub fn from_bits(bits: u8) -> Result<Self, ProtocolError> {
+        if bits & !Self::ALL.0 == 0 {
+            Ok(Self(bits))
+        } else {
+            Err(ProtocolError::InvalidByte(bits))
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns whether all of the given fields are in this set.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the number of bytes needed to encode the values of the fields in this set.
+    fn encoded_length(self) -> usize {
+        [
+            (Self::POSITION, 8),
+            (Self::VELOCITY, 4),
+            (Self::BATTERY, 8),
+            (Self::CHARGER, 1),
+        ]
+        .iter()
+        .filter(|(field, _)| self.contains(*field))
+        .map(|(_, length)| length)
+        .sum()
+    }
+}
+
+impl BitOr for TelemetryFields {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Voltage readings in millivolts and current in milliamps from the ADC.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BatteryReadings {
+    pub battery_voltage: u16,
+    pub backup_battery_voltage: u16,
+    /// Positive when the motor is drawing power from the battery, negative when regenerating.
+    pub motor_current: i32,
+}
+
+/// A single telemetry report. Each field is present only if it was subscribed to.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Telemetry {
+    pub position: Option<i64>,
+    /// Motor velocity in hall sensor steps per second.
+    pub velocity: Option<i32>,
+    pub battery: Option<BatteryReadings>,
+    pub charger_connected: Option<bool>,
+}
+
+impl Telemetry {
+    /// Returns the set of fields which are present in this report.
+    pub fn fields(&self) -> TelemetryFields {
+        let mut fields = TelemetryFields::empty();
+        if self.position.is_some() {
+            fields = fields | TelemetryFields::POSITION;
+        }
+        if self.velocity.is_some() {
+            fields = fields | TelemetryFields::VELOCITY;
+        }
+        if self.battery.is_some() {
+            fields = fields | TelemetryFields::BATTERY;
+        }
+        if self.charger_connected.is_some() {
+            fields = fields | TelemetryFields::CHARGER;
+        }
+        fields
+    }
+
+    /// Writes the set of fields present, followed by the value of each of them in order.
+    pub(crate) fn write_to<W>(&self, writer: &mut W) -> Result<(), W::Error>
+    where
+        W: embedded_io::Write,
+    {
+        writer.write_all(&[self.fields().bits()])?;
+        if let Some(position) = self.position {
+            writer.write_all(&position.to_le_bytes())?;
+        }
+        if let Some(velocity) = self.velocity {
+            writer.write_all(&velocity.to_le_bytes())?;
+        }
+        if let Some(battery) = self.battery {
+            writer.write_all(&battery.battery_voltage.to_le_bytes())?;
+            writer.write_all(&battery.backup_battery_voltage.to_le_bytes())?;
+            writer.write_all(&battery.motor_current.to_le_bytes())?;
+        }
+        if let Some(charger_connected) = self.charger_connected {
+            writer.write_all(&[bool_to_ascii(charger_connected)])?;
+        }
+        Ok(())
+    }
+
+    /// Parses a report written by `write_to`, returning it along with the number of bytes used.
+    pub(crate) fn parse(buf: &[u8]) -> nb::Result<(Self, usize), (ProtocolError, usize)> {
+        let fields = match buf.first() {
+            Some(&bits) => TelemetryFields::from_bits(bits).map_err(|e| (e, 1))?,
+            None => return Err(WouldBlock),
+        };
+        let length = 1 + fields.encoded_length();
+        if buf.len() < length {
+            return Err(WouldBlock);
+        }
+
+        let mut telemetry = Self::default();
+        let mut rest = &buf[1..length];
+        if fields.contains(TelemetryFields::POSITION) {
+            telemetry.position = Some(i64::from_le_bytes(rest[..8].try_into().unwrap()));
+            rest = &rest[8..];
+        }
+        if fields.contains(TelemetryFields::VELOCITY) {
+            telemetry.velocity = Some(i32::from_le_bytes(rest[..4].try_into().unwrap()));
+            rest = &rest[4..];
+        }
+        if fields.contains(TelemetryFields::BATTERY) {
+            telemetry.battery = Some(BatteryReadings {
+                battery_voltage: u16::from_le_bytes(rest[..2].try_into().unwrap()),
+                backup_battery_voltage: u16::from_le_bytes(rest[2..4].try_into().unwrap()),
+                motor_current: i32::from_le_bytes(rest[4..8].try_into().unwrap()),
+            });
+            rest = &rest[8..];
+        }
+        if fields.contains(TelemetryFields::CHARGER) {
+            telemetry.charger_connected =
+                Some(ascii_to_bool(rest[0]).map_err(|e| Other((e, length)))?);
+        }
+        Ok((telemetry, length))
+    }
+}
+
+impl Display for Telemetry {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut separator = "";
+        if let Some(position) = self.position {
+            write!(f, "position {}", position)?;
+            separator = ", ";
+        }
+        if let Some(velocity) = self.velocity {
+            write!(f, "{}velocity {}/s", separator, velocity)?;
+            separator = ", ";
+        }
+        if let Some(battery) = self.battery {
+            write!(
+                f,
+                "{}battery voltage {} mV, backup {} mV, current {} mA",
+                separator,
+                battery.battery_voltage,
+                battery.backup_battery_voltage,
+                battery.motor_current
+            )?;
+            separator = ", ";
+        }
+        if let Some(charger_connected) = self.charger_connected {
+            write!(
+                f,
+                "{}charger {}",
+                separator,
+                if charger_connected {
+                    "connected"
+                } else {
+                    "not connected"
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_from_bits() {
+        assert_eq!(
+            TelemetryFields::from_bits(0x05),
+            Ok(TelemetryFields::POSITION | TelemetryFields::BATTERY)
+        );
+        assert_eq!(
+            TelemetryFields::from_bits(0x10),
+            Err(ProtocolError::InvalidByte(0x10))
+        );
+    }
+
+    #[test]
+    fn fields_contains() {
+        let fields = TelemetryFields::POSITION | TelemetryFields::CHARGER;
+        assert!(fields.contains(TelemetryFields::POSITION));
+        assert!(!fields.contains(TelemetryFields::VELOCITY));
+        assert!(TelemetryFields::ALL.contains(fields));
+        assert!(!fields.contains(TelemetryFields::ALL));
+    }
+
+    #[test]
+    fn fields_of_report() {
+        let telemetry = Telemetry {
+            velocity: Some(-3),
+            charger_connected: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            telemetry.fields(),
+            TelemetryFields::VELOCITY | TelemetryFields::CHARGER
+        );
+        assert!(Telemetry::default().fields().is_empty());
+    }
+
+    #[test]
+    fn display() {
+        let telemetry = Telemetry {
+            position: Some(42),
+            charger_connected: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(telemetry.to_string(), "position 42, charger not connected");
+    }
+}